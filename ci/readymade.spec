@@ -35,17 +35,20 @@ This package contains the configuration files for Readymade to install Ultramari
 %cargo_prep_online
 
 %build
-%{cargo_build} --locked
+%{cargo_build} --locked --workspace
 
 %install
 install -Dm755 target/rpm/readymade %buildroot%_bindir/readymade
+install -Dm755 target/rpm/readymade-playbook %buildroot%_bindir/readymade-playbook
 ./install.sh %buildroot
 
 %files
 %_bindir/readymade
+%_bindir/readymade-playbook
 %_datadir/polkit-1/actions/com.fyralabs.pkexec.readymade.policy
 %{_datadir}/applications/com.fyralabs.Readymade.desktop
 %{_datadir}/icons/hicolor/*/apps/com.fyralabs.Readymade.*
+%{_unitdir}/readymade-unattended.service
 
 %files config-ultramarine
 %_sysconfdir/readymade.toml
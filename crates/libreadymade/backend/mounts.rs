@@ -1,19 +1,19 @@
+use crate::plan::{Action, Step};
 use crate::prelude::*;
 
 use std::{
-    cell::OnceCell,
     fmt::Write,
     fs::create_dir_all,
     path::{Component, Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, OnceLock},
 };
 
 use gpt::partition_types;
 use nix::mount::umount;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema)]
 pub enum EncryptionOption {
     // TODO: document
     KeyFile,
@@ -21,7 +21,7 @@ pub enum EncryptionOption {
     KeyFileTpm2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Mount {
     /// Path to partition
     pub partition: PathBuf,
@@ -36,9 +36,10 @@ pub struct Mount {
     /// GPT Partition type (assume we only support GPT)
     // TODO: make this a method? / private
     #[serde(skip)]
-    pub(crate) gpt_type: OnceCell<gpt::partition_types::Type>,
+    pub(crate) gpt_type: OnceLock<gpt::partition_types::Type>,
 }
 
+#[derive(Clone)]
 pub struct MapperCache {
     cache: std::collections::HashMap<String, PathBuf>,
 }
@@ -58,6 +59,10 @@ impl MapperCache {
         self.cache.insert(node, path);
     }
 
+    pub(crate) fn remove(&mut self, node: &str) -> Option<PathBuf> {
+        self.cache.remove(node)
+    }
+
     pub(crate) fn clear(&mut self) {
         for (node, path) in self.cache.drain() {
             if let Err(e) = cryptsetup_close(&path.to_string_lossy()) {
@@ -113,6 +118,10 @@ pub fn luks_decrypt(node: &str, passphrase: &str, label: &str) -> color_eyre::Re
     Arc::get_mut(&mut *MAPPER_CACHE.write())
         .unwrap()
         .insert(node.to_owned(), mapper.clone());
+    crate::session::acquire(crate::session::Resource::Mapper {
+        node: node.to_owned(),
+        mapper: mapper.clone(),
+    });
 
     Ok(mapper)
 }
@@ -138,7 +147,16 @@ pub fn generate_unique_mapper_label(mntpoint: &str) -> String {
 }
 
 impl Mount {
+    /// Swap is listed with the mountpoint `none`, like in fstab. It is never mounted while installing.
+    #[must_use]
+    pub fn is_swap(&self) -> bool {
+        self.mountpoint == Path::new("none")
+    }
+
     fn mount(&self, root: &Path, passphrase: Option<&str>) -> Result<()> {
+        if self.is_swap() {
+            return Ok(());
+        }
         create_dir_all(root)?;
 
         let target = (self.mountpoint.strip_prefix("/")).unwrap_or(&self.mountpoint);
@@ -162,17 +180,22 @@ impl Mount {
 
         sys_mount::Mount::builder()
             .data(&self.options)
-            .mount(&source, target)?;
+            .mount(&source, &target)?;
+        crate::session::acquire(crate::session::Resource::Mount(target));
 
         Ok(())
     }
 
     pub fn umount(&self, root: &Path) -> std::io::Result<()> {
+        if self.is_swap() {
+            return Ok(());
+        }
         // sanitize target path
         let target = (self.mountpoint.strip_prefix("/")).unwrap_or(&self.mountpoint);
         let target = root.join(target);
 
         umount(&target)?;
+        crate::session::forget(&crate::session::Resource::Mount(target));
         Ok(())
     }
 
@@ -200,7 +223,7 @@ impl Mount {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, schemars::JsonSchema)]
 pub struct Mounts(pub Vec<Mount>);
 
 impl Mounts {
@@ -231,6 +254,37 @@ impl Mounts {
         self.0.iter().try_for_each(|m| m.mount(root, passphrase))
     }
 
+    /// Describe [`Self::mount_all`] as plan actions, without mounting anything.
+    pub fn plan_mount_all(&self, root: &Path, step: &mut Step) {
+        for mount in self.0.iter().filter(|mount| !mount.is_swap()) {
+            let target = root.join(
+                mount
+                    .mountpoint
+                    .strip_prefix("/")
+                    .unwrap_or(&mount.mountpoint),
+            );
+            let source = if mount.encryption_type.is_some() {
+                let label = generate_unique_mapper_label(&mount.mountpoint.to_string_lossy());
+                step.push(Action::host_command(
+                    "cryptsetup",
+                    [
+                        "open".to_owned(),
+                        mount.partition.to_string_lossy().to_string(),
+                        label.clone(),
+                    ],
+                ));
+                PathBuf::from(format!("/dev/mapper/{label}"))
+            } else {
+                mount.partition.clone()
+            };
+            step.push(Action::Mount {
+                source,
+                target,
+                options: Some(mount.options.clone()).filter(|o| !o.is_empty()),
+            });
+        }
+    }
+
     /// Unmount all the targets in reverse.
     pub fn umount_all(&self, root: &Path) -> std::io::Result<()> {
         self.0.iter().rev().try_for_each(|m| m.umount(root))
@@ -293,6 +347,36 @@ fn cryptsetup_luks_uuid(node: &Path) -> Result<String, color_eyre::eyre::Error>
     Ok(String::from_utf8_lossy(&cmd.stdout).trim().to_owned())
 }
 
+/// LUKS containers underneath the device-mapper device `node`, such as the physical volume of an
+/// LVM logical volume, along with the mapper names they are opened as.
+fn luks_below(node: &Path) -> Vec<(PathBuf, String)> {
+    let Some(name) = (node.canonicalize().ok()).and_then(|p| p.file_name().map(ToOwned::to_owned))
+    else {
+        return vec![];
+    };
+    let sysfs = Path::new("/sys/class/block").join(name);
+    (crate::backend::util::fs::exist_then_read_dir(sysfs.join("slaves")).into_iter())
+        .flatten()
+        .flat_map(|slave| {
+            let slave = Path::new("/dev").join(slave.file_name());
+            if !is_luks(&slave) {
+                return luks_below(&slave);
+            }
+            let mapper = std::fs::read_to_string(sysfs.join("dm/name")).unwrap_or_default();
+            vec![(slave, mapper.trim().to_owned())]
+        })
+        .collect()
+}
+
+/// Whether the LUKS container `node` has a TPM2 key enrolled by `systemd-cryptenroll`.
+fn has_tpm2_token(node: &Path) -> bool {
+    Command::new("cryptsetup")
+        .arg("luksDump")
+        .arg(node)
+        .output()
+        .is_ok_and(|out| String::from_utf8_lossy(&out.stdout).contains("systemd-tpm2"))
+}
+
 #[allow(clippy::unwrap_in_result)]
 /// # Panics
 /// if LUKS UUID cannot be obtained.
@@ -301,27 +385,40 @@ pub fn generate_cryptdata(mounts: &Mounts) -> Result<Option<CryptData>, color_ey
     let mut crypttab = String::new();
     let mut cmdline_opts = vec![];
 
-    let luks_partitions = mounts.0.iter().filter(|part| is_luks(&part.partition));
+    // LUKS containers by node, with their mapper name and whether they unlock with the TPM
+    let mut containers: Vec<(PathBuf, String, bool)> = vec![];
+    for part in &mounts.0 {
+        let found = if is_luks(&part.partition) {
+            let label = (part.label.clone()).expect("LUKS partition must have a label");
+            let tpm = matches!(
+                part.encryption_type,
+                Some(EncryptionOption::KeyFileTpm2 | EncryptionOption::Tpm2)
+            );
+            vec![(part.partition.clone(), label, tpm)]
+        } else {
+            // e.g. LVM on LUKS, where one container holds the volume group of several mounts
+            (luks_below(&part.partition).into_iter())
+                .map(|(node, name)| {
+                    let tpm = has_tpm2_token(&node);
+                    (node, name, tpm)
+                })
+                .collect()
+        };
+        for container in found {
+            if !containers.iter().any(|(node, ..)| *node == container.0) {
+                containers.push(container);
+            }
+        }
+    }
 
     let mut is_tpm = false;
 
-    let has_luks = luks_partitions.clone().count() > 0;
-    for part in luks_partitions {
-        let uuid = cryptsetup_luks_uuid(&part.partition).expect("Failed to get LUKS UUID");
-        let label = part
-            .label
-            .as_ref()
-            .expect("LUKS partition must have a label");
+    let has_luks = !containers.is_empty();
+    for (node, label, part_uses_tpm) in containers {
+        let uuid = cryptsetup_luks_uuid(&node).expect("Failed to get LUKS UUID");
 
         let mut extra_opts = String::new();
 
-        let part_uses_tpm: bool = {
-            matches!(
-                part.encryption_type,
-                Some(EncryptionOption::KeyFileTpm2 | EncryptionOption::Tpm2)
-            )
-        };
-
         if part_uses_tpm {
             is_tpm = true;
             extra_opts.push_str("tpm2-device=auto,");
@@ -1,8 +1,9 @@
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct CleanupBoot;
 
 impl PostInstallModule for CleanupBoot {
@@ -28,4 +29,19 @@ impl PostInstallModule for CleanupBoot {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::remove_file("/boot/initramfs-*"))
+            .push(Action::remove_file("/boot/vmlinuz-*"))
+            .push(Action::remove_file("/boot/loader/entries/*"));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::BootCleaned]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
@@ -1,10 +1,11 @@
 use std::io::Write;
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct CryptSetup;
 
 impl PostInstallModule for CryptSetup {
@@ -16,4 +17,17 @@ impl PostInstallModule for CryptSetup {
 
         Ok(())
     }
+
+    fn plan(&self, _: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::write_file("/etc/crypttab", "empty crypttab"));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::CrypttabWritten]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
@@ -1,11 +1,22 @@
-use crate::stage;
+use crate::{
+    plan::{Action, Step},
+    stage,
+};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule, module_command};
 use color_eyre::{Result, eyre::bail};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+const DRACUT_ARGS: [&str; 6] = [
+    "--force",
+    "--parallel",
+    "--regenerate-all",
+    "--hostonly",
+    "--strip",
+    "--aggressive-strip",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Dracut;
 
 impl PostInstallModule for Dracut {
@@ -17,16 +28,7 @@ impl PostInstallModule for Dracut {
             //
             // on my system this reduces the size from 170M down to 43M.
             // — mado
-            let dracut_cmd_status = Command::new("dracut")
-                .args([
-                    "--force",
-                    "--parallel",
-                    "--regenerate-all",
-                    "--hostonly",
-                    "--strip",
-                    "--aggressive-strip",
-                ])
-                .status()?;
+            let dracut_cmd_status = module_command("dracut").args(DRACUT_ARGS).status()?;
 
             if !dracut_cmd_status.success() {
                 bail!(
@@ -38,4 +40,23 @@ impl PostInstallModule for Dracut {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::target_command("dracut", DRACUT_ARGS));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::InitramfsBuilt]
+    }
+
+    fn requires(&self) -> &'static [Capability] {
+        &[
+            Capability::BootCleaned,
+            Capability::KernelInstalled,
+            Capability::FstabWritten,
+            Capability::CrypttabWritten,
+            Capability::RaidConfigured,
+        ]
+    }
 }
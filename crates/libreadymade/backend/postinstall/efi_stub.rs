@@ -1,40 +1,28 @@
 use crate::backend::util::sys::check_uefi;
 use color_eyre::{Result, eyre::bail};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::path::Path;
 
 use crate::{
-    backend::util::fs::{get_whole_disk, partition_number},
+    backend::util::fs::{get_whole_disk, md_members, partition_number},
     consts::shim_path,
 };
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Condition, Context, PostInstallModule, module_command};
+use crate::plan::{Action, Step};
 
 /// Generate an EFI stub for the bootloader
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct EfiStub {
     pub distro_name: String,
 }
 
-impl PostInstallModule for EfiStub {
-    #[tracing::instrument(skip(self, context))]
-    fn run(&self, context: &Context) -> Result<()> {
-        // two guard clauses for checking EFI and
-        // existence of an ESP partition
-        if !context.uefi || !check_uefi() {
-            return Ok(());
-        }
-
-        let esp_partition = context.mounts.get_esp_partition();
-
-        tracing::debug!(esp_part = ?esp_partition, uefi = ?context.uefi, "Generating EFI stub");
-
-        let Some(esp_partition) = esp_partition.as_ref() else {
-            bail!("No ESP partition found, cannot generate EFI stub")
-        };
+impl EfiStub {
+    /// Create a boot entry for the shim on the ESP `partition`.
+    fn create_entry(&self, partition: &Path) -> Result<()> {
         // get the partition number
-        let partition_number = partition_number(&esp_partition.partition)?;
-        let esp_disk = get_whole_disk(&esp_partition.partition)?;
+        let partition_number = partition_number(partition)?;
+        let esp_disk = get_whole_disk(partition)?;
 
         tracing::debug!(
             disk = esp_disk,
@@ -44,7 +32,7 @@ impl PostInstallModule for EfiStub {
             "Creating EFI boot entry"
         );
 
-        let status = Command::new("/usr/sbin/efibootmgr")
+        let status = module_command("/usr/sbin/efibootmgr")
             .arg("--create")
             .arg("--disk")
             .arg(esp_disk)
@@ -72,3 +60,84 @@ impl PostInstallModule for EfiStub {
         Ok(())
     }
 }
+
+impl PostInstallModule for EfiStub {
+    #[tracing::instrument(skip(self, context))]
+    fn run(&self, context: &Context) -> Result<()> {
+        // two guard clauses for checking EFI and
+        // existence of an ESP partition
+        if !context.uefi || !check_uefi() {
+            return Ok(());
+        }
+
+        let esp_partition = context.mounts.get_esp_partition();
+
+        tracing::debug!(esp_part = ?esp_partition, uefi = ?context.uefi, "Generating EFI stub");
+
+        let Some(esp_partition) = esp_partition.as_ref() else {
+            bail!("No ESP partition found, cannot generate EFI stub")
+        };
+        // a mirrored ESP gets an entry for each copy, so every disk can boot on its own
+        let mut partitions = md_members(&esp_partition.partition);
+        if partitions.is_empty() {
+            partitions.push(esp_partition.partition.clone());
+        }
+        for partition in partitions {
+            self.create_entry(&partition)?;
+        }
+
+        Ok(())
+    }
+
+    fn plan(&self, context: &Context, step: &mut Step) -> Result<()> {
+        if !context.uefi {
+            return Ok(());
+        }
+        let Some(esp_partition) = context.mounts.get_esp_partition() else {
+            bail!("No ESP partition found, cannot generate EFI stub")
+        };
+        let entries = if context.mirrors.is_empty() {
+            // the ESP may not have been created yet
+            let esp_disk = get_whole_disk(&esp_partition.partition)
+                .unwrap_or_else(|_| context.destination_disk.display().to_string());
+            let partition_number = partition_number(&esp_partition.partition)
+                .map_or_else(|_| "<esp>".to_owned(), |n| n.to_string());
+            vec![(esp_disk, partition_number)]
+        } else {
+            // a mirrored ESP gets an entry on every disk
+            (context.boot_disks())
+                .map(|disk| (disk.display().to_string(), "<esp>".to_owned()))
+                .collect()
+        };
+        for (esp_disk, partition_number) in entries {
+            step.push(Action::host_command(
+                "/usr/sbin/efibootmgr",
+                [
+                    "--create".to_owned(),
+                    "--disk".to_owned(),
+                    esp_disk,
+                    "--part".to_owned(),
+                    partition_number,
+                    "--label".to_owned(),
+                    self.distro_name.clone(),
+                    "--loader".to_owned(),
+                    shim_path().to_owned(),
+                ],
+            ));
+        }
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::BootEntryCreated]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
+
+    /// Boot entries go to the NVRAM of the host, which has nothing to do with a disk image.
+    fn when(&self) -> &'static [Condition] {
+        &[Condition::Hardware]
+    }
+}
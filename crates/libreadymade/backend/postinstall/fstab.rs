@@ -1,4 +1,5 @@
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 use crate::prelude::*;
 use color_eyre::Result;
 use color_eyre::eyre::OptionExt;
@@ -6,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Write;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Fstab;
 
 impl PostInstallModule for Fstab {
@@ -17,6 +18,25 @@ impl PostInstallModule for Fstab {
         std::fs::write("/etc/fstab", fstab).wrap_err("cannot write to /etc/fstab")?;
         Ok(())
     }
+
+    fn plan(&self, context: &Context, step: &mut Step) -> Result<()> {
+        let mountpoints = (context.mounts.0.iter())
+            .map(|m| m.mountpoint.display().to_string())
+            .join(", ");
+        step.push(Action::write_file(
+            "/etc/fstab",
+            &format!("entries for {mountpoints}"),
+        ));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::FstabWritten]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
 
 /// Generate a /etc/fstab file from the DDI partition types
@@ -70,10 +90,13 @@ pub fn generate_fstab(mounts: &Mounts) -> color_eyre::Result<String> {
         // if let Some(_mntpnt) = part.ddi_mountpoint() {
         tracing::trace!(?part, "Processing partition");
 
-        let entry = fstab_entry(
-            mountobj.clone(),
-            fstypes[&format!("{}", mnt.display()).as_str()],
-        )?;
+        // swap isn't mounted while installing, so it's not in /proc/mounts
+        let fstype = if mountobj.is_swap() {
+            "swap"
+        } else {
+            fstypes[&format!("{}", mnt.display()).as_str()]
+        };
+        let entry = fstab_entry(mountobj.clone(), fstype)?;
         writeln!(&mut fstab, "{entry}").unwrap();
     }
 
@@ -184,7 +207,7 @@ pub fn fstab_entry(mount: Mount, fstype: &str) -> color_eyre::Result<String> {
     // or the root device it should be 1. For other partitions it should be 2, or 0 to disable checking.
     // If the root file system is btrfs or XFS, the fsck order should be set to 0 instead of 1.
     let pass = match fs_fmt {
-        "btrfs" | "xfs" => 0,
+        "btrfs" | "xfs" | "swap" => 0,
         _ if mount.mountpoint.to_str().unwrap() == "/" => 1,
         _ => FALLBACK_PASS,
     };
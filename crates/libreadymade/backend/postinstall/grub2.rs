@@ -1,10 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::{io::Write, path::Path, process::Command};
+use std::{io::Write, path::Path};
 use tracing::{info, warn};
 
-use crate::{backend::mounts::generate_cryptdata, prelude::*, stage};
+use crate::{
+    backend::mounts::generate_cryptdata,
+    plan::{Action, Step},
+    prelude::*,
+    stage,
+};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
 
 #[derive(Clone, Debug)]
 struct Grub2Defaults {
@@ -77,10 +82,9 @@ GRUB_ENABLE_BLSCFG={enable_blsconfig}
 ///
 /// # Arguments
 ///
-/// * `disk` - The path to the disk to install GRUB2 on.
-fn grub2_install_bios<P: AsRef<Path>>(disk: P) -> Result<()> {
-    let disk_display = disk.as_ref().display();
-    info!(disk = ?disk_display, "Generating GRUB2 configuration...");
+/// * `disks` - The disks to install GRUB2 on, e.g. every disk of a mirror.
+fn grub2_install_bios<P: AsRef<Path>>(disks: &[P]) -> Result<()> {
+    info!("Generating GRUB2 configuration...");
     // this should probably be run inside a chroot... but we'll see
     crate::cmd!("grub2-mkconfig" [["-o", "/boot/grub2/grub.cfg"]] => |r| {
         warn!("Failed to generate GRUB2 configuration (status code {:?})", r.code());
@@ -89,7 +93,15 @@ fn grub2_install_bios<P: AsRef<Path>>(disk: P) -> Result<()> {
         }
     });
 
-    info!("Blessing the disk with GRUB2...");
+    for disk in disks {
+        grub2_install_bios_disk(disk.as_ref())?;
+    }
+    Ok(())
+}
+
+fn grub2_install_bios_disk(disk: &Path) -> Result<()> {
+    let disk_display = disk.display();
+    info!(disk = ?disk_display, "Blessing the disk with GRUB2...");
     crate::cmd!("grub2-install" [
         ["--target=i386-pc", "--recheck", "--boot-directory=/boot"],
         // We are going tov4_10 force the installation, because for some reason
@@ -97,12 +109,12 @@ fn grub2_install_bios<P: AsRef<Path>>(disk: P) -> Result<()> {
         // even though it exists.
         //
         // --force is a last resort, but in our layout it's kind of necessary :P
-        ["--force"], [disk.as_ref()]
+        ["--force"], [disk]
     ] => |cmd| bail!("Failed to install GRUB2 on disk {disk_display}: status code {:?}", cmd.code()));
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct GRUB2;
 
 impl PostInstallModule for GRUB2 {
@@ -174,10 +186,49 @@ impl PostInstallModule for GRUB2 {
             });
         } else {
             stage!(biosgrub "Installing BIOS Grub2" {
-                grub2_install_bios(&context.destination_disk)?;
+                grub2_install_bios(&context.boot_disks().collect_vec())?;
             });
         }
 
         Ok(())
     }
+
+    fn plan(&self, context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::write_file("/etc/default/grub", "GRUB2 defaults"));
+        if context.uefi {
+            if context.mounts.get_xbootldr_partition().is_none() {
+                bail!("No xbootldr partition found");
+            }
+            step.push(Action::write_file(
+                "/boot/efi/EFI/fedora/grub.cfg",
+                "stage 1 config pointing to the xbootldr partition",
+            ));
+            step.push(Action::target_command(
+                "grub2-mkconfig",
+                ["-o", "/boot/grub2/grub.cfg"],
+            ));
+        } else {
+            step.push(Action::target_command(
+                "grub2-mkconfig",
+                ["-o", "/boot/grub2/grub.cfg"],
+            ));
+            for disk in context.boot_disks() {
+                step.push(Action::target_command(
+                    "grub2-install",
+                    [
+                        "--target=i386-pc".to_owned(),
+                        "--recheck".to_owned(),
+                        "--boot-directory=/boot".to_owned(),
+                        "--force".to_owned(),
+                        disk.display().to_string(),
+                    ],
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::GrubConfigured]
+    }
 }
@@ -1,9 +1,10 @@
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct InitialSetup;
 
 impl PostInstallModule for InitialSetup {
@@ -12,4 +13,20 @@ impl PostInstallModule for InitialSetup {
         std::fs::File::create("/.unconfigured")?;
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::write_file(
+            "/.unconfigured",
+            "first boot setup marker",
+        ));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::FirstBootConfigured]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
@@ -1,9 +1,10 @@
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Language {
     pub lang: String,
 }
@@ -28,4 +29,20 @@ LC_MESSAGES={}",
         )?; // welcome to rust and rustfmt
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::write_file(
+            "/etc/locale.conf",
+            &format!("LANG={}.UTF-8", self.lang),
+        ));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::LocaleConfigured]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
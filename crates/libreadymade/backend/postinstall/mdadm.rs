@@ -0,0 +1,60 @@
+use super::{Capability, Context, PostInstallModule, module_command};
+use crate::plan::{Action, Step};
+use crate::prelude::*;
+
+/// Writes `/etc/mdadm.conf` with the md RAID arrays the installation is mounted from, so the
+/// initramfs can assemble them. Does nothing if there are none.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Mdadm;
+
+/// The mounted md RAID arrays, e.g. `/dev/md127` or `/dev/md/root`.
+fn arrays(context: &Context) -> Vec<&Path> {
+    (context.mounts.0.iter())
+        .map(|mount| mount.partition.as_path())
+        .filter(|node| node.to_string_lossy().starts_with("/dev/md"))
+        .collect()
+}
+
+impl PostInstallModule for Mdadm {
+    fn run(&self, context: &Context) -> Result<()> {
+        let arrays = arrays(context);
+        if arrays.is_empty() {
+            return Ok(());
+        }
+        tracing::info!(?arrays, "Writing /etc/mdadm.conf...");
+        let output = module_command("mdadm")
+            .args(["--detail", "--brief"])
+            .args(&arrays)
+            .output()
+            .wrap_err("cannot run mdadm")?;
+        if !output.status.success() {
+            bail!(
+                "mdadm --detail failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let mut conf = "# This file is generated by Readymade.\n".to_owned();
+        conf.push_str(&String::from_utf8_lossy(&output.stdout));
+        std::fs::write("/etc/mdadm.conf", conf).wrap_err("cannot write to /etc/mdadm.conf")
+    }
+
+    fn plan(&self, context: &Context, step: &mut Step) -> Result<()> {
+        let arrays = arrays(context);
+        if !arrays.is_empty() {
+            let arrays = arrays.iter().map(|node| node.display()).join(", ");
+            step.push(Action::write_file(
+                "/etc/mdadm.conf",
+                &format!("arrays {arrays}"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::RaidConfigured]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
+}
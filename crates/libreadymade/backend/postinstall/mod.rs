@@ -1,4 +1,4 @@
-use crate::prelude::*;
+use crate::plan::Step;
 use crate::prelude::*;
 
 use cleanup_boot::CleanupBoot;
@@ -11,12 +11,15 @@ use fstab::Fstab;
 use grub2::GRUB2;
 use initial_setup::InitialSetup;
 use language::Language;
+use mdadm::Mdadm;
 use prepare_fedora::PrepareFedora;
 use reinstall_kernel::ReinstallKernel;
 use script::Script;
 use selinux::SELinux;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
 
 pub mod cleanup_boot;
 pub mod cryptsetup;
@@ -26,15 +29,21 @@ pub mod fstab;
 pub mod grub2;
 pub mod initial_setup;
 pub mod language;
+pub mod mdadm;
 pub mod prepare_fedora;
 pub mod reinstall_kernel;
+pub mod schedule;
 pub mod script;
 pub mod selinux;
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Clone)]
 pub struct Context {
     pub destination_disk: PathBuf,
+    /// Other disks with a copy of the boot partitions, which get the bootloader as well.
+    pub mirrors: Vec<PathBuf>,
     pub uefi: bool,
+    /// The destination disk is an image file on a loop device, see [`crate::image`].
+    pub image: bool,
     // pub esp_partition: Option<String>,
     // Installs should always have an xbootldr partition
     // pub xbootldr_partition: String,
@@ -42,13 +51,103 @@ pub struct Context {
     pub mounts: Mounts,
 }
 
+impl Context {
+    /// The destination disk, then its [mirrors](Self::mirrors).
+    pub fn boot_disks(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.destination_disk).chain(&self.mirrors)
+    }
+}
+
+/// Something a postinstall module leaves behind in the installed system, which other modules may need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Kernels, initramfs images and boot entries that came with the image are removed from `/boot`.
+    BootCleaned,
+    /// `/etc/default/grub` and the GRUB configuration are written.
+    GrubConfigured,
+    /// `/etc/fstab` is written.
+    FstabWritten,
+    /// `/etc/crypttab` is written.
+    CrypttabWritten,
+    /// `/etc/mdadm.conf` lists the RAID arrays of the installation.
+    RaidConfigured,
+    /// The kernel is installed to `/boot` along with its boot entry.
+    KernelInstalled,
+    /// The initramfs is regenerated for the installed system.
+    InitramfsBuilt,
+    /// `/etc/locale.conf` is written.
+    LocaleConfigured,
+    /// State specific to the machine the image was built on is wiped.
+    SystemPrepared,
+    /// Initial setup runs on the first boot.
+    FirstBootConfigured,
+    /// The firmware has a boot entry for the installed system.
+    BootEntryCreated,
+    /// Every file is labelled for `SELinux`.
+    SelinuxRelabelled,
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::BootCleaned => "/boot cleaned",
+            Self::GrubConfigured => "GRUB configured",
+            Self::FstabWritten => "fstab written",
+            Self::CrypttabWritten => "crypttab written",
+            Self::RaidConfigured => "mdadm.conf written",
+            Self::KernelInstalled => "kernel installed",
+            Self::InitramfsBuilt => "initramfs built",
+            Self::LocaleConfigured => "locale configured",
+            Self::SystemPrepared => "system prepared",
+            Self::FirstBootConfigured => "initial setup enabled",
+            Self::BootEntryCreated => "boot entry created",
+            Self::SelinuxRelabelled => "SELinux labels set",
+        })
+    }
+}
+
 #[enum_dispatch(Module)]
 pub trait PostInstallModule {
     fn run(&self, context: &Context) -> Result<()>;
+
+    /// Describe what [`PostInstallModule::run`] would do, without doing it.
+    ///
+    /// The target system does not exist yet when planning, so anything that depends on its
+    /// contents (kernel versions, UUIDs, …) is shown as a `<placeholder>`.
+    ///
+    /// # Errors
+    /// The module cannot run against the given [`Context`].
+    fn plan(&self, context: &Context, step: &mut Step) -> Result<()>;
+
+    fn name(&self) -> &'static str {
+        crate::backend::util::type_name::<Self>()
+    }
+
+    /// What the module leaves behind, for modules that [`PostInstallModule::requires`] it.
+    fn provides(&self) -> &'static [Capability] {
+        &[]
+    }
+
+    /// What has to be in place before the module runs, if another module in the playbook provides it.
+    fn requires(&self) -> &'static [Capability] {
+        &[]
+    }
+
+    /// Whether the module can run at the same time as other concurrent modules it does not depend on.
+    ///
+    /// Modules that run package scripts or arbitrary commands should not.
+    fn concurrent(&self) -> bool {
+        false
+    }
+
+    /// Conditions the module only ever runs under, on top of [`ModuleOptions::when`].
+    fn when(&self) -> &'static [Condition] {
+        &[]
+    }
 }
 
 #[enum_dispatch]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 #[serde(tag = "module")]
 pub enum Module {
     SELinux,
@@ -63,4 +162,374 @@ pub enum Module {
     CryptSetup,
     Script,
     Fstab,
+    Mdadm,
+}
+
+/// A condition a postinstall module only runs under.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// The machine boots with UEFI.
+    Uefi,
+    /// The machine boots with legacy BIOS.
+    Bios,
+    /// At least one partition is encrypted.
+    Encrypted,
+    /// The installer runs on this architecture, e.g. `x86_64` or `aarch64`.
+    Arch(String),
+    /// This path exists in the installed system.
+    FileExists(PathBuf),
+    /// The destination disk is a real disk rather than a disk image file.
+    Hardware,
+}
+
+impl Condition {
+    /// Whether the condition holds, or [`None`] if that can only be told inside the installed system.
+    #[must_use]
+    pub fn holds_on_host(&self, context: &Context) -> Option<bool> {
+        match self {
+            Self::Uefi => Some(context.uefi),
+            Self::Bios => Some(!context.uefi),
+            Self::Encrypted => {
+                Some((context.mounts.0.iter()).any(|mount| mount.encryption_type.is_some()))
+            }
+            Self::Arch(arch) => Some(arch == std::env::consts::ARCH),
+            Self::FileExists(_) => None,
+            Self::Hardware => Some(!context.image),
+        }
+    }
+
+    /// Whether the condition holds. Must be called inside the chroot of the installed system.
+    #[must_use]
+    pub fn holds(&self, context: &Context) -> bool {
+        match self {
+            Self::FileExists(path) => path.exists(),
+            condition => condition.holds_on_host(context).unwrap_or(true),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Uefi => f.write_str("UEFI"),
+            Self::Bios => f.write_str("BIOS"),
+            Self::Encrypted => f.write_str("encryption"),
+            Self::Arch(arch) => write!(f, "arch {arch}"),
+            Self::FileExists(path) => write!(f, "{} exists", path.display()),
+            Self::Hardware => f.write_str("a real disk"),
+        }
+    }
+}
+
+/// How a postinstall module is run, alongside the module in the playbook.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, schemars::JsonSchema)]
+pub struct ModuleOptions {
+    /// Only run the module if all of these conditions hold.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub when: Vec<Condition>,
+    /// Record a warning and carry on with the installation if the module fails.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub continue_on_error: bool,
+    /// Fail the module if it takes longer than this many seconds, killing the commands it runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// A postinstall module along with its [`ModuleOptions`], e.g.
+/// `{ "module": "EfiStub", "when": ["uefi"], "continue_on_error": true }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Entry {
+    // must come first, so its fields are taken out before the module sees the rest
+    #[serde(flatten)]
+    pub options: ModuleOptions,
+    #[serde(flatten)]
+    pub module: Module,
+}
+
+impl From<Module> for Entry {
+    fn from(module: Module) -> Self {
+        Self {
+            options: ModuleOptions::default(),
+            module,
+        }
+    }
+}
+
+/// What came of running an [`Entry`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    /// A condition did not hold, so the module was not run.
+    Skipped(Condition),
+    /// The module failed, but is allowed to.
+    Failed(String),
+}
+
+/// Give a module that was killed for timing out a moment to return.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Start building a command for a postinstall module.
+///
+/// Its stdin is closed, so a command that asks a question fails instead of waiting for an answer
+/// until the module times out.
+pub fn module_command<S: AsRef<std::ffi::OsStr>>(program: S) -> std::process::Command {
+    let mut cmd = std::process::Command::new(program);
+    cmd.stdin(std::process::Stdio::null());
+    cmd
+}
+
+impl Entry {
+    /// [`ModuleOptions::when`] along with the conditions of the module itself, see
+    /// [`PostInstallModule::when`].
+    fn conditions(&self) -> impl Iterator<Item = &Condition> + Clone {
+        self.module.when().iter().chain(&self.options.when)
+    }
+
+    /// Whether the module will be run, or [`None`] if that can only be told inside the installed system.
+    #[must_use]
+    pub fn runs_on_host(&self, context: &Context) -> Option<bool> {
+        let mut runs = Some(true);
+        for condition in self.conditions() {
+            match condition.holds_on_host(context) {
+                Some(false) => return Some(false),
+                None => runs = None,
+                Some(true) => {}
+            }
+        }
+        runs
+    }
+
+    /// Run the module if its conditions hold, within its timeout.
+    ///
+    /// # Errors
+    /// The module fails, unless [`ModuleOptions::continue_on_error`] is set, or is still running
+    /// after timing out.
+    pub fn run(&self, context: &Context) -> Result<Outcome> {
+        let name = self.module.name();
+        if let Some(condition) = self.conditions().find(|c| !c.holds(context)) {
+            tracing::info!(name, %condition, "Condition does not hold, skipping module");
+            return Ok(Outcome::Skipped(condition.clone()));
+        }
+        let res = match self.options.timeout_secs {
+            Some(secs) => self.run_with_timeout(context, Duration::from_secs(secs))?,
+            None => self.module.run(context),
+        };
+        match res {
+            Ok(()) => Ok(Outcome::Completed),
+            Err(err) if self.options.continue_on_error => {
+                tracing::warn!(name, ?err, "Module failed, continuing anyway");
+                Ok(Outcome::Failed(format!("{err:#}")))
+            }
+            Err(err) => Err(err.wrap_err(format!("{name} failed"))),
+        }
+    }
+
+    /// Run the module on a thread of its own, killing its commands if it takes longer than
+    /// `timeout`.
+    ///
+    /// Returns what the module returned, or that it timed out once it is stopped. A module that
+    /// is still running after being killed fails the whole installation, even with
+    /// [`ModuleOptions::continue_on_error`], since it would keep working on the installed system.
+    fn run_with_timeout(&self, context: &Context, timeout: Duration) -> Result<Result<()>> {
+        let name = self.module.name();
+        let (module, context) = (self.module.clone(), context.clone());
+        let (tx, rx) = mpsc::channel();
+        let (tid_tx, tid_rx) = mpsc::sync_channel(1);
+        let thread = std::thread::spawn(move || {
+            _ = tid_tx.send(nix::unistd::gettid());
+            _ = tx.send(module.run(&context));
+        });
+        let res = match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Disconnected) => Err(eyre!("{name} panicked")),
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::error!(name, ?timeout, "Module timed out, killing its commands");
+                if let Ok(tid) = tid_rx.try_recv() {
+                    kill_commands(tid);
+                }
+                if rx.recv_timeout(KILL_GRACE).is_err() {
+                    bail!(
+                        "{name} timed out after {}s and is still running",
+                        timeout.as_secs()
+                    );
+                }
+                Err(eyre!("{name} timed out after {}s", timeout.as_secs()))
+            }
+        };
+        // the module has returned, so this doesn't block
+        _ = thread.join();
+        Ok(res)
+    }
+
+    /// Describe what [`Entry::run`] would do, see [`PostInstallModule::plan`].
+    ///
+    /// # Errors
+    /// See [`PostInstallModule::plan`].
+    pub fn plan(&self, context: &Context, step: &mut Step) -> Result<()> {
+        use crate::plan::Action;
+        if let Some(condition) =
+            (self.conditions()).find(|condition| condition.holds_on_host(context) == Some(false))
+        {
+            step.push(Action::note(format!("skipped, {condition} does not hold")));
+            return Ok(());
+        }
+        for condition in (self.conditions()).filter(|c| c.holds_on_host(context).is_none()) {
+            step.push(Action::note(format!("only if {condition}")));
+        }
+        if self.options.continue_on_error {
+            step.push(Action::note("failures are ignored".to_owned()));
+        }
+        if let Some(secs) = self.options.timeout_secs {
+            step.push(Action::note(format!("times out after {secs}s")));
+        }
+        self.module.plan(context, step)
+    }
+}
+
+/// Kill the commands started by the thread `tid`, i.e. by a module that timed out, along with
+/// everything they started in turn.
+///
+/// Each process is stopped before its own children are listed, so that none can start another
+/// one behind our back.
+fn kill_commands(tid: nix::unistd::Pid) {
+    use nix::sys::signal::{Signal, kill};
+    let Ok(pids) = std::fs::read_to_string(format!("/proc/self/task/{tid}/children")) else {
+        tracing::warn!(%tid, "Cannot list the commands of the module, not killing any");
+        return;
+    };
+    let mut pending = parse_pids(&pids);
+    let mut stopped = vec![];
+    while let Some(pid) = pending.pop() {
+        if let Err(err) = kill(pid, Signal::SIGSTOP) {
+            tracing::warn!(%pid, ?err, "Cannot stop command");
+            continue;
+        }
+        pending.extend(children(pid));
+        stopped.push(pid);
+    }
+    for pid in stopped {
+        if let Err(err) = kill(pid, Signal::SIGKILL) {
+            tracing::warn!(%pid, ?err, "Cannot kill command");
+        }
+    }
+}
+
+/// The children of every thread of the process `pid`.
+fn children(pid: nix::unistd::Pid) -> Vec<nix::unistd::Pid> {
+    let tasks = std::fs::read_dir(format!("/proc/{pid}/task"))
+        .into_iter()
+        .flatten();
+    (tasks.flatten())
+        .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|pids| parse_pids(&pids))
+        .collect()
+}
+
+/// The PIDs in a `/proc/*/task/*/children` file.
+fn parse_pids(pids: &str) -> Vec<nix::unistd::Pid> {
+    (pids.split_whitespace())
+        .filter_map(|pid| pid.parse().ok())
+        .map(nix::unistd::Pid::from_raw)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_options() {
+        let entry: Entry = serde_json::from_value(serde_json::json!({
+            "module": "Script",
+            "when": ["bios", { "file_exists": "/usr/bin/true" }],
+            "continue_on_error": true,
+            "timeout_secs": 30
+        }))
+        .unwrap();
+        assert_eq!(entry.module, Module::Script(Script));
+        assert_eq!(
+            entry.options.when,
+            [
+                Condition::Bios,
+                Condition::FileExists("/usr/bin/true".into())
+            ]
+        );
+        assert!(entry.options.continue_on_error);
+
+        let plain: Entry =
+            serde_json::from_value(serde_json::json!({ "module": "Dracut" })).unwrap();
+        assert_eq!(plain, Module::Dracut(Dracut).into());
+        assert_eq!(
+            serde_json::to_value(&plain).unwrap(),
+            serde_json::json!({ "module": "Dracut" })
+        );
+
+        let context = |uefi| Context {
+            destination_disk: "/dev/vda".into(),
+            mirrors: vec![],
+            uefi,
+            image: false,
+            mounts: Mounts(vec![]),
+        };
+        assert_eq!(entry.runs_on_host(&context(true)), Some(false));
+        assert_eq!(entry.runs_on_host(&context(false)), None);
+        assert_eq!(
+            entry.run(&context(true)).unwrap(),
+            Outcome::Skipped(Condition::Bios)
+        );
+    }
+
+    #[test]
+    fn efi_stub_skips_images() {
+        let entry: Entry = serde_json::from_value(serde_json::json!({
+            "module": "EfiStub",
+            "distro_name": "Ultramarine Linux"
+        }))
+        .unwrap();
+        let context = |image| Context {
+            destination_disk: "/dev/loop0".into(),
+            mirrors: vec![],
+            uefi: true,
+            image,
+            mounts: Mounts(vec![]),
+        };
+        assert_eq!(entry.runs_on_host(&context(false)), Some(true));
+        assert_eq!(entry.runs_on_host(&context(true)), Some(false));
+        assert_eq!(
+            entry.run(&context(true)).unwrap(),
+            Outcome::Skipped(Condition::Hardware)
+        );
+    }
+
+    #[test]
+    fn kill_timed_out_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+        let script = format!("sleep 60 & echo $! > {}; wait", pidfile.display());
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            _ = tid_tx.send(nix::unistd::gettid());
+            module_command("sh").args(["-c", &script]).status()
+        });
+        let tid = tid_rx.recv().unwrap();
+        let grandchild = loop {
+            if let Ok(pid) = std::fs::read_to_string(&pidfile)
+                && !pid.trim().is_empty()
+            {
+                break pid.trim().to_owned();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        kill_commands(tid);
+        assert!(!thread.join().unwrap().unwrap().success());
+        // the grandchild is gone, or a zombie waiting for whoever inherited it
+        std::thread::sleep(Duration::from_millis(100));
+        let stat = std::fs::read_to_string(format!("/proc/{grandchild}/stat")).unwrap_or_default();
+        assert!(
+            stat.is_empty() || stat.split_whitespace().nth(2) == Some("Z"),
+            "{stat}"
+        );
+    }
 }
@@ -3,9 +3,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::backend::util::fs::{exist_then, exist_then_read_dir};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule};
+use crate::plan::{Action, Step};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct PrepareFedora;
 
 impl PostInstallModule for PrepareFedora {
@@ -35,4 +36,27 @@ impl PostInstallModule for PrepareFedora {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::remove_file("/var/lib/systemd/random-seed"))
+            .push(Action::write_file("/etc/machine-id", "empty machine-id"))
+            .push(Action::remove_file(
+                "/etc/NetworkManager/system-connections",
+            ))
+            .push(Action::remove_file("/var/lib/rpm/__db*"))
+            .push(Action::remove_file("/var/cache/dnf"));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::SystemPrepared]
+    }
+
+    fn requires(&self) -> &'static [Capability] {
+        &[Capability::KernelInstalled]
+    }
+
+    fn concurrent(&self) -> bool {
+        true
+    }
 }
@@ -1,11 +1,13 @@
-use super::{Context, PostInstallModule};
-use crate::stage;
+use super::{Capability, Context, PostInstallModule, module_command};
+use crate::{
+    plan::{Action, Step},
+    stage,
+};
 use color_eyre::{Result, eyre::bail};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::process::Command;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct ReinstallKernel;
 
 impl PostInstallModule for ReinstallKernel {
@@ -22,7 +24,7 @@ impl PostInstallModule for ReinstallKernel {
         // install kernel
 
         stage!(kernel "Reinstalling kernels" {
-            let kernel_install_cmd_status = Command::new("kernel-install")
+            let kernel_install_cmd_status = module_command("kernel-install")
                 .arg("add")
                 .arg(kver)
                 .arg(format!("/lib/modules/{kver}/vmlinuz"))
@@ -39,4 +41,20 @@ impl PostInstallModule for ReinstallKernel {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::target_command(
+            "kernel-install",
+            ["add", "<kver>", "/lib/modules/<kver>/vmlinuz", "--verbose"],
+        ));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::KernelInstalled]
+    }
+
+    fn requires(&self) -> &'static [Capability] {
+        &[Capability::BootCleaned]
+    }
 }
@@ -0,0 +1,208 @@
+//! Ordering and execution of postinstall modules.
+//!
+//! Modules declare the [`Capability`]s they [provide](PostInstallModule::provides) and
+//! [require](PostInstallModule::requires). The playbook lists modules in the order they run, and
+//! [`problems`] reports modules listed before a module they depend on. [`run`] starts every module
+//! as soon as the modules it depends on have completed, so [concurrent](PostInstallModule::concurrent)
+//! modules that don't depend on each other run at the same time.
+
+use super::{Capability, Context, Entry, Outcome, PostInstallModule};
+use crate::prelude::*;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::time::Instant;
+
+fn provides_any(entry: &Entry, capabilities: &[Capability]) -> bool {
+    (entry.module.provides().iter()).any(|capability| capabilities.contains(capability))
+}
+
+/// Whether `entry` has to wait for `earlier`, which is listed before it, to complete.
+fn depends_on(entry: &Entry, earlier: &Entry) -> bool {
+    !(entry.module.concurrent() && earlier.module.concurrent())
+        || provides_any(earlier, entry.module.requires())
+}
+
+/// For each entry, the indices of the earlier entries that have to complete before it starts.
+#[must_use]
+pub fn dependencies(entries: &[Entry]) -> Vec<Vec<usize>> {
+    (entries.iter().enumerate())
+        .map(|(i, entry)| {
+            (entries.iter().take(i).enumerate())
+                .filter(|(_, earlier)| depends_on(entry, earlier))
+                .map(|(j, _)| j)
+                .collect()
+        })
+        .collect()
+}
+
+/// Modules listed before a module providing something they require.
+///
+/// Modules whose conditions can't hold in `context` are left out.
+#[must_use]
+pub fn problems(entries: &[Entry], context: &Context) -> Vec<String> {
+    let entries = (entries.iter())
+        .filter(|entry| entry.runs_on_host(context) != Some(false))
+        .collect_vec();
+    let mut problems = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        for later in entries.iter().skip(i + 1) {
+            if provides_any(later, entry.module.requires()) {
+                problems.push(format!(
+                    "{} must run before {}",
+                    later.module.name(),
+                    entry.module.name()
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Running,
+    Done,
+}
+
+/// Run `entries` in dependency order, see the [module documentation](self).
+///
+/// Entries that are `done` already are not run again. `started` and `finished` are called from the
+/// calling thread with the index of each entry as it starts, and as it completes along with when it
+/// started.
+///
+/// # Errors
+/// A module failed. Modules that are already running are waited for, but no more are started.
+pub fn run<S, F>(
+    entries: &[Entry],
+    context: &Context,
+    done: &[bool],
+    mut started: S,
+    mut finished: F,
+) -> Result<()>
+where
+    S: FnMut(usize),
+    F: FnMut(usize, Outcome, Instant),
+{
+    let dependencies = dependencies(entries);
+    let mut states = (0..entries.len())
+        .map(|i| match done.get(i) {
+            Some(true) => State::Done,
+            _ => State::Pending,
+        })
+        .collect_vec();
+
+    std::thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        let mut running = 0_usize;
+        let mut error = None;
+        loop {
+            for (i, entry) in entries.iter().enumerate() {
+                let ready = error.is_none()
+                    && states.get(i) == Some(&State::Pending)
+                    && (dependencies.get(i).into_iter().flatten())
+                        .all(|&j| states.get(j) == Some(&State::Done));
+                let Some(state) = states.get_mut(i).filter(|_| ready) else {
+                    continue;
+                };
+                *state = State::Running;
+                running += 1;
+                started(i);
+                let tx = tx.clone();
+                scope.spawn(move || {
+                    let at = Instant::now();
+                    let res = std::panic::catch_unwind(AssertUnwindSafe(|| entry.run(context)))
+                        .unwrap_or_else(|_| Err(eyre!("{} panicked", entry.module.name())));
+                    _ = tx.send((i, at, res));
+                });
+            }
+
+            if running == 0 {
+                break;
+            }
+            let Ok((i, at, res)) = rx.recv() else {
+                unreachable!("a sender is kept alive while modules are running");
+            };
+            running -= 1;
+            match res {
+                Ok(outcome) => {
+                    if let Some(state) = states.get_mut(i) {
+                        *state = State::Done;
+                    }
+                    finished(i, outcome, at);
+                }
+                Err(err) if error.is_some() => {
+                    tracing::error!(?err, "Another module failed at the same time");
+                }
+                Err(err) => error = Some(err),
+            }
+        }
+        error.map_or(Ok(()), Err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::postinstall::Module;
+
+    fn entries(modules: serde_json::Value) -> Vec<Entry> {
+        serde_json::from_value(modules).unwrap()
+    }
+
+    #[test]
+    fn dependencies_and_order() {
+        let entries = entries(serde_json::json!([
+            { "module": "GRUB2" },
+            { "module": "Language", "lang": "en_US" },
+            { "module": "Fstab" },
+            { "module": "Dracut" },
+            { "module": "InitialSetup" },
+        ]));
+        // Language and Fstab don't need each other, but both have to wait for GRUB2
+        assert_eq!(
+            dependencies(&entries),
+            [vec![], vec![0], vec![0], vec![0, 1, 2], vec![0, 3]]
+        );
+
+        let context = Context {
+            destination_disk: "/dev/vda".into(),
+            mirrors: vec![],
+            uefi: false,
+            image: false,
+            mounts: Mounts(vec![]),
+        };
+        assert!(problems(&entries, &context).is_empty());
+        let mut reversed = entries;
+        reversed.reverse();
+        assert!(matches!(
+            reversed.get(1).map(|e| &e.module),
+            Some(Module::Dracut(_))
+        ));
+        assert_eq!(
+            problems(&reversed, &context),
+            ["Fstab must run before Dracut"]
+        );
+    }
+
+    #[test]
+    fn shipped_template_is_ordered() {
+        #[derive(Deserialize)]
+        struct Template {
+            postinstall: Vec<Entry>,
+        }
+        let template: Template =
+            toml::from_str(include_str!("../../templates/ultramarine.toml")).unwrap();
+        let context = Context {
+            destination_disk: "/dev/vda".into(),
+            mirrors: vec![],
+            uefi: true,
+            image: false,
+            mounts: Mounts(vec![]),
+        };
+        assert_eq!(
+            problems(&template.postinstall, &context),
+            Vec::<String>::new()
+        );
+    }
+}
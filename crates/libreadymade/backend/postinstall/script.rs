@@ -3,16 +3,17 @@ use std::os::unix::fs::PermissionsExt;
 use color_eyre::{Result, Section as _, eyre::Context as _};
 use serde::{Deserialize, Serialize};
 
-use super::{Context, PostInstallModule};
+use super::{Context, PostInstallModule, module_command};
+use crate::plan::{Action, Step};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Script;
 
 impl PostInstallModule for Script {
     #[allow(clippy::unwrap_in_result)]
     fn run(&self, context: &Context) -> Result<()> {
         if std::fs::exists("/etc/readymade/postinstall.sh").is_ok_and(|x| x) {
-            let cmd = std::process::Command::new("/etc/readymade/postinstall.sh")
+            let cmd = module_command("/etc/readymade/postinstall.sh")
                 .stdin(std::process::Stdio::piped())
                 .stdout(std::process::Stdio::piped())
                 .stderr(std::process::Stdio::piped())
@@ -22,7 +23,7 @@ impl PostInstallModule for Script {
         }
 
         if std::fs::exists("/usr/share/readymade/postinstall.sh").is_ok_and(|x| x) {
-            let cmd = std::process::Command::new("/usr/share/readymade/postinstall.sh")
+            let cmd = module_command("/usr/share/readymade/postinstall.sh")
                 .stdin(std::process::Stdio::piped())
                 .stdout(std::process::Stdio::piped())
                 .stderr(std::process::Stdio::piped())
@@ -35,7 +36,7 @@ impl PostInstallModule for Script {
             for f in std::fs::read_dir("/etc/readymade/postinstall.d/")? {
                 let f = f?;
                 if f.metadata()?.is_file() && f.metadata()?.permissions().mode() & 0o111 != 0 {
-                    let cmd = std::process::Command::new(f.path())
+                    let cmd = module_command(f.path())
                         .stdin(std::process::Stdio::piped())
                         .stdout(std::process::Stdio::piped())
                         .stderr(std::process::Stdio::piped())
@@ -49,7 +50,7 @@ impl PostInstallModule for Script {
             for f in std::fs::read_dir("/usr/share/readymade/postinstall.d/")? {
                 let f = f?;
                 if f.metadata()?.is_file() && f.metadata()?.permissions().mode() & 0o111 != 0 {
-                    let cmd = std::process::Command::new(f.path())
+                    let cmd = module_command(f.path())
                         .stdin(std::process::Stdio::piped())
                         .stdout(std::process::Stdio::piped())
                         .stderr(std::process::Stdio::piped())
@@ -61,6 +62,20 @@ impl PostInstallModule for Script {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        // which scripts exist is only known once the target has been copied,
+        // so list every location that would be looked at
+        for script in [
+            "/etc/readymade/postinstall.sh",
+            "/usr/share/readymade/postinstall.sh",
+            "/etc/readymade/postinstall.d/*",
+            "/usr/share/readymade/postinstall.d/*",
+        ] {
+            step.push(Action::target_command::<_, &str>(script, []));
+        }
+        Ok(())
+    }
 }
 
 fn handle_process(
@@ -1,22 +1,29 @@
 use color_eyre::{Result, eyre::bail};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
 
-use crate::stage;
+use crate::{
+    plan::{Action, Step},
+    stage,
+};
 
-use super::{Context, PostInstallModule};
+use super::{Capability, Context, PostInstallModule, module_command};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+const SETFILES_ARGS: [&str; 6] = [
+    "-e",
+    "/proc",
+    "-e",
+    "/sys",
+    "/etc/selinux/targeted/contexts/files/file_contexts",
+    "/",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct SELinux;
 
 impl PostInstallModule for SELinux {
     fn run(&self, _context: &Context) -> Result<()> {
         stage!(selinux "Setting SELinux labels" {
-            let setfiles_cmd_status = Command::new("setfiles")
-                .args(["-e", "/proc", "-e", "/sys"])
-                .arg("/etc/selinux/targeted/contexts/files/file_contexts")
-                .arg("/")
-                .status()?;
+            let setfiles_cmd_status = module_command("setfiles").args(SETFILES_ARGS).status()?;
 
             if !setfiles_cmd_status.success() {
                 bail!(
@@ -28,4 +35,25 @@ impl PostInstallModule for SELinux {
 
         Ok(())
     }
+
+    fn plan(&self, _context: &Context, step: &mut Step) -> Result<()> {
+        step.push(Action::target_command("setfiles", SETFILES_ARGS));
+        Ok(())
+    }
+
+    fn provides(&self) -> &'static [Capability] {
+        &[Capability::SelinuxRelabelled]
+    }
+
+    fn requires(&self) -> &'static [Capability] {
+        &[
+            Capability::BootCleaned,
+            Capability::CrypttabWritten,
+            Capability::FstabWritten,
+            Capability::GrubConfigured,
+            Capability::InitramfsBuilt,
+            Capability::KernelInstalled,
+            Capability::SystemPrepared,
+        ]
+    }
 }
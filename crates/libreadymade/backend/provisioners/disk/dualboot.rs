@@ -0,0 +1,552 @@
+//! Installing next to another operating system.
+//!
+//! The filesystem of an existing partition is shrunk, then the partition itself, and xbootldr and
+//! root partitions are created in the space freed after it. The ESP of the other system is reused,
+//! so both systems show up in the firmware boot menu.
+
+use ::gpt::{GptConfig, GptDisk, partition_types};
+use bytesize::ByteSize;
+use std::fs::File;
+
+use super::gpt::{
+    ALIGNMENT, Filesystem, GptPartition, PartitionType, logical_block_size, reread_commands,
+    run_command,
+};
+use crate::{
+    backend::{
+        provisioners::disk::{DiskProvisionerModule, dry_run},
+        util::fs::{get_whole_disk, partition_node, partition_number},
+    },
+    plan::{Action, Step},
+    prelude::*,
+};
+
+/// Where a btrfs filesystem is mounted while it is shrunk, since btrfs only resizes mounted.
+const SHRINK_MOUNTPOINT: &str = "/run/readymade/shrink";
+
+const fn default_xbootldr_size() -> ByteSize {
+    ByteSize::gib(1)
+}
+
+const fn default_root_format() -> Filesystem {
+    Filesystem::Btrfs
+}
+
+/// Shrinks a partition and installs into the space freed after it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct DualBoot {
+    /// The partition of the other system, e.g. the Windows `C:` drive. It must be on the
+    /// destination disk.
+    pub partition: PathBuf,
+    /// What to shrink [`Self::partition`] to, rounded down to a whole MiB.
+    #[schemars(with = "String")]
+    pub shrink_to: ByteSize,
+    #[serde(default = "default_xbootldr_size")]
+    #[schemars(with = "String")]
+    pub xbootldr_size: ByteSize,
+    #[serde(default = "default_root_format")]
+    pub root_format: Filesystem,
+}
+
+/// Filesystems that can be shrunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shrinkable {
+    Ntfs,
+    Ext4,
+    Btrfs,
+}
+
+impl std::fmt::Display for Shrinkable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ntfs => "ntfs",
+            Self::Ext4 => "ext4",
+            Self::Btrfs => "btrfs",
+        })
+    }
+}
+
+impl Shrinkable {
+    /// Probe the filesystem on `partition`.
+    ///
+    /// # Errors
+    /// The filesystem cannot be probed, or cannot be shrunk.
+    pub fn detect(partition: &Path) -> Result<Self> {
+        let fstype = output(
+            Command::new("blkid")
+                .args(["--output", "value", "--match-tag", "TYPE"])
+                .arg(partition),
+        )?;
+        Ok(match fstype.trim() {
+            "ntfs" => Self::Ntfs,
+            "ext4" => Self::Ext4,
+            "btrfs" => Self::Btrfs,
+            "" => bail!("{} has no filesystem", partition.display()),
+            fstype => bail!("cannot shrink {fstype} filesystems"),
+        })
+    }
+
+    /// The smallest size the filesystem on `partition` can be shrunk to, in bytes.
+    ///
+    /// # Errors
+    /// The filesystem tools failed, or their output is not understood.
+    pub fn min_size(self, partition: &Path) -> Result<u64> {
+        let size = match self {
+            Self::Ntfs => number_after(
+                &output(
+                    Command::new("ntfsresize")
+                        .args(["--info", "--force", "--no-progress-bar"])
+                        .arg(partition),
+                )?,
+                "You might resize at ",
+            ),
+            Self::Ext4 => {
+                let blocks = number_after(
+                    &output(Command::new("resize2fs").arg("-P").arg(partition))?,
+                    "Estimated minimum size of the filesystem:",
+                );
+                let block_size = number_after(
+                    &output(Command::new("dumpe2fs").arg("-h").arg(partition))?,
+                    "Block size:",
+                );
+                blocks.zip(block_size).map(|(n, size)| n * size)
+            }
+            Self::Btrfs => with_mounted(partition, |mountpoint| {
+                Ok(number_after(
+                    &output(
+                        Command::new("btrfs")
+                            .args(["inspect-internal", "min-dev-size"])
+                            .arg(mountpoint),
+                    )?,
+                    "",
+                ))
+            })?,
+        };
+        size.ok_or_else(|| eyre!("cannot tell how small the {self} filesystem can get"))
+    }
+
+    /// Commands shrinking the filesystem on `partition` to `size` bytes.
+    ///
+    /// btrfs is resized through [`SHRINK_MOUNTPOINT`], which it has to be mounted on.
+    fn shrink_commands(self, partition: &Path, size: u64) -> Vec<Command> {
+        match self {
+            Self::Ntfs => {
+                let mut cmd = Command::new("ntfsresize");
+                cmd.args(["--force", "--no-progress-bar", "--size", &size.to_string()])
+                    .arg(partition);
+                vec![cmd]
+            }
+            Self::Ext4 => {
+                // resize2fs refuses filesystems that weren't checked since they were last mounted
+                let mut fsck = Command::new("e2fsck");
+                fsck.args(["-f", "-y"]).arg(partition);
+                let mut cmd = Command::new("resize2fs");
+                cmd.arg(partition).arg(format!("{}K", size / 1024));
+                vec![fsck, cmd]
+            }
+            Self::Btrfs => {
+                let mut cmd = Command::new("btrfs");
+                cmd.args(["filesystem", "resize", &size.to_string(), SHRINK_MOUNTPOINT]);
+                vec![cmd]
+            }
+        }
+    }
+
+    fn shrink(self, partition: &Path, size: u64) -> Result<()> {
+        let run_all =
+            || (self.shrink_commands(partition, size).iter_mut()).try_for_each(run_shrink_command);
+        match self {
+            Self::Btrfs => with_mounted(partition, |_| run_all()),
+            Self::Ntfs | Self::Ext4 => run_all(),
+        }
+    }
+}
+
+fn run_shrink_command(cmd: &mut Command) -> Result<()> {
+    if cmd.get_program() != "e2fsck" {
+        return run_command(cmd, None);
+    }
+    // 1 means errors were found and corrected
+    let status = cmd.status().wrap_err("cannot run e2fsck")?;
+    if !matches!(status.code(), Some(0 | 1)) {
+        bail!("e2fsck failed with status code {:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Run `f` with `partition` mounted on [`SHRINK_MOUNTPOINT`].
+fn with_mounted<T, F>(partition: &Path, f: F) -> Result<T>
+where
+    F: FnOnce(&Path) -> Result<T>,
+{
+    let mountpoint = Path::new(SHRINK_MOUNTPOINT);
+    std::fs::create_dir_all(mountpoint)?;
+    sys_mount::Mount::builder()
+        .mount(partition, mountpoint)
+        .wrap_err_with(|| format!("cannot mount {}", partition.display()))?;
+    scopeguard::defer! {
+        if let Err(err) = nix::mount::umount(mountpoint) {
+            tracing::warn!(?partition, ?err, "Cannot unmount shrunk filesystem");
+        }
+    };
+    f(mountpoint)
+}
+
+/// Run `cmd` and return what it printed.
+fn output(cmd: &mut Command) -> Result<String> {
+    tracing::debug!(?cmd, "Running command");
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let out = (cmd.stderr(std::process::Stdio::inherit()).output())
+        .wrap_err_with(|| format!("cannot run {program}"))?;
+    if !out.status.success() {
+        bail!("{program} failed with status code {:?}", out.status.code());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The number following `prefix` on the first line that has it.
+fn number_after(output: &str, prefix: &str) -> Option<u64> {
+    (output.lines())
+        .find_map(|line| line.split_once(prefix).map(|(_, rest)| rest.trim_start()))
+        .and_then(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest.get(..end)?.parse().ok()
+        })
+}
+
+/// A partition to create, in sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placement {
+    id: u32,
+    first_lba: u64,
+    len: u64,
+}
+
+/// Where the partitions go once the shrunk partition ends at `last_lba`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    esp: u32,
+    target: u32,
+    last_lba: u64,
+    xbootldr: Placement,
+    root: Placement,
+}
+
+/// Place the xbootldr and root partitions in the free region right after `last_lba`.
+///
+/// `free` lists the free regions as `(first_lba, len)`, as the `gpt` crate does.
+fn place(
+    last_lba: u64,
+    free: &[(u64, u64)],
+    ids: [u32; 2],
+    alignment: u64,
+    xbootldr_len: u64,
+) -> Result<[Placement; 2]> {
+    let &(start, len) = (free.iter())
+        .find(|(start, _)| *start == last_lba + 1)
+        .ok_or_else(|| eyre!("no space was freed"))?;
+    let end = start + len;
+    let [xbootldr_id, root_id] = ids;
+    let xbootldr = Placement {
+        id: xbootldr_id,
+        first_lba: start.next_multiple_of(alignment),
+        len: xbootldr_len,
+    };
+    let root_start = (xbootldr.first_lba + xbootldr.len).next_multiple_of(alignment);
+    if root_start >= end {
+        bail!(
+            "the freed space is too small, the xbootldr partition alone needs {}",
+            root_start - start
+        );
+    }
+    let root = Placement {
+        id: root_id,
+        first_lba: root_start,
+        len: end - root_start,
+    };
+    Ok([xbootldr, root])
+}
+
+impl DualBoot {
+    /// [`Self::shrink_to`], rounded down so the freed space starts aligned.
+    const fn shrunk_size(&self) -> u64 {
+        self.shrink_to.as_u64() / ALIGNMENT * ALIGNMENT
+    }
+
+    /// The partitions created in the freed space.
+    fn new_partitions(&self, playbook: &crate::playbook::Playbook) -> [GptPartition; 2] {
+        [
+            GptPartition {
+                part_type: PartitionType::Xbootldr,
+                label: Some("xbootldr".to_owned()),
+                size: Some(self.xbootldr_size),
+                format: Some(Filesystem::Ext4),
+                mountpoint: Some("/boot".into()),
+                options: String::new(),
+                encrypt: false,
+            },
+            GptPartition {
+                part_type: PartitionType::Root,
+                label: Some("root".to_owned()),
+                size: None,
+                format: Some(self.root_format),
+                mountpoint: Some("/".into()),
+                options: if self.root_format == Filesystem::Btrfs {
+                    "compress=zstd:1".to_owned()
+                } else {
+                    String::new()
+                },
+                encrypt: playbook.encryption.is_some(),
+            },
+        ]
+    }
+
+    fn open_table(disk: &Path, writable: bool) -> Result<GptDisk<File>> {
+        GptConfig::new()
+            .writable(writable)
+            .logical_block_size(logical_block_size(disk)?)
+            .open(disk)
+            .wrap_err_with(|| format!("cannot read the partition table of {}", disk.display()))
+    }
+
+    /// Work out where everything goes, without writing anything.
+    fn layout(&self, disk: &Path) -> Result<Layout> {
+        // fail before anything is shrunk
+        PartitionType::Root.gpt_type()?;
+        let whole_disk = get_whole_disk(&self.partition)?;
+        if Path::new(&whole_disk).canonicalize()? != disk.canonicalize()? {
+            bail!(
+                "{} is on {whole_disk}, not on the destination disk",
+                self.partition.display()
+            );
+        }
+        let target = u32::try_from(partition_number(&self.partition)?)?;
+
+        let mut table = Self::open_table(disk, false)?;
+        let lb = u64::from(*table.logical_block_size());
+        let mut partitions = table.partitions().clone();
+        let esp = (partitions.iter())
+            .find(|(_, part)| part.part_type_guid == partition_types::EFI)
+            .map(|(id, _)| *id)
+            .ok_or_else(|| eyre!("{} has no ESP to share", disk.display()))?;
+        let part = (partitions.get_mut(&target))
+            .ok_or_else(|| eyre!("{} is not in the partition table", self.partition.display()))?;
+        let last_lba = part.first_lba + self.shrunk_size() / lb - 1;
+        if last_lba >= part.last_lba {
+            bail!(
+                "{} is already smaller than {}",
+                self.partition.display(),
+                self.shrink_to
+            );
+        }
+        part.last_lba = last_lba;
+
+        let mut ids = (1..).filter(|id| !partitions.contains_key(id));
+        let ids = [
+            ids.next().unwrap_or_default(),
+            ids.next().unwrap_or_default(),
+        ];
+        table.update_partitions(partitions)?;
+        let [xbootldr, root] = place(
+            last_lba,
+            &table.find_free_sectors(),
+            ids,
+            ALIGNMENT / lb,
+            self.xbootldr_size.as_u64().div_ceil(lb),
+        )?;
+        Ok(Layout {
+            esp,
+            target,
+            last_lba,
+            xbootldr,
+            root,
+        })
+    }
+
+    /// Shrink the partition and add the new ones, as laid out.
+    fn write_table(
+        &self,
+        disk: &Path,
+        layout: &Layout,
+        playbook: &crate::playbook::Playbook,
+    ) -> Result<()> {
+        let mut table = Self::open_table(disk, true)?;
+        let mut partitions = table.partitions().clone();
+        if let Some(part) = partitions.get_mut(&layout.target) {
+            part.last_lba = layout.last_lba;
+        }
+        table.update_partitions(partitions)?;
+        for (placement, part) in [layout.xbootldr, layout.root]
+            .into_iter()
+            .zip(self.new_partitions(playbook))
+        {
+            table.add_partition_at(
+                part.label.as_deref().unwrap_or_default(),
+                placement.id,
+                placement.first_lba,
+                placement.len,
+                part.part_type.gpt_type()?,
+                0,
+            )?;
+        }
+        table.write()?.sync_all()?;
+        Ok(())
+    }
+
+    fn mounts(&self, playbook: &crate::playbook::Playbook, disk: &Path, layout: &Layout) -> Mounts {
+        let esp = Mount {
+            label: None,
+            partition: partition_node(disk, layout.esp as usize),
+            mountpoint: "/boot/efi".into(),
+            options: "umask=0077,shortname=winnt".to_owned(),
+            encryption_type: None,
+            gpt_type: std::sync::OnceLock::default(),
+        };
+        _ = esp.gpt_type.set(partition_types::EFI);
+        let new = ([layout.xbootldr, layout.root].into_iter())
+            .zip(self.new_partitions(playbook))
+            .filter_map(|(placement, part)| {
+                part.mount(partition_node(disk, placement.id as usize), playbook)
+            });
+        let mut mounts = Mounts(new.chain([esp]).collect());
+        mounts.sort_mounts();
+        mounts
+    }
+}
+
+impl DiskProvisionerModule for DualBoot {
+    fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        let layout = self.layout(disk)?;
+        let mounts = self.mounts(playbook, disk, &layout);
+        if dry_run() {
+            tracing::info!(?disk, "Dry run, leaving the disk alone");
+            return Ok(mounts);
+        }
+
+        let fs = Shrinkable::detect(&self.partition)?;
+        let min_size = fs.min_size(&self.partition)?;
+        if self.shrunk_size() < min_size {
+            bail!(
+                "{} cannot be shrunk below {}",
+                self.partition.display(),
+                ByteSize::b(min_size)
+            );
+        }
+        tracing::info!(partition = ?self.partition, %fs, "Shrinking filesystem");
+        fs.shrink(&self.partition, self.shrunk_size())?;
+        self.write_table(disk, &layout, playbook)?;
+        for mut cmd in reread_commands(disk) {
+            run_command(&mut cmd, None)?;
+        }
+        for (placement, part) in [layout.xbootldr, layout.root]
+            .into_iter()
+            .zip(self.new_partitions(playbook))
+        {
+            part.format(partition_node(disk, placement.id as usize), playbook)?;
+        }
+        Ok(mounts)
+    }
+
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        let layout = self.layout(disk)?;
+        let fs = Shrinkable::detect(&self.partition)?;
+        let size = ByteSize::b(self.shrunk_size());
+        step.push(Action::note(format!(
+            "the {fs} filesystem on {} must fit in {size}",
+            self.partition.display()
+        )));
+        if fs == Shrinkable::Btrfs {
+            step.push(Action::Mount {
+                source: self.partition.clone(),
+                target: SHRINK_MOUNTPOINT.into(),
+                options: None,
+            });
+        }
+        for cmd in fs.shrink_commands(&self.partition, size.as_u64()) {
+            step.push(Action::from_command(&cmd, false));
+        }
+        step.push(Action::DiskWrite {
+            device: self.partition.clone(),
+            description: format!("shrink the partition to {size}"),
+        });
+        let new = [layout.xbootldr, layout.root]
+            .into_iter()
+            .zip(self.new_partitions(playbook))
+            .map(|(placement, part)| (partition_node(disk, placement.id as usize), part))
+            .collect_vec();
+        for (node, part) in &new {
+            step.push(Action::DiskWrite {
+                device: node.clone(),
+                description: format!("{} in the freed space", part.describe()?),
+            });
+        }
+        for cmd in reread_commands(disk) {
+            step.push(Action::from_command(&cmd, false));
+        }
+        for (node, part) in new {
+            part.plan_format(node, playbook, step);
+        }
+        step.push(Action::note(format!(
+            "share the ESP {} with the other system",
+            partition_node(disk, layout.esp as usize).display()
+        )));
+        Ok(self.mounts(playbook, disk, &layout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_min_sizes() {
+        let ntfsresize = "ntfsresize v2022.10.3 (libntfs-3g)\n\
+            Device name        : /dev/sda3\n\
+            You might resize at 33607680 bytes or 34 MB (freeing 69 MB).\n";
+        assert_eq!(
+            number_after(ntfsresize, "You might resize at "),
+            Some(33_607_680)
+        );
+        assert_eq!(
+            number_after(
+                "Estimated minimum size of the filesystem: 1234567\n",
+                "Estimated minimum size of the filesystem:"
+            ),
+            Some(1_234_567)
+        );
+        assert_eq!(
+            number_after("Block size:               4096\n", "Block size:"),
+            Some(4096)
+        );
+        assert_eq!(
+            number_after("5379194880 bytes (5.01GiB)\n", ""),
+            Some(5_379_194_880)
+        );
+        assert_eq!(number_after("nothing here\n", "Block size:"), None);
+    }
+
+    #[test]
+    fn place_in_freed_space() {
+        // 512 byte sectors: partition shrunk to end at 1 GiB, then 9 GiB freed before the next one
+        let last_lba = 2048 + 2 * 1024 * 1024 - 2048 - 1;
+        let free = [(34, 2014), (last_lba + 1, 18 * 1024 * 1024)];
+        let [xbootldr, root] = place(last_lba, &free, [4, 5], 2048, 2 * 1024 * 1024).unwrap();
+        assert_eq!(xbootldr.first_lba % 2048, 0);
+        assert!(xbootldr.first_lba > last_lba);
+        assert_eq!(root.first_lba, xbootldr.first_lba + xbootldr.len);
+        assert_eq!(root.first_lba + root.len, last_lba + 1 + 18 * 1024 * 1024);
+        assert_eq!((xbootldr.id, root.id), (4, 5));
+
+        let err = place(last_lba, &[(34, 2014)], [4, 5], 2048, 2048).unwrap_err();
+        assert_eq!(err.to_string(), "no space was freed");
+        let err = place(last_lba, &[(last_lba + 1, 4096)], [4, 5], 2048, 4096).unwrap_err();
+        assert!(
+            err.to_string().starts_with("the freed space is too small"),
+            "{err}"
+        );
+    }
+}
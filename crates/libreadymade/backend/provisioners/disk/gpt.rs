@@ -0,0 +1,525 @@
+//! Partitioning without `systemd-repart`.
+//!
+//! The partition table is written with the [`gpt`](::gpt) crate from a declarative layout, then each
+//! partition is encrypted with `cryptsetup` and formatted with the matching `mkfs.*` tool. This only
+//! needs util-linux, cryptsetup and the filesystem tools, so it also works on hosts with a systemd
+//! older than the one [`Repart`](super::repart::Repart) requires.
+
+use ::gpt::{GptConfig, GptDisk, disk::LogicalBlockSize, mbr::ProtectiveMBR, partition_types};
+use bytesize::ByteSize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Seek, SeekFrom, Write as _};
+use std::process::Stdio;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::{
+    backend::{
+        mounts::luks_decrypt,
+        provisioners::disk::{DiskProvisionerModule, dry_run, ini_value},
+        util::fs::partition_node,
+    },
+    plan::{Action, Step},
+    prelude::*,
+};
+
+/// Partitions start on 1 MiB boundaries, like every other partitioning tool does.
+pub(super) const ALIGNMENT: u64 = bytesize::MIB;
+
+/// Partition types, named like the `Type=` of repart definitions.
+///
+/// See the [Discoverable Partitions Specification](https://uapi-group.org/specifications/specs/discoverable_partitions_specification/).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionType {
+    Esp,
+    Xbootldr,
+    /// BIOS boot partition, for GRUB on BIOS systems.
+    BiosBoot,
+    /// Root partition for the architecture Readymade was built for.
+    Root,
+    Home,
+    Srv,
+    Var,
+    Swap,
+    /// LVM physical volume.
+    Lvm,
+    /// Member of an md RAID array.
+    Raid,
+    LinuxGeneric,
+}
+
+impl PartitionType {
+    /// The type GUID, or `None` for the root partition of an architecture whose type isn't known.
+    #[allow(clippy::unnecessary_wraps)] // `None` on other architectures
+    pub(super) const fn guid(self) -> Option<&'static str> {
+        Some(match self {
+            Self::Esp => "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+            Self::Xbootldr => "bc13c2ff-59e6-4262-a352-b275fd6f7172",
+            Self::BiosBoot => "21686148-6449-6e6f-744e-656564454649",
+            #[cfg(target_arch = "aarch64")]
+            Self::Root => "b921b045-1df0-41c3-af44-4c6f280d3fae",
+            #[cfg(target_arch = "x86_64")]
+            Self::Root => "4f68bce3-e8cd-4db1-96e7-fbcaf984b709",
+            #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+            Self::Root => return None,
+            Self::Home => "933ac7e1-2eb4-4f13-b844-0e14e2aef915",
+            Self::Srv => "3b8f8425-20e0-4f3b-907f-1a25a76f98e8",
+            Self::Var => "4d21b016-b534-45c2-a9fb-5c16e091fd2d",
+            Self::Swap => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+            Self::Lvm => "e6d6d379-f507-44c2-a23c-238f2a3df928",
+            Self::Raid => "a19d880f-05fc-4d3b-a006-743f0f84911e",
+            Self::LinuxGeneric => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+        })
+    }
+
+    /// # Errors
+    /// The type of the root partition isn't known for the architecture Readymade was built for.
+    pub(super) fn gpt_type(self) -> Result<partition_types::Type> {
+        if self == Self::Esp {
+            return Ok(partition_types::EFI);
+        }
+        // a root partition of the wrong type is not found by systemd-gpt-auto-generator
+        let guid = (self.guid()).ok_or_else(|| {
+            eyre!("the GPT type of the root partition is only known for x86-64 and AArch64")
+        })?;
+        Ok(partition_types::Type::from(
+            Uuid::from_str(guid).expect("GUIDs above are valid"),
+        ))
+    }
+}
+
+/// Filesystems a partition can be formatted as.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Vfat,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Swap,
+}
+
+impl Filesystem {
+    /// Build the command formatting `device` with this filesystem.
+    pub(super) fn mkfs_command(self, device: &Path, label: Option<&str>) -> Command {
+        let (program, args, label_flag): (_, &[_], _) = match self {
+            Self::Vfat => ("mkfs.vfat", &["-F", "32"], "-n"),
+            Self::Ext4 => ("mkfs.ext4", &["-F"], "-L"),
+            Self::Xfs => ("mkfs.xfs", &["-f"], "-L"),
+            // same as what we ask of repart, see `repart_command`
+            Self::Btrfs => ("mkfs.btrfs", &["-f", "--nodiscard"], "-L"),
+            Self::Swap => ("mkswap", &[], "-L"),
+        };
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        if let Some(label) = label {
+            cmd.args([label_flag, label]);
+        }
+        cmd.arg(device);
+        cmd
+    }
+}
+
+/// A partition in a [`Gpt`] layout.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct GptPartition {
+    #[serde(rename = "type")]
+    pub part_type: PartitionType,
+    /// GPT partition name, also used as the filesystem label and the LUKS mapper name.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Size of the partition, e.g. `"512 MiB"`. The last partition may leave this out to fill the
+    /// rest of the disk.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub size: Option<ByteSize>,
+    /// Leave out to keep the partition unformatted.
+    #[serde(default)]
+    pub format: Option<Filesystem>,
+    #[serde(default)]
+    pub mountpoint: Option<PathBuf>,
+    /// Raw text `mountopts`
+    #[serde(default)]
+    pub options: String,
+    /// Encrypt the partition with LUKS, using the encryption settings of the playbook.
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+impl GptPartition {
+    /// What creating this partition does, for plans.
+    pub(super) fn describe(&self) -> Result<String> {
+        let mut description = format!("create {} partition", ini_value(&self.part_type)?);
+        if let Some(label) = &self.label {
+            write!(description, " labelled {label:?}")?;
+        }
+        if let Some(size) = self.size {
+            write!(description, " of {size}")?;
+        }
+        Ok(description)
+    }
+
+    /// The mount of this partition once it is created as `node`, if it has a mountpoint.
+    pub(super) fn mount(
+        &self,
+        node: PathBuf,
+        playbook: &crate::playbook::Playbook,
+    ) -> Option<Mount> {
+        let tpm = playbook.encryption.as_ref().is_some_and(|e| e.tpm);
+        let mount = Mount {
+            label: self.label.clone(),
+            partition: node,
+            mountpoint: self.mountpoint.clone()?,
+            options: self.options.clone(),
+            encryption_type: self.encrypt.then_some(if tpm {
+                EncryptionOption::KeyFileTpm2
+            } else {
+                EncryptionOption::KeyFile
+            }),
+            gpt_type: std::sync::OnceLock::default(),
+        };
+        // the type is known already, so `Mount::get_gpt_type` doesn't have to probe
+        if let Ok(gpt_type) = self.part_type.gpt_type() {
+            _ = mount.gpt_type.set(gpt_type);
+        }
+        Some(mount)
+    }
+
+    /// Encrypt and format the freshly created partition `node`.
+    pub(super) fn format(&self, node: PathBuf, playbook: &crate::playbook::Playbook) -> Result<()> {
+        let tpm = playbook.encryption.as_ref().is_some_and(|e| e.tpm);
+        let encryption = (playbook.encryption_key()?).zip(self.label.as_deref());
+        let device = if let (true, Some((key, label))) = (self.encrypt, encryption) {
+            run_command(&mut luks_format_command(&node, label), Some(key))?;
+            if tpm {
+                run_command(&mut tpm_enroll_command(&node), Some(key))?;
+            }
+            luks_decrypt(&node.to_string_lossy(), key, label)?
+        } else {
+            node
+        };
+        if let Some(format) = self.format {
+            run_command(
+                &mut format.mkfs_command(&device, self.label.as_deref()),
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Describe [`Self::format`] as plan actions.
+    pub(super) fn plan_format(
+        &self,
+        node: PathBuf,
+        playbook: &crate::playbook::Playbook,
+        step: &mut Step,
+    ) {
+        let tpm = playbook.encryption.as_ref().is_some_and(|e| e.tpm);
+        let device = if let (true, Some(label)) = (self.encrypt, &self.label) {
+            step.push(Action::from_command(
+                &luks_format_command(&node, label),
+                false,
+            ));
+            if tpm {
+                step.push(Action::from_command(&tpm_enroll_command(&node), false));
+            }
+            step.push(Action::host_command(
+                "cryptsetup",
+                [
+                    "open".to_owned(),
+                    node.to_string_lossy().to_string(),
+                    label.clone(),
+                ],
+            ));
+            PathBuf::from(format!("/dev/mapper/{label}"))
+        } else {
+            node
+        };
+        if let Some(format) = self.format {
+            let cmd = format.mkfs_command(&device, self.label.as_deref());
+            step.push(Action::from_command(&cmd, false));
+        }
+    }
+}
+
+/// Writes a new GPT from a list of partitions, then formats them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Gpt {
+    /// The partitions, in the order they are laid out on the disk.
+    pub partitions: Vec<GptPartition>,
+}
+
+impl Gpt {
+    /// Check the layout against the playbook.
+    fn check(&self, playbook: &crate::playbook::Playbook) -> Result<()> {
+        if let Some(i) = (self.partitions.iter().rev().skip(1)).position(|p| p.size.is_none()) {
+            bail!(
+                "partition {} has no size, only the last partition can fill the rest of the disk",
+                self.partitions.len() - 1 - i
+            );
+        }
+        for (i, part) in self.partitions.iter().enumerate() {
+            if part.encrypt && playbook.encryption.is_none() {
+                bail!(
+                    "partition {} is encrypted, but the playbook sets no encryption",
+                    i + 1
+                );
+            }
+            if part.encrypt && part.label.is_none() {
+                bail!("partition {} is encrypted, so it needs a label", i + 1);
+            }
+            (part.part_type.gpt_type()).wrap_err_with(|| format!("partition {}", i + 1))?;
+        }
+        Ok(())
+    }
+
+    /// The mounts of the partitions of this layout on `disk`.
+    fn mounts(&self, playbook: &crate::playbook::Playbook, disk: &Path) -> Mounts {
+        Mounts(
+            (self.partitions.iter().enumerate())
+                .filter_map(|(i, part)| part.mount(partition_node(disk, i + 1), playbook))
+                .collect(),
+        )
+    }
+
+    /// Replace the partition table of `disk` with this layout.
+    pub(super) fn write_table(&self, disk: &Path) -> Result<()> {
+        let mut device = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(disk)
+            .wrap_err("cannot open block device")?;
+        let block_size = logical_block_size(disk)?;
+        let lb = u64::from(block_size);
+        let disk_size = device.seek(SeekFrom::End(0))?;
+        ProtectiveMBR::with_lb_size(
+            u32::try_from((disk_size / lb).saturating_sub(1)).unwrap_or(u32::MAX),
+        )
+        .overwrite_lba0(&mut device)
+        .wrap_err("cannot write protective MBR")?;
+
+        let mut table = GptConfig::new()
+            .writable(true)
+            .logical_block_size(block_size)
+            .create_from_device(device, None)
+            .wrap_err("cannot create GPT")?;
+        table.update_partitions(BTreeMap::new())?;
+        let alignment = ALIGNMENT / lb;
+        for (i, part) in self.partitions.iter().enumerate() {
+            let size = (part.size)
+                .map_or_else(
+                    || free_space(&table, alignment, lb),
+                    |size| Ok(size.as_u64()),
+                )
+                .wrap_err_with(|| format!("no space left on {}", disk.display()))?;
+            let name = part.label.as_deref().unwrap_or_default();
+            table
+                .add_partition(name, size, part.part_type.gpt_type()?, 0, Some(alignment))
+                .wrap_err_with(|| format!("cannot fit partition {} on the disk", i + 1))?;
+        }
+        table.write()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Bytes in the largest free region of `table`, after aligning its start to `alignment` sectors.
+fn free_space<D>(table: &GptDisk<D>, alignment: u64, lb: u64) -> Result<u64> {
+    let (start, len) = (table.find_free_sectors().into_iter())
+        .max_by_key(|(_, len)| *len)
+        .ok_or_else(|| eyre!("the partition table is full"))?;
+    let skip = start.next_multiple_of(alignment) - start;
+    Ok(len.saturating_sub(skip) * lb)
+}
+
+/// Logical block size of `disk`, as reported by the kernel.
+pub(super) fn logical_block_size(disk: &Path) -> Result<LogicalBlockSize> {
+    let name = (disk.canonicalize()?.file_name())
+        .ok_or_else(|| eyre!("{} is not a block device", disk.display()))?
+        .to_owned();
+    let size = std::fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(name)
+            .join("queue/logical_block_size"),
+    )
+    .unwrap_or_default();
+    Ok(match size.trim() {
+        "4096" => LogicalBlockSize::Lb4096,
+        _ => LogicalBlockSize::Lb512,
+    })
+}
+
+/// Make the kernel pick up the new partition table.
+///
+/// Unlike rereading the whole table, `partx` updates the partitions one by one, so it doesn't fail
+/// when something still holds the disk open.
+pub(super) fn reread_commands(disk: &Path) -> [Command; 2] {
+    let mut partx = Command::new("partx");
+    partx.arg("--update").arg(disk);
+    let mut settle = Command::new("udevadm");
+    settle.arg("settle");
+    [partx, settle]
+}
+
+fn luks_format_command(node: &Path, label: &str) -> Command {
+    let mut cmd = Command::new("cryptsetup");
+    cmd.args([
+        "luksFormat",
+        "--batch-mode",
+        "--type",
+        "luks2",
+        "--label",
+        label,
+    ])
+    .args(["--key-file", "-"])
+    .arg(node);
+    cmd
+}
+
+fn tpm_enroll_command(node: &Path) -> Command {
+    let mut cmd = Command::new("systemd-cryptenroll");
+    cmd.args(["--tpm2-device", "auto", "--unlock-key-file", "/dev/stdin"])
+        .arg(node);
+    cmd
+}
+
+/// Run `cmd`, passing `input` on stdin.
+pub(super) fn run_command(cmd: &mut Command, input: Option<&str>) -> Result<()> {
+    tracing::debug!(?cmd, "Running command");
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let mut child = (cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    }))
+    .spawn()
+    .wrap_err_with(|| format!("cannot run {program}"))?;
+    // dropping stdin closes it, so the key is read up to EOF
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("{program} failed with status code {:?}", status.code());
+    }
+    Ok(())
+}
+
+impl DiskProvisionerModule for Gpt {
+    fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        self.check(playbook)?;
+        let disk = playbook.disk()?;
+        let mounts = self.mounts(playbook, disk);
+        if dry_run() {
+            tracing::info!(?disk, "Dry run, leaving the disk alone");
+            return Ok(mounts);
+        }
+
+        self.write_table(disk)?;
+        for mut cmd in reread_commands(disk) {
+            run_command(&mut cmd, None)?;
+        }
+        for (i, part) in self.partitions.iter().enumerate() {
+            part.format(partition_node(disk, i + 1), playbook)?;
+        }
+        Ok(mounts)
+    }
+
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        self.check(playbook)?;
+        let disk = playbook.disk()?;
+        step.push(Action::DiskWrite {
+            device: disk.to_owned(),
+            description: "replace the partition table with a new GPT".to_owned(),
+        });
+        for cmd in reread_commands(disk) {
+            step.push(Action::from_command(&cmd, false));
+        }
+
+        for (i, part) in self.partitions.iter().enumerate() {
+            let node = partition_node(disk, i + 1);
+            let mut description = part.describe()?;
+            if part.size.is_none() {
+                description.push_str(" filling the rest of the disk");
+            }
+            step.push(Action::DiskWrite {
+                device: node.clone(),
+                description,
+            });
+            part.plan_format(node, playbook, step);
+        }
+        Ok(self.mounts(playbook, disk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::{Playbook, test_playbook};
+
+    fn playbook(partitions: &serde_json::Value) -> Playbook {
+        test_playbook(
+            "/dev/nvme0n1",
+            serde_json::json!({ "module": "Gpt", "partitions": partitions }),
+            serde_json::json!({ "encryption": { "tpm": false, "encryption_key": "hunter2" } }),
+        )
+    }
+
+    #[test]
+    fn plan_layout() {
+        let playbook = playbook(&serde_json::json!([
+            { "type": "esp", "label": "ESP", "size": "512 MiB", "format": "vfat", "mountpoint": "/boot/efi" },
+            { "type": "swap", "size": "4 GiB", "format": "swap" },
+            { "type": "root", "label": "root", "format": "btrfs", "mountpoint": "/", "options": "compress=zstd", "encrypt": true },
+        ]));
+        let mut step = Step::default();
+        let mounts = playbook
+            .disk_provisioner
+            .plan(&playbook, &mut step)
+            .unwrap();
+
+        assert_eq!(
+            (mounts.0.iter())
+                .map(|m| (
+                    m.partition.to_str().unwrap(),
+                    m.mountpoint.to_str().unwrap()
+                ))
+                .collect_vec(),
+            [("/dev/nvme0n1p1", "/boot/efi"), ("/dev/nvme0n1p3", "/")]
+        );
+        assert_eq!(
+            mounts.get_esp_partition().map(|m| &m.partition),
+            Some(&PathBuf::from("/dev/nvme0n1p1"))
+        );
+        let root = (mounts.0.iter())
+            .find(|m| m.mountpoint == Path::new("/"))
+            .unwrap();
+        assert_eq!(root.encryption_type, Some(EncryptionOption::KeyFile));
+        assert_eq!(root.options, "compress=zstd");
+
+        let actions = step.actions.iter().map(ToString::to_string).collect_vec();
+        assert!(
+            actions
+                .iter()
+                .any(|a| a.contains("mkfs.btrfs") && a.contains("/dev/mapper/root"))
+        );
+        assert!(
+            actions
+                .iter()
+                .any(|a| a.contains("filling the rest of the disk"))
+        );
+    }
+
+    #[test]
+    fn only_last_partition_fills_disk() {
+        let playbook = playbook(&serde_json::json!([
+            { "type": "root", "format": "ext4", "mountpoint": "/" },
+            { "type": "home", "format": "ext4", "mountpoint": "/home" },
+        ]));
+        let err = (playbook.disk_provisioner)
+            .plan(&playbook, &mut Step::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("partition 1 has no size"), "{err}");
+    }
+}
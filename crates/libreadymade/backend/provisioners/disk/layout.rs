@@ -0,0 +1,391 @@
+//! Partition layouts for [`Repart`](super::repart::Repart), compiled into systemd-repart
+//! definitions.
+//!
+//! Instead of a directory of definition files for every variant, a [`Layout`] lists the partitions
+//! by type and where they are mounted. The rest of each definition follows from that: a partition
+//! copies the tree at its mountpoint from the copy source, except for what other partitions are
+//! mounted on, and btrfs subvolumes are created and mounted by their own mountpoints.
+
+use bytesize::ByteSize;
+use repart::{Config, EncryptOption, FileSystem, PartTypeIdent, Partition};
+
+use super::{
+    gpt::{Filesystem, PartitionType},
+    repart::encrypt_option,
+};
+use crate::{playbook::EncryptionConfig, prelude::*};
+
+/// A list of partitions, laid out in order.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Layout {
+    pub partitions: Vec<LayoutPartition>,
+}
+
+/// A partition of a [`Layout`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct LayoutPartition {
+    #[serde(rename = "type")]
+    pub part_type: PartitionType,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Leave out to keep the partition unformatted.
+    #[serde(default)]
+    pub format: Option<Filesystem>,
+    /// Smallest size of the partition, e.g. `"512 MiB"`. Defaults to 10 MiB, like systemd-repart.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub min_size: Option<ByteSize>,
+    /// Largest size of the partition. Leave out to let it grow into the free space.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub max_size: Option<ByteSize>,
+    /// Share of the free space the partition grows into, relative to the other partitions.
+    /// Defaults to 1000, like systemd-repart.
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// Ignored with [`Self::subvolumes`], which are mounted instead.
+    #[serde(default)]
+    pub mountpoint: Option<PathBuf>,
+    /// Raw text `mountopts`
+    #[serde(default)]
+    pub options: String,
+    /// Btrfs subvolumes to create on the partition.
+    #[serde(default)]
+    pub subvolumes: Vec<Subvolume>,
+    /// Encrypt the partition with LUKS when the playbook sets
+    /// [encryption](crate::playbook::Playbook::encryption).
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+/// A btrfs subvolume of a [`LayoutPartition`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Subvolume {
+    /// Path of the subvolume inside the filesystem, e.g. `/home`.
+    ///
+    /// Files are copied to the same paths they are mounted at, so this must mirror
+    /// [`Self::mountpoint`] relative to the shortest mountpoint of the partition, e.g. `/` for the
+    /// subvolume mounted at `/`.
+    pub path: String,
+    pub mountpoint: PathBuf,
+    /// Raw text `mountopts`, besides the `subvol=` option.
+    #[serde(default)]
+    pub options: String,
+}
+
+/// `MountPoint=` of a definition, mounting it at `mountpoint` with `options`.
+fn mount_point(mountpoint: &Path, options: &str) -> String {
+    if options.is_empty() {
+        mountpoint.display().to_string()
+    } else {
+        format!("{}:{options}", mountpoint.display())
+    }
+}
+
+const fn repart_format(format: Filesystem) -> FileSystem {
+    match format {
+        Filesystem::Vfat => FileSystem::Vfat,
+        Filesystem::Ext4 => FileSystem::Ext4,
+        Filesystem::Xfs => FileSystem::Xfs,
+        Filesystem::Btrfs => FileSystem::Btrfs,
+        Filesystem::Swap => FileSystem::Swap,
+    }
+}
+
+impl LayoutPartition {
+    fn repart_type(&self) -> PartTypeIdent {
+        match self.part_type {
+            PartitionType::Esp => PartTypeIdent::Esp,
+            PartitionType::Xbootldr => PartTypeIdent::Xbootldr,
+            PartitionType::Root => PartTypeIdent::Root,
+            PartitionType::Home => PartTypeIdent::Home,
+            PartitionType::Srv => PartTypeIdent::Srv,
+            PartitionType::Var => PartTypeIdent::Var,
+            PartitionType::Swap => PartTypeIdent::Swap,
+            PartitionType::LinuxGeneric => PartTypeIdent::LinuxGeneric,
+            // repart has no names for these, but takes the GUID
+            PartitionType::BiosBoot | PartitionType::Lvm | PartitionType::Raid => {
+                PartTypeIdent::Unknown(self.part_type.guid().unwrap_or_default().to_owned())
+            }
+        }
+    }
+
+    /// Where the partition is mounted, with the subvolumes instead if there are any.
+    fn mountpoints(&self) -> Vec<&Path> {
+        if self.subvolumes.is_empty() {
+            self.mountpoint.as_deref().into_iter().collect()
+        } else {
+            (self.subvolumes.iter())
+                .map(|subvolume| subvolume.mountpoint.as_path())
+                .collect()
+        }
+    }
+
+    /// The mountpoint the partition copies its files from, the shortest one.
+    fn copy_root(&self) -> Option<&Path> {
+        if self.format.is_none_or(|format| format == Filesystem::Swap) {
+            return None;
+        }
+        (self.mountpoints().into_iter()).min_by_key(|mountpoint| mountpoint.components().count())
+    }
+
+    fn config(&self, others: &[&Path], encryption: Option<&EncryptionConfig>) -> Result<Config> {
+        if !self.subvolumes.is_empty() && self.format != Some(Filesystem::Btrfs) {
+            bail!("only btrfs partitions can have subvolumes");
+        }
+        if let Some(root) = self.copy_root() {
+            // the files are copied into the filesystem as they are laid out under the root
+            for subvolume in &self.subvolumes {
+                let Ok(relative) = subvolume.mountpoint.strip_prefix(root) else {
+                    bail!(
+                        "subvolume {} is mounted at {}, outside of {}",
+                        subvolume.path,
+                        subvolume.mountpoint.display(),
+                        root.display()
+                    );
+                };
+                let expected = Path::new("/").join(relative);
+                if Path::new(&subvolume.path) != expected {
+                    bail!(
+                        "subvolume {} is mounted at {}, so its path must be {}",
+                        subvolume.path,
+                        subvolume.mountpoint.display(),
+                        expected.display()
+                    );
+                }
+            }
+        }
+        let mount_point = if self.subvolumes.is_empty() {
+            (self.mountpoint.iter())
+                .map(|mountpoint| mount_point(mountpoint, &self.options))
+                .collect()
+        } else {
+            (self.subvolumes.iter())
+                .map(|subvolume| {
+                    let mut options = format!("subvol={}", subvolume.path);
+                    if !subvolume.options.is_empty() {
+                        options.push(',');
+                        options.push_str(&subvolume.options);
+                    }
+                    mount_point(&subvolume.mountpoint, &options)
+                })
+                .collect()
+        };
+        let (copy_files, exclude_files) = self.copy_root().map_or_else(Default::default, |root| {
+            // keep the directories to mount the other partitions on, but nothing inside them
+            let excluded = (others.iter())
+                .filter(|other| other.starts_with(root) && **other != root)
+                .map(|other| format!("{}/", other.display()))
+                .collect();
+            (vec![root.display().to_string(), "/".to_owned()], excluded)
+        });
+        Ok(Config {
+            partition: Partition {
+                part_type: self.repart_type(),
+                label: self.label.clone(),
+                weight: self.weight.unwrap_or(1000),
+                size_min_bytes: self.min_size.unwrap_or(ByteSize::mib(10)),
+                size_max_bytes: self.max_size.unwrap_or_default(),
+                format: self.format.map(repart_format),
+                copy_files,
+                exclude_files,
+                subvolumes: (self.subvolumes.iter())
+                    .map(|subvolume| subvolume.path.clone())
+                    .collect(),
+                default_subvolume: (self.subvolumes.iter())
+                    .find(|subvolume| subvolume.mountpoint == Path::new("/"))
+                    .map(|subvolume| subvolume.path.clone()),
+                encrypt: encryption
+                    .filter(|_| self.encrypt)
+                    .map_or(EncryptOption::Off, encrypt_option),
+                mount_point,
+                ..Partition::default()
+            },
+        })
+    }
+}
+
+impl Layout {
+    /// The definitions of the partitions, in order, along with their file names.
+    ///
+    /// # Errors
+    /// A partition has subvolumes but isn't btrfs, or a mountpoint is used twice.
+    pub fn compile(&self, encryption: Option<&EncryptionConfig>) -> Result<Vec<(String, Config)>> {
+        let mountpoints = (self.partitions.iter())
+            .flat_map(LayoutPartition::mountpoints)
+            .collect_vec();
+        if let Some(mountpoint) = mountpoints.iter().duplicates().next() {
+            bail!(
+                "more than one partition is mounted at {}",
+                mountpoint.display()
+            );
+        }
+        let mut configs = vec![];
+        for (i, part) in self.partitions.iter().enumerate() {
+            let own = part.mountpoints();
+            let others = (mountpoints.iter().copied())
+                .filter(|mountpoint| !own.contains(mountpoint))
+                .collect_vec();
+            // numbered so that systemd-repart keeps them in order, the label only goes in `Label=`
+            let file = format!(
+                "{:02}-{}.conf",
+                (i + 1) * 10,
+                super::ini_value(&part.part_type)?
+            );
+            let config = (part.config(&others, encryption))
+                .wrap_err_with(|| format!("cannot compile partition {file}"))?;
+            configs.push((file, config));
+        }
+        Ok(configs)
+    }
+
+    /// Write the definitions to `directory`.
+    ///
+    /// # Errors
+    /// The layout doesn't compile, or the files can't be written.
+    pub fn write(&self, directory: &Path, encryption: Option<&EncryptionConfig>) -> Result<()> {
+        for (file, config) in self.compile(encryption)? {
+            let path = directory.join(file);
+            std::fs::write(&path, serde_systemd_unit::to_string(&config)?)
+                .wrap_err_with(|| format!("cannot write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workstation() -> Layout {
+        serde_json::from_value(serde_json::json!({
+            "partitions": [
+                { "type": "esp", "format": "vfat", "min_size": "512 MiB", "max_size": "512 MiB",
+                  "mountpoint": "/boot/efi", "options": "umask=0077,shortname=winnt" },
+                { "type": "xbootldr", "format": "ext4", "min_size": "1 GiB", "max_size": "2 GiB",
+                  "weight": 100, "mountpoint": "/boot" },
+                { "type": "root", "format": "btrfs", "encrypt": true, "subvolumes": [
+                    { "path": "/", "mountpoint": "/", "options": "compress=zstd:1" },
+                    { "path": "/home", "mountpoint": "/home", "options": "compress=zstd:1" },
+                ] },
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn compile_workstation() {
+        let encryption = EncryptionConfig {
+            tpm: true,
+            ..Default::default()
+        };
+        let configs = workstation().compile(Some(&encryption)).unwrap();
+        let files = configs.iter().map(|(file, _)| file.as_str()).collect_vec();
+        assert_eq!(files, ["10-esp.conf", "20-xbootldr.conf", "30-root.conf"]);
+
+        let [esp, xbootldr, root] = configs
+            .iter()
+            .map(|(_, config)| serde_systemd_unit::to_string(config).unwrap())
+            .collect_vec()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            esp,
+            "[Partition]
+Type=esp
+Priority=0
+Weight=1000
+PaddingWeight=0
+SizeMinBytes=536870912
+SizeMaxBytes=536870912
+Format=vfat
+CopyFiles=/boot/efi:/
+Encrypt=off
+Verity=off
+MountPoint=/boot/efi:umask=0077,shortname=winnt
+"
+        );
+        assert!(
+            xbootldr.contains("\nExcludeFiles=/boot/efi/\n"),
+            "{xbootldr}"
+        );
+        assert!(xbootldr.contains("\nWeight=100\n"), "{xbootldr}");
+        for line in [
+            "CopyFiles=/:/",
+            "ExcludeFiles=/boot/efi/",
+            "ExcludeFiles=/boot/",
+            "Subvolumes=/ /home",
+            "DefaultSubvolume=/",
+            "Encrypt=key-file+tpm2",
+            "MountPoint=/:subvol=/,compress=zstd:1",
+            "MountPoint=/home:subvol=/home,compress=zstd:1",
+        ] {
+            assert!(root.lines().any(|l| l == line), "no {line:?} in {root}");
+        }
+
+        // what repart reads back is what the layout says
+        let (_, root) = configs.into_iter().nth(2).unwrap();
+        let parsed: Config =
+            serde_systemd_unit::from_str(&serde_systemd_unit::to_string(&root).unwrap()).unwrap();
+        assert_eq!(
+            parsed.partition.mount_point_as_tuple(),
+            root.partition.mount_point_as_tuple()
+        );
+        assert!(matches!(
+            parsed.partition.encrypt,
+            EncryptOption::KeyFileTpm2
+        ));
+        assert!(parsed.partition.size_max_bytes.as_u64() == 0);
+    }
+
+    #[test]
+    fn reject_bad_layouts() {
+        let mut layout = workstation();
+        if let Some(part) = layout.partitions.get_mut(1) {
+            part.mountpoint = Some(PathBuf::from("/home"));
+        }
+        let err = layout.compile(None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "more than one partition is mounted at /home"
+        );
+
+        let mut layout = workstation();
+        if let Some(part) = layout.partitions.get_mut(2) {
+            part.format = Some(Filesystem::Xfs);
+        }
+        let err = layout.compile(None).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "cannot compile partition 30-root.conf: only btrfs partitions can have subvolumes"
+        );
+
+        let mut layout = workstation();
+        if let Some(subvolume) =
+            (layout.partitions.get_mut(2)).and_then(|part| part.subvolumes.first_mut())
+        {
+            subvolume.path = "/root".to_owned();
+        }
+        let err = layout.compile(None).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "cannot compile partition 30-root.conf: subvolume /root is mounted at /, so its path \
+             must be /"
+        );
+    }
+
+    #[test]
+    fn label_only_in_definition() {
+        let mut layout = workstation();
+        if let Some(part) = layout.partitions.get_mut(2) {
+            part.label = Some("../My Root".to_owned());
+        }
+        let configs = layout.compile(None).unwrap();
+        let files = configs.iter().map(|(file, _)| file.as_str()).collect_vec();
+        assert_eq!(files, ["10-esp.conf", "20-xbootldr.conf", "30-root.conf"]);
+        let root = (configs.last())
+            .map(|(_, config)| serde_systemd_unit::to_string(config).unwrap())
+            .unwrap_or_default();
+        assert!(root.contains("Label=../My Root\n"), "{root}");
+    }
+}
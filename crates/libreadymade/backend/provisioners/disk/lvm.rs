@@ -0,0 +1,337 @@
+//! LVM on a fresh partition table, optionally on top of LUKS.
+//!
+//! The partitions of the layout are written like [`Gpt`] does, followed by a partition filling the
+//! rest of the disk that becomes the only physical volume of a new volume group. With `encrypt`,
+//! the physical volume sits inside a LUKS container, so root, home and swap are all unlocked with
+//! the one passphrase of the playbook.
+
+use ::gpt::partition_types;
+use bytesize::ByteSize;
+
+use super::gpt::{Filesystem, Gpt, GptPartition, PartitionType, run_command};
+use crate::{
+    backend::{
+        mounts::luks_decrypt,
+        provisioners::disk::{DiskProvisionerModule, dry_run},
+        util::fs::partition_node,
+    },
+    plan::{Action, Step},
+    prelude::*,
+    session::{self, Resource},
+};
+
+/// Creates a volume group and logical volumes from a declarative layout.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Lvm {
+    /// Partitions laid out before the physical volume, e.g. the ESP and XBOOTLDR. They all need a
+    /// size, since the physical volume fills the rest of the disk.
+    #[serde(default)]
+    pub partitions: Vec<GptPartition>,
+    /// Name of the new volume group.
+    pub volume_group: String,
+    /// Put the physical volume inside LUKS, using the encryption settings of the playbook.
+    #[serde(default)]
+    pub encrypt: bool,
+    /// The logical volumes, created in this order.
+    pub volumes: Vec<LogicalVolume>,
+}
+
+/// A logical volume in an [`Lvm`] volume group.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct LogicalVolume {
+    /// Name of the volume, also used as the filesystem label.
+    pub name: String,
+    /// Size of the volume, e.g. `"8 GiB"`. The last volume may leave this out to fill the rest of
+    /// the volume group.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub size: Option<ByteSize>,
+    /// Leave out to keep the volume unformatted. Volumes formatted as swap are used as swap.
+    #[serde(default)]
+    pub format: Option<Filesystem>,
+    #[serde(default)]
+    pub mountpoint: Option<PathBuf>,
+    /// Raw text `mountopts`
+    #[serde(default)]
+    pub options: String,
+}
+
+/// Whether `name` is a valid LVM volume group or logical volume name.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !matches!(name, "." | "..")
+        && (name.chars()).all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+}
+
+impl LogicalVolume {
+    /// The device-mapper node of this volume in `vg`.
+    ///
+    /// Device-mapper joins the names with a dash, so dashes inside them are doubled.
+    fn node(&self, vg: &str) -> PathBuf {
+        let escape = |name: &str| name.replace('-', "--");
+        PathBuf::from(format!("/dev/mapper/{}-{}", escape(vg), escape(&self.name)))
+    }
+
+    fn create_command(&self, vg: &str) -> Command {
+        let mut cmd = Command::new("lvcreate");
+        cmd.args(["--yes", "--name", &self.name]);
+        if let Some(size) = self.size {
+            cmd.args(["--size", &format!("{}b", size.as_u64())]);
+        } else {
+            cmd.args(["--extents", "100%FREE"]);
+        }
+        cmd.arg(vg);
+        cmd
+    }
+
+    fn mkfs_command(&self, vg: &str) -> Option<Command> {
+        (self.format).map(|format| format.mkfs_command(&self.node(vg), Some(&self.name)))
+    }
+
+    /// The mount of this volume in `vg`. Swap is listed with the mountpoint `none`, like in fstab.
+    fn mount(&self, vg: &str) -> Option<Mount> {
+        let mountpoint = if self.format == Some(Filesystem::Swap) {
+            PathBuf::from("none")
+        } else {
+            self.mountpoint.clone()?
+        };
+        let mount = Mount {
+            partition: self.node(vg),
+            mountpoint,
+            options: self.options.clone(),
+            // LUKS is underneath the volume group, not the volume
+            encryption_type: None,
+            label: Some(self.name.clone()),
+            gpt_type: std::sync::OnceLock::default(),
+        };
+        // volumes aren't in the partition table, so there's nothing for `Mount::get_gpt_type` to probe
+        _ = mount.gpt_type.set(partition_types::LINUX_FS);
+        Some(mount)
+    }
+}
+
+impl Lvm {
+    /// The partition table: the partitions of the layout, then the physical volume.
+    fn table(&self) -> Gpt {
+        let mut partitions = self.partitions.clone();
+        partitions.push(GptPartition {
+            part_type: PartitionType::Lvm,
+            label: Some(self.pv_label()),
+            size: None,
+            format: None,
+            mountpoint: None,
+            options: String::new(),
+            encrypt: self.encrypt,
+        });
+        Gpt { partitions }
+    }
+
+    /// Partition name of the physical volume, which is also the mapper name of its LUKS container.
+    fn pv_label(&self) -> String {
+        if self.encrypt {
+            format!("luks-{}", self.volume_group)
+        } else {
+            self.volume_group.clone()
+        }
+    }
+
+    /// The physical volume, once the partition table is written to `disk`.
+    fn physical_volume(&self, disk: &Path) -> PathBuf {
+        if self.encrypt {
+            PathBuf::from(format!("/dev/mapper/{}", self.pv_label()))
+        } else {
+            partition_node(disk, self.partitions.len() + 1)
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if !valid_name(&self.volume_group) {
+            bail!("{:?} is not a valid volume group name", self.volume_group);
+        }
+        if let Some(volume) = (self.volumes.iter()).find(|volume| !valid_name(&volume.name)) {
+            bail!("{:?} is not a valid logical volume name", volume.name);
+        }
+        if let Some(name) = (self.volumes.iter().map(|volume| &volume.name))
+            .duplicates()
+            .next()
+        {
+            bail!("there is more than one logical volume named {name:?}");
+        }
+        if let Some(volume) = (self.volumes.iter().rev().skip(1)).find(|v| v.size.is_none()) {
+            bail!(
+                "logical volume {:?} has no size, only the last volume can fill the rest of the volume group",
+                volume.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Create the physical volume, the volume group and the logical volumes on `pv`.
+    fn commands(&self, pv: &Path) -> Vec<Command> {
+        let vg = &self.volume_group;
+        let mut pvcreate = Command::new("pvcreate");
+        pvcreate.arg("--yes").arg(pv);
+        let mut vgcreate = Command::new("vgcreate");
+        vgcreate.args(["--yes", vg]).arg(pv);
+        [pvcreate, vgcreate]
+            .into_iter()
+            .chain(self.volumes.iter().map(|volume| volume.create_command(vg)))
+            .collect()
+    }
+
+    fn mounts(&self, playbook: &crate::playbook::Playbook, disk: &Path) -> Mounts {
+        let partitions = (self.partitions.iter().enumerate())
+            .filter_map(|(i, part)| part.mount(partition_node(disk, i + 1), playbook));
+        let volumes = (self.volumes.iter()).filter_map(|volume| volume.mount(&self.volume_group));
+        Mounts(partitions.chain(volumes).collect())
+    }
+}
+
+impl DiskProvisionerModule for Lvm {
+    fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        self.check()?;
+        let disk = playbook.disk()?;
+        // writes the table and opens the LUKS container, or does nothing on a dry run
+        self.table().run(playbook)?;
+        let mounts = self.mounts(playbook, disk);
+        if dry_run() {
+            return Ok(mounts);
+        }
+
+        let mut commands = self.commands(&self.physical_volume(disk)).into_iter();
+        for mut cmd in commands.by_ref().take(2) {
+            run_command(&mut cmd, None)?;
+        }
+        // deactivated again at the end, before the LUKS container underneath is closed
+        session::acquire(Resource::VolumeGroup(self.volume_group.clone()));
+        for mut cmd in commands {
+            run_command(&mut cmd, None)?;
+        }
+        for volume in &self.volumes {
+            if let Some(mut cmd) = volume.mkfs_command(&self.volume_group) {
+                run_command(&mut cmd, None)?;
+            }
+        }
+        Ok(mounts)
+    }
+
+    fn reactivate(&self, playbook: &crate::playbook::Playbook, _mounts: &mut Mounts) -> Result<()> {
+        if dry_run() {
+            return Ok(());
+        }
+        let disk = playbook.disk()?;
+        if self.encrypt {
+            let key = (playbook.encryption_key()?).ok_or_eyre(
+                "the physical volume is encrypted, but the playbook sets no encryption",
+            )?;
+            let node = partition_node(disk, self.partitions.len() + 1);
+            luks_decrypt(&node.to_string_lossy(), key, &self.pv_label())?;
+        }
+        run_command(
+            Command::new("vgchange").args(["--activate", "y", &self.volume_group]),
+            None,
+        )?;
+        session::acquire(Resource::VolumeGroup(self.volume_group.clone()));
+        Ok(())
+    }
+
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        self.check()?;
+        let disk = playbook.disk()?;
+        self.table().plan(playbook, step)?;
+        for cmd in self.commands(&self.physical_volume(disk)) {
+            step.push(Action::from_command(&cmd, false));
+        }
+        for volume in &self.volumes {
+            if let Some(cmd) = volume.mkfs_command(&self.volume_group) {
+                step.push(Action::from_command(&cmd, false));
+            }
+        }
+        Ok(self.mounts(playbook, disk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::{Playbook, test_playbook};
+
+    fn playbook(volumes: &serde_json::Value) -> Playbook {
+        test_playbook(
+            "/dev/loop0",
+            serde_json::json!({
+                "module": "Lvm",
+                "partitions": [
+                    { "type": "esp", "label": "ESP", "size": "512 MiB", "format": "vfat", "mountpoint": "/boot/efi" },
+                    { "type": "xbootldr", "label": "boot", "size": "1 GiB", "format": "ext4", "mountpoint": "/boot" },
+                ],
+                "volume_group": "fedora-vg",
+                "encrypt": true,
+                "volumes": volumes,
+            }),
+            serde_json::json!({ "encryption": { "tpm": false, "encryption_key": "hunter2" } }),
+        )
+    }
+
+    #[test]
+    fn plan_lvm_on_luks() {
+        let playbook = playbook(&serde_json::json!([
+            { "name": "swap", "size": "4 GiB", "format": "swap" },
+            { "name": "root", "size": "32 GiB", "format": "xfs", "mountpoint": "/" },
+            { "name": "home", "format": "xfs", "mountpoint": "/home" },
+        ]));
+        let mut step = Step::default();
+        let mounts = (playbook.disk_provisioner)
+            .plan(&playbook, &mut step)
+            .unwrap();
+
+        assert_eq!(
+            (mounts.0.iter())
+                .map(|m| (
+                    m.partition.to_str().unwrap(),
+                    m.mountpoint.to_str().unwrap()
+                ))
+                .collect_vec(),
+            [
+                ("/dev/loop0p1", "/boot/efi"),
+                ("/dev/loop0p2", "/boot"),
+                ("/dev/mapper/fedora--vg-swap", "none"),
+                ("/dev/mapper/fedora--vg-root", "/"),
+                ("/dev/mapper/fedora--vg-home", "/home"),
+            ]
+        );
+        assert!((mounts.0.iter().skip(2)).all(|m| m.encryption_type.is_none()));
+
+        let actions = step.actions.iter().map(ToString::to_string).collect_vec();
+        let position = |needle: &str| {
+            (actions.iter().position(|a| a.contains(needle)))
+                .unwrap_or_else(|| panic!("no {needle:?} in {actions:#?}"))
+        };
+        assert!(
+            position("cryptsetup open /dev/loop0p3 luks-fedora-vg")
+                < position("pvcreate --yes /dev/mapper/luks-fedora-vg")
+        );
+        assert!(
+            position("vgcreate --yes fedora-vg /dev/mapper/luks-fedora-vg") < position("lvcreate")
+        );
+        position("lvcreate --yes --name root --size 34359738368b fedora-vg");
+        position("lvcreate --yes --name home --extents 100%FREE fedora-vg");
+        position("mkswap -L swap /dev/mapper/fedora--vg-swap");
+    }
+
+    #[test]
+    fn only_last_volume_fills_group() {
+        let playbook = playbook(&serde_json::json!([
+            { "name": "root", "format": "xfs", "mountpoint": "/" },
+            { "name": "home", "format": "xfs", "mountpoint": "/home" },
+        ]));
+        let err = (playbook.disk_provisioner)
+            .plan(&playbook, &mut Step::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("\"root\" has no size"), "{err}");
+
+        assert!(valid_name("fedora_root.1"));
+        assert!(!valid_name("-root") && !valid_name("root vg") && !valid_name(".."));
+    }
+}
@@ -1,55 +1,216 @@
-use std::str::FromStr;
+use std::collections::{BTreeMap, btree_map::Entry};
 
+use gpt::partition_types;
+
+use super::gpt::{Filesystem, run_command};
 use crate::{
-    backend::provisioners::disk::DiskProvisionerModule, backend::util::fs::get_whole_disk,
+    backend::{
+        mounts::{generate_unique_mapper_label, luks_decrypt},
+        provisioners::disk::DiskProvisionerModule,
+        util::fs::{get_whole_disk, partition_number},
+    },
+    plan::{Action, Step},
     prelude::*,
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, schemars::JsonSchema)]
 pub struct Manual {
     pub mounts: Mounts,
+    /// Partitions to format before they are mounted. Everything on them is lost.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub format: Vec<Format>,
+}
+
+/// A partition for [`Manual`] to format.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, schemars::JsonSchema)]
+pub struct Format {
+    /// Path to partition. If it is mounted encrypted, the filesystem is created inside the LUKS
+    /// container, which has to exist already.
+    pub partition: PathBuf,
+    pub filesystem: Filesystem,
+    /// Filesystem label
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// The GPT types of the partitions on `disk`, by partition number.
+fn gpt_types(disk: &str) -> Result<BTreeMap<u32, partition_types::Type>> {
+    let table = gpt::disk::read_disk(disk).wrap_err_with(|| format!("{disk} has no GPT"))?;
+    Ok((table.partitions().iter())
+        .map(|(n, part)| (*n, part.part_type_guid.clone()))
+        .collect())
+}
+
+impl Manual {
+    /// Check that no mountpoint is used twice. Any number of swap partitions can be used.
+    fn check_mountpoints(&self) -> Result<()> {
+        if let Some(mountpoint) = (self.mounts.0.iter())
+            .filter(|m| !m.is_swap())
+            .map(|m| &m.mountpoint)
+            .duplicates()
+            .next()
+        {
+            bail!(
+                "more than one partition is mounted at {}",
+                mountpoint.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the partitions exist on GPT disks and aren't mounted twice, and read their types.
+    fn resolve(&self) -> Result<Mounts> {
+        self.check_mountpoints()?;
+        let mut disks = BTreeMap::new();
+        let mounts = self.mounts.clone();
+        for mount in &mounts.0 {
+            let node = (mount.partition.canonicalize())
+                .wrap_err_with(|| format!("{} does not exist", mount.partition.display()))?;
+            let disk = get_whole_disk(&node)?;
+            let number = u32::try_from(partition_number(&node)?)?;
+            let types = match disks.entry(disk) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let types = gpt_types(entry.key())?;
+                    entry.insert(types)
+                }
+            };
+            let gpt_type = types.get(&number).ok_or_else(|| {
+                eyre!(
+                    "{} is not in the partition table",
+                    mount.partition.display()
+                )
+            })?;
+            _ = mount.gpt_type.set(gpt_type.clone());
+        }
+        Ok(mounts)
+    }
+
+    /// The mount of the partition `format` is for, if it is mounted encrypted.
+    fn encrypted_mount(&self, format: &Format) -> Option<&Mount> {
+        (self.mounts.0.iter())
+            .find(|mount| mount.partition == format.partition && mount.encryption_type.is_some())
+    }
 }
 
 impl DiskProvisionerModule for Manual {
-    fn run(&self, _: &crate::playbook::Playbook) -> Result<Mounts> {
-        // let mounts = self.mounts.clone();
-        // let block_devices = lsblk::BlockDevice::list()?;
-
-        // let disk_parts = self.mounts.0.iter().map(|mount| {
-        //     (get_whole_disk(&mount.partition).expect("cannot get whole disk"), (block_devices.iter().find(|b| b.fullname == mount.mountpoint).expect("cannot find block device").partuuid.unwrap(), mount))
-        // }).into_group_map();
-
-        // disk_parts.iter().for_each(|(disk, parts)| {
-        //    let disk = gpt::disk::read_disk(disk).expect("cannot read disk");
-        //       disk.partitions().values().for_each(|p| {
-        //             let partuuid = p.part_guid.to_string();
-        //             if let Some(mount) = parts.iter().find(|(uuid, _)| uuid::Uuid::from_str(uuid).unwrap() == p.part_guid) {
-        //                 mount.gpt_type = Some(p.part_type_guid);
-        //             } else {
-        //                 tracing::warn!(?partuuid, "Partition with partuuid {partuuid} not found in mounts");
-        //             }
-        //         });
-        // });
-        // let blockdevs = lsblk::BlockDevice::list().expect("cannot lsblk");
-
-        // // FIXME: is this compatible with encryptions?
-        // let diskparts = self.mounts.0.iter().into_group_map(|part| {
-        //     let diskname = blockdevs
-        //         .iter()
-        //         .find(|bd| &bd.fullname == &part.partition) // TODO: this currently assumes that the partition path is in the /dev/<disk><number> format
-        //         .expect("not partition?")
-        //         .disk_name()
-        //         .expect("can't get disk name");
-        //     (diskname, part)
-        // });
-        // let partuuid2mount = |partuuid: &str| mounts.0.iter_mut().find(|m| m.partition == blockdevs.iter().find(|bd| bd.partuuid.is_some_and(|x| == partuuid)).expect("can't find part by partuuid").fullname);
-        // diskparts.iter().for_each(|(disk, parts)| {
-        //     let disk = gpt::disk::read_disk(&disk.fullname).expect("cannot read disk");
-        //     disk.partitions().values().for_each(|p| {
-        //         let mount = partuuid2mount(&p.part_guid.to_string());
-        //         mount.gpt_type = p.part_type_guid;
-        //     });
-        // });
+    fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        let mounts = self.resolve()?;
+        for format in &self.format {
+            if !format.partition.exists() {
+                bail!("{} does not exist", format.partition.display());
+            }
+            let device = if let Some(mount) = self.encrypted_mount(format) {
+                let key = (playbook.encryption_key()?)
+                    .ok_or_else(|| eyre!("encrypted partitions need an encryption key"))?;
+                // the same mapper `Mount::mount` would open, so it is reused from the cache
+                let label = generate_unique_mapper_label(&mount.mountpoint.to_string_lossy());
+                luks_decrypt(&format.partition.to_string_lossy(), key, &label)?
+            } else {
+                format.partition.clone()
+            };
+            tracing::info!(partition = ?format.partition, filesystem = ?format.filesystem, "Formatting");
+            let mut cmd = (format.filesystem).mkfs_command(&device, format.label.as_deref());
+            run_command(&mut cmd, None)?;
+        }
+        Ok(mounts)
+    }
+
+    fn plan(&self, _: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        // The partitions already exist, so there is nothing to write to the disk unless asked to.
+        for format in &self.format {
+            let device = self.encrypted_mount(format).map_or_else(
+                || format.partition.clone(),
+                |mount| {
+                    let label = generate_unique_mapper_label(&mount.mountpoint.to_string_lossy());
+                    step.push(Action::host_command(
+                        "cryptsetup",
+                        [
+                            "open".to_owned(),
+                            format.partition.to_string_lossy().to_string(),
+                            label.clone(),
+                        ],
+                    ));
+                    PathBuf::from(format!("/dev/mapper/{label}"))
+                },
+            );
+            let cmd = (format.filesystem).mkfs_command(&device, format.label.as_deref());
+            step.push(Action::from_command(&cmd, false));
+        }
+        // `Playbook::problems` reports missing partitions by itself, so only the types of existing
+        // ones are read
+        if (self.mounts.0.iter()).all(|mount| mount.partition.exists()) {
+            return self.resolve();
+        }
+        self.check_mountpoints()?;
         Ok(self.mounts.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::test_playbook;
+
+    fn manual() -> Manual {
+        serde_json::from_value(serde_json::json!({
+            "mounts": [
+                { "partition": "/dev/readymade-test1", "mountpoint": "/", "options": "", "encryption_type": "KeyFile", "label": "root" },
+                { "partition": "/dev/readymade-test2", "mountpoint": "/boot", "options": "", "encryption_type": null, "label": null },
+            ],
+            "format": [
+                { "partition": "/dev/readymade-test1", "filesystem": "btrfs" },
+                { "partition": "/dev/readymade-test2", "filesystem": "ext4", "label": "boot" },
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plan_formats() {
+        let playbook = test_playbook(
+            "/dev/readymade-test",
+            serde_json::json!({ "module": "Manual", "mounts": [] }),
+            serde_json::Value::Null,
+        );
+        let mut step = Step::default();
+        manual().plan(&playbook, &mut step).unwrap();
+        let actions = step.actions.iter().map(ToString::to_string).collect_vec();
+        let [open, btrfs, ext4] = actions.as_slice() else {
+            panic!("expected 3 actions, got {actions:#?}");
+        };
+        assert!(open.contains("cryptsetup open /dev/readymade-test1 root"));
+        assert!(btrfs.ends_with("mkfs.btrfs -f --nodiscard /dev/mapper/root"));
+        assert!(ext4.ends_with("mkfs.ext4 -F -L boot /dev/readymade-test2"));
+    }
+
+    #[test]
+    fn resolve_checks_partitions() {
+        let err = manual().resolve().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("/dev/readymade-test1 does not exist"),
+            "{err}"
+        );
+
+        let mut twice = manual();
+        if let Some(mount) = twice.mounts.0.get_mut(1) {
+            mount.mountpoint = "/".into();
+        }
+        let err = twice.resolve().unwrap_err();
+        assert_eq!(err.to_string(), "more than one partition is mounted at /");
+        let playbook = test_playbook(
+            "/dev/readymade-test",
+            serde_json::json!({ "module": "Manual", "mounts": [] }),
+            serde_json::Value::Null,
+        );
+        let err = twice.plan(&playbook, &mut Step::default()).unwrap_err();
+        assert_eq!(err.to_string(), "more than one partition is mounted at /");
+
+        let mut swaps = manual();
+        for mount in &mut swaps.mounts.0 {
+            mount.mountpoint = "none".into();
+        }
+        swaps.check_mountpoints().unwrap();
+    }
+}
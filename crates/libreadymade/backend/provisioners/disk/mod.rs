@@ -1,22 +1,61 @@
-use crate::prelude::*;
+use crate::{plan::Step, prelude::*};
+use dualboot::DualBoot;
 use enum_dispatch::enum_dispatch;
+use gpt::Gpt;
+use lvm::Lvm;
 use manual::Manual;
+use raid::Raid;
 use repart::Repart;
 use serde::{Deserialize, Serialize};
 
+pub mod dualboot;
+pub mod gpt;
+pub mod layout;
+pub mod lvm;
 pub mod manual;
+pub mod raid;
 pub mod repart;
 
 #[enum_dispatch(DiskProvisioner)]
 pub trait DiskProvisionerModule {
     fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts>;
+    /// Describe what [`DiskProvisionerModule::run`] would do without touching the disk.
+    ///
+    /// Returns the mounts the provisioner is expected to produce, so later steps can be planned against them.
+    ///
+    /// # Errors
+    /// The provisioner configuration is invalid.
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts>;
+    /// Set up again what [`DiskProvisionerModule::run`] built on top of the partitions, such as
+    /// volume groups and RAID arrays, which the [`Session`](crate::session::Session) of the
+    /// previous run tore down. Called instead of `run` when an installation is resumed with the
+    /// journal's `mounts`, which are updated if the devices come back under other names.
+    ///
+    /// # Errors
+    /// What was built cannot be set up again.
+    fn reactivate(
+        &self,
+        _playbook: &crate::playbook::Playbook,
+        _mounts: &mut Mounts,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Disks other than the destination disk that the provisioner writes a copy of the boot
+    /// partitions to, so the bootloader is installed to them as well.
+    fn mirrors(&self) -> &[PathBuf] {
+        &[]
+    }
+    /// Name of the provisioner, as used in playbooks.
+    fn name(&self) -> &'static str {
+        crate::backend::util::type_name::<Self>()
+    }
 }
 
 /// The disk provisioner is responsible for partitioning the disk, before the filesystem provisioner sets up the install files on the partitions.
 /// Provisioners may also use context from the playbook to determine how to provision the installation, such as the destination disk and encryption settings.
 /// Some disk provisioners support copying files to the installation disk, making a filesystem provisioner optional.
 #[enum_dispatch]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 #[serde(tag = "module")]
 pub enum DiskProvisioner {
     /// Uses systemd-repart to partition and provision the disk, this is recommended for most users as it is fast and flexible.
@@ -24,4 +63,26 @@ pub enum DiskProvisioner {
     Repart,
     /// Readymade will not partition the disk. Instead, the user provides a list of mountpoints.
     Manual,
+    /// Writes the partition table itself and formats the partitions with `mkfs.*`, for hosts
+    /// whose systemd-repart is too old.
+    Gpt,
+    /// Shrinks a partition of another system and installs into the freed space, sharing its ESP.
+    DualBoot,
+    /// Creates LVM logical volumes, optionally inside a LUKS container unlocked with one passphrase.
+    Lvm,
+    /// Mirrors the boot partitions and builds an md RAID1 or RAID10 root across several disks.
+    Raid,
+}
+
+/// Whether provisioners leave the disks alone, set with `READYMADE_DRY_RUN=1`. On by default in
+/// debug builds.
+fn dry_run() -> bool {
+    std::env::var("READYMADE_DRY_RUN").map_or(cfg!(debug_assertions), |v| v == "1")
+}
+
+/// Render an enum the way it is spelled in playbooks and repart definitions, e.g. `raid10`.
+fn ini_value<T: Serialize>(value: &T) -> Result<String> {
+    Ok((serde_json::to_value(value)?.as_str())
+        .unwrap_or_default()
+        .to_owned())
 }
@@ -0,0 +1,400 @@
+//! Software RAID with `mdadm`, for machines that need mirrored boot disks.
+//!
+//! Every member disk gets the same partition table. The ESP and XBOOTLDR partitions of all members
+//! are mirrored as RAID1 with the superblock at the end of the partition, so firmware and GRUB read
+//! each member as a plain filesystem and any disk can boot on its own. The rest of each disk is
+//! assembled into the root array, at the requested [`RaidLevel`].
+//!
+//! The arrays are not encrypted, and the installed system needs the
+//! [`Mdadm`](crate::backend::postinstall::Module::Mdadm) postinstall module to assemble them on boot.
+
+use bytesize::ByteSize;
+
+use super::{
+    gpt::{Filesystem, Gpt, GptPartition, PartitionType, reread_commands, run_command},
+    ini_value,
+};
+use crate::{
+    backend::{
+        provisioners::disk::{DiskProvisionerModule, dry_run},
+        util::fs::partition_node,
+    },
+    plan::{Action, Step},
+    prelude::*,
+    session::{self, Resource},
+};
+
+const fn default_esp_size() -> ByteSize {
+    ByteSize::mib(600)
+}
+
+const fn default_xbootldr_size() -> ByteSize {
+    ByteSize::gib(1)
+}
+
+const fn default_root_format() -> Filesystem {
+    Filesystem::Xfs
+}
+
+/// RAID level of the root array. The boot arrays are always RAID1.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RaidLevel {
+    /// Every disk holds a full copy.
+    Raid1,
+    /// Striped across mirrored pairs, which needs at least 4 disks.
+    Raid10,
+}
+
+impl RaidLevel {
+    const fn as_arg(self) -> &'static str {
+        match self {
+            Self::Raid1 => "1",
+            Self::Raid10 => "10",
+        }
+    }
+
+    const fn min_disks(self) -> usize {
+        match self {
+            Self::Raid1 => 2,
+            Self::Raid10 => 4,
+        }
+    }
+}
+
+/// An md array built from the partition with the same number on every member disk.
+struct Array {
+    partno: usize,
+    level: &'static str,
+    /// Superblock format. 1.0 keeps it at the end, so the start of each member looks like a filesystem.
+    metadata: &'static str,
+    /// What the array is formatted as and mounted at.
+    filesystem: GptPartition,
+}
+
+impl Array {
+    fn node(&self) -> PathBuf {
+        let name = self.filesystem.label.as_deref().unwrap_or_default();
+        PathBuf::from(format!("/dev/md/{name}"))
+    }
+
+    fn create_command(&self, disks: &[&Path]) -> Command {
+        let mut cmd = Command::new("mdadm");
+        cmd.arg("--create")
+            .arg(self.node())
+            .args(["--run", "--level", self.level])
+            .args(["--raid-devices", &disks.len().to_string()])
+            .args(["--metadata", self.metadata])
+            .args(disks.iter().map(|disk| partition_node(disk, self.partno)));
+        cmd
+    }
+}
+
+/// Builds md RAID arrays across the destination disk and [`Self::members`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct Raid {
+    /// The other disks of the arrays. Everything on them is lost, like on the destination disk.
+    pub members: Vec<PathBuf>,
+    pub level: RaidLevel,
+    #[serde(default = "default_esp_size")]
+    #[schemars(with = "String")]
+    pub esp_size: ByteSize,
+    #[serde(default = "default_xbootldr_size")]
+    #[schemars(with = "String")]
+    pub xbootldr_size: ByteSize,
+    #[serde(default = "default_root_format")]
+    pub root_format: Filesystem,
+}
+
+/// A partition of a member disk, which is formatted as part of an [`Array`] instead.
+fn member_partition(part_type: PartitionType, label: &str, size: Option<ByteSize>) -> GptPartition {
+    GptPartition {
+        part_type,
+        label: Some(label.to_owned()),
+        size,
+        format: None,
+        mountpoint: None,
+        options: String::new(),
+        encrypt: false,
+    }
+}
+
+impl Raid {
+    /// The destination disk, then the other members.
+    fn disks<'a>(&'a self, disk: &'a Path) -> Vec<&'a Path> {
+        std::iter::once(disk)
+            .chain(self.members.iter().map(PathBuf::as_path))
+            .collect()
+    }
+
+    fn check(&self, disk: &Path) -> Result<()> {
+        let disks = self.disks(disk);
+        if disks.len() < self.level.min_disks() {
+            bail!(
+                "{} needs at least {} disks, but there are {}",
+                ini_value(&self.level)?,
+                self.level.min_disks(),
+                disks.len()
+            );
+        }
+        if let Some(disk) = disks.iter().duplicates().next() {
+            bail!("{} is a member of the arrays twice", disk.display());
+        }
+        Ok(())
+    }
+
+    /// The partition table written to every member disk.
+    fn table(&self) -> Gpt {
+        Gpt {
+            partitions: vec![
+                // for GRUB on BIOS systems, which is installed to every member
+                member_partition(PartitionType::BiosBoot, "bios", Some(ByteSize::mib(1))),
+                member_partition(PartitionType::Esp, "esp", Some(self.esp_size)),
+                member_partition(
+                    PartitionType::Xbootldr,
+                    "xbootldr",
+                    Some(self.xbootldr_size),
+                ),
+                member_partition(PartitionType::Raid, "root", None),
+            ],
+        }
+    }
+
+    fn arrays(&self) -> [Array; 3] {
+        let filesystem =
+            |part_type, label: &str, format, mountpoint: &str, options: &str| GptPartition {
+                part_type,
+                label: Some(label.to_owned()),
+                size: None,
+                format: Some(format),
+                mountpoint: Some(mountpoint.into()),
+                options: options.to_owned(),
+                encrypt: false,
+            };
+        let root_options = if self.root_format == Filesystem::Btrfs {
+            "compress=zstd:1"
+        } else {
+            ""
+        };
+        [
+            Array {
+                partno: 2,
+                level: "1",
+                metadata: "1.0",
+                filesystem: filesystem(
+                    PartitionType::Esp,
+                    "esp",
+                    Filesystem::Vfat,
+                    "/boot/efi",
+                    "umask=0077,shortname=winnt",
+                ),
+            },
+            Array {
+                partno: 3,
+                level: "1",
+                metadata: "1.0",
+                filesystem: filesystem(
+                    PartitionType::Xbootldr,
+                    "xbootldr",
+                    Filesystem::Ext4,
+                    "/boot",
+                    "",
+                ),
+            },
+            Array {
+                partno: 4,
+                level: self.level.as_arg(),
+                metadata: "1.2",
+                filesystem: filesystem(
+                    PartitionType::Root,
+                    "root",
+                    self.root_format,
+                    "/",
+                    root_options,
+                ),
+            },
+        ]
+    }
+
+    /// The mounts of the arrays, using `node` to find the device of each.
+    fn mounts<F>(&self, playbook: &crate::playbook::Playbook, node: F) -> Result<Mounts>
+    where
+        F: Fn(&Array) -> Result<PathBuf>,
+    {
+        let mut mounts = Mounts(vec![]);
+        for array in self.arrays() {
+            mounts
+                .0
+                .extend(array.filesystem.mount(node(&array)?, playbook));
+        }
+        mounts.sort_mounts();
+        Ok(mounts)
+    }
+}
+
+impl DiskProvisionerModule for Raid {
+    fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        self.check(disk)?;
+        if dry_run() {
+            tracing::info!(?disk, members = ?self.members, "Dry run, leaving the disks alone");
+            return self.mounts(playbook, |array| Ok(array.node()));
+        }
+
+        let disks = self.disks(disk);
+        let table = self.table();
+        for disk in &disks {
+            table.write_table(disk)?;
+            for mut cmd in reread_commands(disk) {
+                run_command(&mut cmd, None)?;
+            }
+        }
+        let arrays = self.arrays();
+        for array in &arrays {
+            run_command(&mut array.create_command(&disks), None)?;
+            // stopped again at the end, so the members aren't kept busy
+            session::acquire(Resource::RaidArray(array.node()));
+        }
+        // wait for udev to create the /dev/md/ links
+        run_command(Command::new("udevadm").arg("settle"), None)?;
+        for array in &arrays {
+            array.filesystem.format(array.node(), playbook)?;
+        }
+        // lsblk and GRUB know the arrays by their kernel names, e.g. /dev/md127
+        self.mounts(playbook, |array| {
+            (array.node().canonicalize())
+                .wrap_err_with(|| format!("{} was not created", array.node().display()))
+        })
+    }
+
+    fn reactivate(&self, _playbook: &crate::playbook::Playbook, mounts: &mut Mounts) -> Result<()> {
+        if dry_run() {
+            return Ok(());
+        }
+        // udev may have assembled them already, once the member partitions showed up
+        if !self.arrays().iter().all(|array| array.node().exists()) {
+            run_command(Command::new("mdadm").args(["--assemble", "--scan"]), None)?;
+        }
+        run_command(Command::new("udevadm").arg("settle"), None)?;
+        for array in self.arrays() {
+            let node = (array.node().canonicalize())
+                .wrap_err_with(|| format!("{} was not assembled", array.node().display()))?;
+            session::acquire(Resource::RaidArray(array.node()));
+            // the kernel names of the arrays may have changed since the previous run
+            if let Some(mount) = (mounts.0.iter_mut())
+                .find(|mount| Some(&mount.mountpoint) == array.filesystem.mountpoint.as_ref())
+            {
+                mount.partition = node;
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        self.check(disk)?;
+        let disks = self.disks(disk);
+        let table = self.table();
+        for disk in &disks {
+            step.push(Action::DiskWrite {
+                device: disk.to_path_buf(),
+                description: "replace the partition table with a new GPT".to_owned(),
+            });
+            for cmd in reread_commands(disk) {
+                step.push(Action::from_command(&cmd, false));
+            }
+            for (i, part) in table.partitions.iter().enumerate() {
+                let mut description = part.describe()?;
+                if part.size.is_none() {
+                    description.push_str(" filling the rest of the disk");
+                }
+                step.push(Action::DiskWrite {
+                    device: partition_node(disk, i + 1),
+                    description,
+                });
+            }
+        }
+        for array in self.arrays() {
+            step.push(Action::from_command(&array.create_command(&disks), false));
+            array.filesystem.plan_format(array.node(), playbook, step);
+        }
+        self.mounts(playbook, |array| Ok(array.node()))
+    }
+
+    fn mirrors(&self) -> &[PathBuf] {
+        &self.members
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::{Playbook, test_playbook};
+
+    fn playbook(level: &str, members: &[&str]) -> Playbook {
+        test_playbook(
+            "/dev/sda",
+            serde_json::json!({ "module": "Raid", "level": level, "members": members }),
+            serde_json::Value::Null,
+        )
+    }
+
+    #[test]
+    fn plan_mirror() {
+        let playbook = playbook("raid1", &["/dev/sdb"]);
+        let mut step = Step::default();
+        let mounts = (playbook.disk_provisioner)
+            .plan(&playbook, &mut step)
+            .unwrap();
+        assert_eq!(
+            (mounts.0.iter())
+                .map(|m| (
+                    m.partition.to_str().unwrap(),
+                    m.mountpoint.to_str().unwrap()
+                ))
+                .collect_vec(),
+            [
+                ("/dev/md/root", "/"),
+                ("/dev/md/xbootldr", "/boot"),
+                ("/dev/md/esp", "/boot/efi"),
+            ]
+        );
+        assert!(mounts.get_esp_partition().is_some());
+        assert!(mounts.get_xbootldr_partition().is_some());
+
+        let actions = step.actions.iter().map(ToString::to_string).collect_vec();
+        for needle in [
+            "mdadm --create /dev/md/esp --run --level 1 --raid-devices 2 --metadata 1.0 /dev/sda2 /dev/sdb2",
+            "mdadm --create /dev/md/root --run --level 1 --raid-devices 2 --metadata 1.2 /dev/sda4 /dev/sdb4",
+            "mkfs.xfs -f -L root /dev/md/root",
+            "create bios-boot partition labelled \"bios\" of 1.0 MiB",
+        ] {
+            assert!(
+                actions.iter().any(|a| a.contains(needle)),
+                "no {needle:?} in {actions:#?}"
+            );
+        }
+        assert_eq!(
+            (playbook.disk_provisioner).mirrors(),
+            [PathBuf::from("/dev/sdb")]
+        );
+    }
+
+    #[test]
+    fn raid10_needs_four_disks() {
+        let raid10 = playbook("raid10", &["/dev/sdb", "/dev/sdc"]);
+        let err = (raid10.disk_provisioner)
+            .plan(&raid10, &mut Step::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "raid10 needs at least 4 disks, but there are 3"
+        );
+
+        let twice = playbook("raid1", &["/dev/sda"]);
+        let err = (twice.disk_provisioner)
+            .plan(&twice, &mut Step::default())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "/dev/sda is a member of the arrays twice");
+    }
+}
@@ -1,11 +1,22 @@
 use gpt::partition_types;
-use repart::{Config, EncryptOption, Output, OutputPartition, Partition};
+use repart::{Config, EncryptOption, Output, OutputPartition, PartTypeIdent, Partition};
 
 use file_guard::Lock;
-use std::{collections::BTreeMap, process::Stdio, str::FromStr};
+use std::{collections::BTreeMap, fmt::Write as _, io::Write as _, process::Stdio, str::FromStr};
 use uuid::Uuid;
 
-use crate::{backend::provisioners::disk::DiskProvisionerModule, prelude::*};
+use crate::{
+    backend::{
+        provisioners::disk::{
+            DiskProvisionerModule, dry_run, gpt::PartitionType, ini_value, layout::Layout,
+        },
+        util::fs::partition_node,
+    },
+    plan::{Action, Step},
+    playbook::EncryptionConfig,
+    prelude::*,
+    preview::{Preview, PreviewPartition},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SystemdRepartData {
@@ -42,126 +53,570 @@ impl SystemdRepartData {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+/// What systemd-repart does with the partitions already on the disk, like its `--empty=`.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Empty {
+    /// Wipe the disk and write a new partition table.
+    #[default]
+    Force,
+    /// Add partitions next to the existing ones, creating a partition table if there is none.
+    Allow,
+    /// Add partitions next to the existing ones, failing if there is no partition table.
+    Refuse,
+}
+
+impl Empty {
+    const fn as_arg(self) -> &'static str {
+        match self {
+            Self::Force => "force",
+            Self::Allow => "allow",
+            Self::Refuse => "refuse",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Repart {
-    pub directory: PathBuf,
+    /// Directory of the definition files. Leave out when giving a [`Self::layout`] instead.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// Partitions to create, compiled into definitions instead of reading [`Self::directory`].
+    #[serde(default)]
+    pub layout: Option<Layout>,
     pub copy_source: Option<PathBuf>,
+    /// Keep the existing partitions with [`Empty::Allow`] or [`Empty::Refuse`].
+    ///
+    /// An existing ESP is then shared, but no other existing partition may match a definition,
+    /// since systemd-repart would take it over instead of creating a new one.
+    #[serde(default)]
+    pub empty: Empty,
+    /// Types of the partitions to encrypt when the playbook sets
+    /// [encryption](crate::playbook::Playbook::encryption).
+    ///
+    /// Definitions that set `Encrypt=` themselves are left as they are, so a single set of
+    /// definitions works for encrypted and unencrypted installations. Doesn't apply to a
+    /// [`Self::layout`], whose partitions say whether they are encrypted.
+    #[serde(default = "default_encrypt")]
+    pub encrypt: Vec<PartitionType>,
+}
+
+fn default_encrypt() -> Vec<PartitionType> {
+    vec![PartitionType::Root]
+}
+
+/// The `Encrypt=` Readymade sets, following the playbook's encryption settings.
+pub(super) const fn encrypt_option(encryption: &EncryptionConfig) -> EncryptOption {
+    if encryption.tpm {
+        EncryptOption::KeyFileTpm2
+    } else {
+        EncryptOption::KeyFile
+    }
+}
+
+/// Set `Encrypt=` in the `[Partition]` section of the definition file `contents`.
+fn set_encrypt(contents: &str, encrypt: EncryptOption) -> Result<String> {
+    let mut ini = serde_systemd_unit::parse(contents)
+        .map_err(|errs| eyre!("cannot parse definition: {}", errs.iter().join(", ")))?;
+    (ini.sections.entry("Partition".to_owned()).or_default()).insert(
+        "Encrypt".to_owned(),
+        serde_systemd_unit::Value::String(ini_value(&encrypt)?),
+    );
+    Ok(ini.to_string())
+}
+
+impl Repart {
+    /// Whether Readymade sets `Encrypt=` on `config` when encrypting, see [`Self::encrypt`].
+    fn encrypts(&self, config: &Config) -> bool {
+        matches!(config.partition.encrypt, EncryptOption::Off)
+            && gpt_type_of(&config.partition.part_type).is_some_and(|gpt_type| {
+                (self.encrypt.iter()).any(|t| t.gpt_type().is_ok_and(|t| t == gpt_type))
+            })
+    }
+
+    /// The directory of the definition files, if they aren't compiled from a layout.
+    fn directory(&self) -> Result<Option<&Path>> {
+        match (&self.directory, &self.layout) {
+            (Some(directory), None) => Ok(Some(directory)),
+            (None, Some(_)) => Ok(None),
+            (Some(_), Some(_)) => bail!("repart takes either a directory or a layout, not both"),
+            (None, None) => bail!("repart needs a directory of definitions or a layout"),
+        }
+    }
+
+    /// Read the repart definitions, sorted by file name like systemd-repart does.
+    ///
+    /// With `encryption`, they are read the way [`Self::private_definitions`] writes them.
+    pub(crate) fn sorted_configs(
+        &self,
+        encryption: Option<&EncryptionConfig>,
+    ) -> Result<Vec<(String, Config)>> {
+        let Some(directory) = self.directory()? else {
+            return (self.layout.as_ref()).map_or_else(|| Ok(vec![]), |l| l.compile(encryption));
+        };
+        let repartcfg_export = SystemdRepartData::get_configs(directory)?;
+        let mut configs = repartcfg_export.configs.into_iter().collect_vec();
+        configs.sort_by_key(|(k, _)| k.clone());
+        for (_, config) in &mut configs {
+            if let Some(encryption) = encryption.filter(|_| self.encrypts(config)) {
+                config.partition.encrypt = encrypt_option(encryption);
+            }
+        }
+        Ok(configs)
+    }
+
+    /// Write the definitions to a private directory, if systemd-repart can't read them from
+    /// [`Self::directory`] as they are: when they are compiled from [`Self::layout`], or to set
+    /// `Encrypt=` with `encryption`.
+    fn private_definitions(
+        &self,
+        encryption: Option<&EncryptionConfig>,
+    ) -> Result<Option<tempfile::TempDir>> {
+        match (self.directory()?, encryption, &self.layout) {
+            (Some(_), None, _) => Ok(None),
+            (Some(directory), Some(encryption), _) => self.layer(directory, encryption).map(Some),
+            (None, _, layout) => {
+                let layer = tempfile::Builder::new()
+                    .prefix("readymade-repart")
+                    .tempdir()?;
+                if let Some(layout) = layout {
+                    layout.write(layer.path(), encryption)?;
+                }
+                Ok(Some(layer))
+            }
+        }
+    }
+
+    /// Copy the definitions in `directory` to a private directory with `Encrypt=` set on those
+    /// Readymade encrypts, leaving the originals untouched.
+    fn layer(&self, directory: &Path, encryption: &EncryptionConfig) -> Result<tempfile::TempDir> {
+        let layer = tempfile::Builder::new()
+            .prefix("readymade-repart")
+            .tempdir()?;
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().filter(|_| path.is_file()) else {
+                continue;
+            };
+            let mut contents = std::fs::read_to_string(&path)?;
+            let config: Config = serde_systemd_unit::from_str(&contents)?;
+            if self.encrypts(&config) {
+                tracing::debug!(?path, "Encrypting partition");
+                contents = set_encrypt(&contents, encrypt_option(encryption))
+                    .wrap_err_with(|| format!("cannot encrypt {}", path.display()))?;
+            }
+            std::fs::write(layer.path().join(name), contents)?;
+        }
+        Ok(layer)
+    }
+
+    /// The partition number each definition will get on `disk`, in definition order, and whether
+    /// that partition exists already.
+    ///
+    /// Unless the disk is wiped, this checks that the new partitions fit next to the existing ones.
+    fn partition_numbers(
+        &self,
+        disk: &Path,
+        configs: &[(String, Config)],
+    ) -> Result<Vec<(u32, bool)>> {
+        let numbers = (1..).take(configs.len()).map(|n| (n, false)).collect();
+        if self.empty == Empty::Force {
+            return Ok(numbers);
+        }
+        let table = match gpt::disk::read_disk(disk) {
+            Ok(table) => table,
+            Err(_) if self.empty == Empty::Allow => return Ok(numbers),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!("cannot read the partition table of {}", disk.display())
+                });
+            }
+        };
+        let lb = u64::from(*table.logical_block_size());
+        // a partition can't span two stretches of free space, so only count the largest one
+        let free = (table.find_free_sectors().iter())
+            .map(|(_, len)| len * lb)
+            .max()
+            .unwrap_or_default();
+        let existing = (table.partitions().iter())
+            .map(|(id, part)| (*id, part.part_type_guid.clone()))
+            .collect_vec();
+        place_definitions(configs, &existing, free)
+            .wrap_err_with(|| format!("cannot add partitions to {}", disk.display()))
+    }
+
+    /// Let systemd-repart lay out the partitions on `disk` without writing anything, to show the
+    /// user what the installation does to the disk.
+    ///
+    /// # Errors
+    /// The definitions cannot be read or don't fit on the disk, or systemd-repart fails.
+    pub fn preview(
+        &self,
+        disk: &Path,
+        encryption: Option<&EncryptionConfig>,
+        key: Option<&str>,
+    ) -> Result<Preview> {
+        let configs = self.sorted_configs(encryption)?;
+        self.partition_numbers(disk, &configs)?;
+        let layer = self.private_definitions(encryption)?;
+        let directory = (layer.as_ref().map(tempfile::TempDir::path))
+            .or(self.directory()?)
+            .ok_or_else(|| eyre!("no directory of definitions"))?;
+        let output = systemd_repart(
+            disk,
+            directory,
+            key,
+            self.copy_source.as_deref(),
+            self.empty,
+            None,
+            true,
+        )?;
+        // systemd-repart starts from an empty table, so it doesn't report what it drops
+        let deleted = if self.empty == Empty::Force {
+            existing_partitions(disk)
+        } else {
+            vec![]
+        };
+        let size = crate::disks::size_of(disk).unwrap_or_default();
+        Ok(Preview::new(&output, deleted, size))
+    }
+}
+
+/// The partitions in the partition table of `disk`, if it has one.
+fn existing_partitions(disk: &Path) -> Vec<PreviewPartition> {
+    let table = match gpt::disk::read_disk(disk) {
+        Ok(table) => table,
+        Err(err) => {
+            tracing::debug!(?err, ?disk, "no partition table to preview");
+            return vec![];
+        }
+    };
+    let lb = *table.logical_block_size();
+    (table.partitions().iter())
+        .map(|(number, part)| PreviewPartition {
+            number: u64::from(*number),
+            part_type: part.part_type_guid.guid.to_string(),
+            label: part.name.clone(),
+            offset: part.bytes_start(lb).unwrap_or_default(),
+            size: bytesize::ByteSize::b(part.bytes_len(lb).unwrap_or_default()),
+            activity: repart::Activity::Delete,
+        })
+        .collect()
+}
+
+/// Number the partitions of `configs` next to the `existing` partitions, given as their numbers and
+/// types, with `free` bytes in the largest stretch of unpartitioned space. See
+/// [`Repart::partition_numbers`].
+///
+/// Definitions of the ESP share an existing one. Any other existing partition of a type that is
+/// defined would be taken over by systemd-repart, so that is refused.
+fn place_definitions(
+    configs: &[(String, Config)],
+    existing: &[(u32, partition_types::Type)],
+    free: u64,
+) -> Result<Vec<(u32, bool)>> {
+    let mut unused = (1..).filter(|id| existing.iter().all(|(n, _)| n != id));
+    let mut needed = 0;
+    let mut numbers = vec![];
+    for (file, Config { partition }) in configs {
+        let part_type = gpt_type_of(&partition.part_type);
+        let matching = (existing.iter()).find(|(_, t)| Some(t) == part_type.as_ref());
+        match matching {
+            Some((id, _)) if matches!(partition.part_type, PartTypeIdent::Esp) => {
+                numbers.push((*id, true));
+            }
+            Some((id, _)) => {
+                bail!("partition {id} would be taken over by {file}, since it has the same type")
+            }
+            None => {
+                needed += partition.size_min_bytes.as_u64() + partition.padding_min_bytes.as_u64();
+                numbers.push((unused.next().unwrap_or_default(), false));
+            }
+        }
+    }
+    if needed > free {
+        bail!(
+            "the new partitions need at least {}, but only {} is free in one piece",
+            bytesize::ByteSize::b(needed),
+            bytesize::ByteSize::b(free)
+        );
+    }
+    Ok(numbers)
+}
+
+/// The GPT type of a repart `Type=`, if it is one Readymade knows.
+fn gpt_type_of(part_type: &PartTypeIdent) -> Option<partition_types::Type> {
+    let known = match part_type {
+        PartTypeIdent::Esp => PartitionType::Esp,
+        PartTypeIdent::Xbootldr => PartitionType::Xbootldr,
+        PartTypeIdent::Swap => PartitionType::Swap,
+        PartTypeIdent::Home => PartitionType::Home,
+        PartTypeIdent::Srv => PartitionType::Srv,
+        PartTypeIdent::Var => PartitionType::Var,
+        PartTypeIdent::LinuxGeneric => PartitionType::LinuxGeneric,
+        PartTypeIdent::Root | PartTypeIdent::RootArch => PartitionType::Root,
+        PartTypeIdent::Unknown(uuid) => {
+            return Uuid::from_str(uuid).ok().map(partition_types::Type::from);
+        }
+        _ => return None,
+    };
+    known.gpt_type().ok()
+}
+
+/// The GPT type a repart `Type=` will end up as, as far as later steps are concerned.
+///
+/// Types Readymade doesn't know are left as the unused type.
+fn planned_gpt_type(part_type: &PartTypeIdent) -> partition_types::Type {
+    gpt_type_of(part_type).unwrap_or_else(|| partition_types::Type::from(Uuid::nil()))
+}
+
+/// Turn the `MountPoint=` entries of a repart definition into [`Mount`]s on `node`.
+fn config_mounts(
+    Config {
+        partition:
+            Partition {
+                label,
+                mount_point,
+                encrypt,
+                ..
+            },
+    }: Config,
+    node: &Path,
+) -> impl Iterator<Item = Mount> + '_ {
+    mount_point.into_iter().filter_map(move |mount_point| {
+        if mount_point.is_empty() {
+            return None;
+        }
+        // If there's a colon, split it into two fields
+        // only the first colon is considered though, so if there are more than one, the rest are ignored
+        let mut parts = mount_point.splitn(2, ':');
+        let fst = parts.next()?.to_owned();
+        let snd = parts.next().map(std::borrow::ToOwned::to_owned);
+        Some(Mount {
+            label: label.clone(),
+            partition: node.to_path_buf(),
+            mountpoint: PathBuf::from(fst),
+            options: snd.unwrap_or_default(),
+            encryption_type: match encrypt {
+                EncryptOption::Off => None,
+                EncryptOption::KeyFile => Some(EncryptionOption::KeyFile),
+                EncryptOption::Tpm2 => Some(EncryptionOption::Tpm2),
+                EncryptOption::KeyFileTpm2 => Some(EncryptionOption::KeyFileTpm2),
+            },
+            gpt_type: std::sync::OnceLock::default(),
+            // gpt_type: Some(partition_types::Type::from(
+            //     Uuid::from_str(part_type).unwrap(),
+            // )),
+        })
+    })
 }
 
 impl DiskProvisionerModule for Repart {
     fn run(&self, playbook: &crate::playbook::Playbook) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        let configs = self.sorted_configs(playbook.encryption.as_ref())?;
+        self.partition_numbers(disk, &configs)?;
+        let layer = self.private_definitions(playbook.encryption.as_ref())?;
+        let directory = (layer.as_ref().map(tempfile::TempDir::path))
+            .or(self.directory()?)
+            .ok_or_else(|| eyre!("no directory of definitions"))?;
         let repart_out = systemd_repart(
-            &playbook.destination_disk,
-            &self.directory,
-            playbook.encryption.is_some(),
+            disk,
+            directory,
+            playbook.encryption_key()?,
             self.copy_source.as_deref(),
+            self.empty,
+            seed(playbook),
+            dry_run(),
         )?;
-        let repartcfg_export = SystemdRepartData::get_configs(&self.directory)?;
-        let mut configs = repartcfg_export.configs.into_iter().collect_vec();
-        configs.sort_by_key(|(k, _)| k.clone());
-        Ok(Mounts(
-            configs
-                .into_iter()
-                .map(|(_, v)| v)
-                .enumerate()
-                .flat_map(
-                    |(
-                        i,
-                        Config {
-                            partition:
-                                Partition {
-                                    label,
-                                    mount_point,
-                                    encrypt,
-                                    ..
-                                },
-                        },
-                    )| {
-                        let OutputPartition {
-                            node, part_type, ..
-                        } = repart_out.partitions.get(i).expect("part doesn't exist");
-
-                        mount_point.into_iter().filter_map(move |mount_point| {
-                            if mount_point.is_empty() {
-                                return None;
-                            }
-                            // If there's a colon, split it into two fields
-                            // only the first colon is considered though, so if there are more than one, the rest are ignored
-                            let mut parts = mount_point.splitn(2, ':');
-                            let fst = parts.next()?.to_owned();
-                            let snd = parts.next().map(std::borrow::ToOwned::to_owned);
-                            Some(std::io::Result::Ok(Mount {
-                                label: label.clone(),
-                                partition: PathBuf::from(node),
-                                mountpoint: PathBuf::from(fst),
-                                options: snd.unwrap_or_default(),
-                                encryption_type: match encrypt {
-                                    EncryptOption::Off => None,
-                                    EncryptOption::KeyFile => Some(EncryptionOption::KeyFile),
-                                    EncryptOption::Tpm2 => Some(EncryptionOption::Tpm2),
-                                    EncryptOption::KeyFileTpm2 => {
-                                        Some(EncryptionOption::KeyFileTpm2)
-                                    }
-                                },
-                                gpt_type: std::cell::OnceCell::default(),
-                                // gpt_type: Some(partition_types::Type::from(
-                                //     Uuid::from_str(part_type).unwrap(),
-                                // )),
-                            }))
-                        })
-                    },
-                )
-                .try_collect()?,
-        ))
+        let mut mounts = vec![];
+        for (file, config) in configs {
+            let OutputPartition { node, .. } = (repart_out.partition_for(&file))
+                .ok_or_else(|| eyre!("systemd-repart did not report a partition for {file}"))?;
+            mounts.extend(config_mounts(config, Path::new(node)));
+        }
+        Ok(Mounts(mounts))
+    }
+
+    fn plan(&self, playbook: &crate::playbook::Playbook, step: &mut Step) -> Result<Mounts> {
+        let disk = playbook.disk()?;
+        let directory = if let Some(directory) = self.directory()? {
+            if let Some(encryption) = &playbook.encryption {
+                step.push(Action::note(format!(
+                    "copy the definitions to a private directory and set Encrypt={} there",
+                    ini_value(&encrypt_option(encryption))?
+                )));
+            }
+            directory.to_owned()
+        } else {
+            step.push(Action::note(
+                "write the definitions compiled from the layout to a private directory".to_owned(),
+            ));
+            std::env::temp_dir().join("readymade-repart")
+        };
+        step.push(Action::from_command(
+            &repart_command(
+                disk,
+                &directory,
+                playbook.encryption.is_some(),
+                self.copy_source.as_deref(),
+                self.empty,
+                seed(playbook),
+                false,
+            ),
+            false,
+        ));
+        step.push(Action::DiskWrite {
+            device: disk.to_owned(),
+            description: match self.empty {
+                Empty::Force => "replace the partition table with a new GPT",
+                Empty::Allow | Empty::Refuse => "add partitions next to the existing ones",
+            }
+            .to_owned(),
+        });
+
+        let configs = self.sorted_configs(playbook.encryption.as_ref())?;
+        let numbers = self.partition_numbers(disk, &configs)?;
+        let mut mounts = vec![];
+        for ((file, config), (number, existing)) in configs.into_iter().zip(numbers) {
+            let node = partition_node(disk, number as usize);
+            if existing {
+                step.push(Action::note(format!(
+                    "share the existing ESP {}",
+                    node.display()
+                )));
+                mounts.extend(config_mounts(config, &node));
+                continue;
+            }
+            let p = &config.partition;
+            let mut description =
+                format!("create {} partition from {file}", ini_value(&p.part_type)?);
+            if let Some(label) = &p.label {
+                write!(description, " labelled {label:?}")?;
+            }
+            if let Some(format) = &p.format {
+                write!(description, ", formatted as {}", ini_value(format)?)?;
+            }
+            if !matches!(p.encrypt, EncryptOption::Off) {
+                write!(description, ", encrypted ({})", ini_value(&p.encrypt)?)?;
+            }
+            if !p.copy_files.is_empty() {
+                write!(description, ", copying {}", p.copy_files.join(":"))?;
+            }
+            step.push(Action::DiskWrite {
+                device: node.clone(),
+                description,
+            });
+            let gpt_type = planned_gpt_type(&p.part_type);
+            mounts.extend(config_mounts(config, &node).inspect(|mount| {
+                // the partition does not exist yet, so `Mount::get_gpt_type` can't probe it
+                _ = mount.gpt_type.set(gpt_type.clone());
+            }));
+        }
+        Ok(Mounts(mounts))
     }
 }
 
-fn systemd_repart(
+/// Build the `systemd-repart` invocation for `blockdev`.
+fn repart_command(
     blockdev: &Path,
     cfgdir: &Path,
     use_keyfile: bool,
     copy_source: Option<&Path>,
+    empty: Empty,
+    seed: Option<Uuid>,
+    dry_run: bool,
+) -> Command {
+    // the key is written to stdin, so it never touches a filesystem
+    let arg_keyfile = use_keyfile.then_some(["--key-file", "/dev/stdin"]);
+
+    // HACK: Disable whole-device TRIM to reduce wear on SSDs and formatting time
+    // https://github.com/systemd/systemd/issues/32760
+    // TODO: Turn off once systemd 259 lands
+    // https://github.com/systemd/systemd/commit/29ee9c6fb7c75c421f887c8579c65eb04d4f634d
+    let mut cmd = Command::new("systemd-repart");
+
+    if let Some(copy_source) = copy_source {
+        cmd.args(["--copy-source", copy_source.to_str().unwrap()]);
+    }
+    if let Some(seed) = seed {
+        cmd.args(["--seed", &seed.to_string()]);
+    }
+
+    cmd.env("SYSTEMD_REPART_MKFS_OPTIONS_BTRFS", "--nodiscard")
+        .args(["--dry-run", if dry_run { "yes" } else { "no" }])
+        .args(["--definitions", cfgdir.to_str().unwrap()])
+        .args([
+            "--empty",
+            empty.as_arg(),
+            "--offline",
+            "false",
+            "--json",
+            "pretty",
+        ])
+        .args(arg_keyfile.iter().flatten())
+        .arg(blockdev);
+    cmd
+}
+
+/// The seed for the UUIDs systemd-repart makes up, set for reproducible disk images.
+fn seed(playbook: &crate::playbook::Playbook) -> Option<Uuid> {
+    (playbook.image.as_ref()).and_then(|image| image.seed)
+}
+
+fn systemd_repart(
+    blockdev: &Path,
+    cfgdir: &Path,
+    key: Option<&str>,
+    copy_source: Option<&Path>,
+    empty: Empty,
+    seed: Option<Uuid>,
+    dry_run: bool,
 ) -> Result<Output> {
-    let dry_run = std::env::var("READYMADE_DRY_RUN").map_or(cfg!(debug_assertions), |v| v == "1");
     tracing::debug!(?dry_run, "Running systemd-repart");
-    let arg_keyfile = use_keyfile.then(|| {
-        let keyfile_path = crate::consts::LUKS_KEYFILE_PATH;
-        tracing::debug!("Using keyfile for systemd-repart: {keyfile_path}");
-        ["--key-file", keyfile_path]
-    });
 
     // Scope to ensure device and lock live long enough for the command
     let repart_cmd = {
-        // We are locking the device so that repart doesn't fail due to device busy
+        // a dry run only reads the device, so it must not block an installation running on it
         let mut device = std::fs::OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(!dry_run)
             .open(blockdev)
             .context("Failed to open block device")?;
-        let mut _lock = file_guard::lock(&mut device, Lock::Exclusive, 0, 1)?;
-
-        // HACK: Disable whole-device TRIM to reduce wear on SSDs and formatting time
-        // https://github.com/systemd/systemd/issues/32760
-        // TODO: Turn off once systemd 259 lands
-        // https://github.com/systemd/systemd/commit/29ee9c6fb7c75c421f887c8579c65eb04d4f634d
-        let mut cmd = Command::new("systemd-repart");
-
-        if let Some(copy_source) = copy_source {
-            cmd.args(["--copy-source", copy_source.to_str().unwrap()]);
-        }
+        // We are locking the device so that repart doesn't fail due to device busy
+        let _lock = if dry_run {
+            None
+        } else {
+            Some(file_guard::lock(&mut device, Lock::Exclusive, 0, 1)?)
+        };
 
-        cmd.env("SYSTEMD_REPART_MKFS_OPTIONS_BTRFS", "--nodiscard")
-            .args(["--dry-run", if dry_run { "yes" } else { "no" }])
-            .args(["--definitions", cfgdir.to_str().unwrap()])
-            .args(["--empty", "force", "--offline", "false", "--json", "pretty"])
-            .args(arg_keyfile.iter().flatten())
-            .arg(blockdev)
+        let mut cmd = repart_command(
+            blockdev,
+            cfgdir,
+            key.is_some(),
+            copy_source,
+            empty,
+            seed,
+            dry_run,
+        );
+        cmd.stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::inherit());
 
         tracing::debug!(?cmd, "Executing systemd-repart command");
 
-        cmd.output().context("can't run systemd-repart")?
+        let mut child = cmd.spawn().context("can't run systemd-repart")?;
+        // dropping stdin closes it, so repart reads the key up to EOF
+        if let (Some(mut stdin), Some(key)) = (child.stdin.take(), key) {
+            stdin
+                .write_all(key.as_bytes())
+                .context("can't pass the encryption key to systemd-repart")?;
+        }
+        child
+            .wait_with_output()
+            .context("can't run systemd-repart")?
     };
 
     if !repart_cmd.status.success() {
@@ -175,3 +630,125 @@ fn systemd_repart(
     tracing::debug!("systemd-repart finished");
     Ok(serde_json::from_slice(&repart_cmd.stdout)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(part_type: &str, size: &str) -> (String, Config) {
+        let ini = format!("[Partition]\nType={part_type}\nSizeMinBytes={size}\n");
+        (
+            format!("{part_type}.conf"),
+            serde_systemd_unit::from_str(&ini).unwrap(),
+        )
+    }
+
+    #[test]
+    fn encrypt_root_only() {
+        let repart: Repart = serde_json::from_value(serde_json::json!({
+            "directory": "/usr/share/readymade/repart-cfgs/wholedisk",
+            "copy_source": null,
+        }))
+        .unwrap();
+        assert_eq!(repart.encrypt, [PartitionType::Root]);
+        let (_, root) = config("root", "10G");
+        let (_, arch) = config(
+            &format!("root-{}", std::env::consts::ARCH.replace('_', "-")),
+            "10G",
+        );
+        let (_, esp) = config("esp", "512M");
+        assert!(repart.encrypts(&root) && repart.encrypts(&arch) && !repart.encrypts(&esp));
+        let tpm: Config =
+            serde_systemd_unit::from_str("[Partition]\nType=root\nEncrypt=tpm2\n").unwrap();
+        assert!(!repart.encrypts(&tpm));
+
+        let definition =
+            "[Partition]\nType=root\nMountPoint=/:subvol=root\nMountPoint=/home:subvol=home\n";
+        let encrypted = set_encrypt(definition, EncryptOption::KeyFileTpm2).unwrap();
+        let config: Config = serde_systemd_unit::from_str(&encrypted).unwrap();
+        assert!(matches!(
+            config.partition.encrypt,
+            EncryptOption::KeyFileTpm2
+        ));
+        assert_eq!(
+            config.partition.mount_point,
+            ["/:subvol=root", "/home:subvol=home"]
+        );
+    }
+
+    #[test]
+    fn plan_layout() {
+        let playbook = crate::playbook::test_playbook(
+            "/dev/sda",
+            serde_json::json!({
+                "module": "Repart",
+                "copy_source": "/run/rootfsbase",
+                "layout": { "partitions": [
+                    { "type": "esp", "format": "vfat", "min_size": "512 MiB", "mountpoint": "/boot/efi" },
+                    { "type": "root", "format": "btrfs", "subvolumes": [
+                        { "path": "/", "mountpoint": "/" },
+                        { "path": "/home", "mountpoint": "/home" },
+                    ] },
+                ] },
+            }),
+            serde_json::Value::Null,
+        );
+        let mut step = Step::default();
+        let mounts = (playbook.disk_provisioner)
+            .plan(&playbook, &mut step)
+            .unwrap();
+        assert_eq!(
+            (mounts.0.iter())
+                .map(|m| (
+                    m.partition.to_str().unwrap(),
+                    m.mountpoint.to_str().unwrap(),
+                    m.options.as_str()
+                ))
+                .collect_vec(),
+            [
+                ("/dev/sda1", "/boot/efi", ""),
+                ("/dev/sda2", "/", "subvol=/"),
+                ("/dev/sda2", "/home", "subvol=/home"),
+            ]
+        );
+        let actions = step.actions.iter().map(ToString::to_string).collect_vec();
+        assert!(
+            (actions.iter()).any(|a| a.contains("create root partition from 20-root.conf")),
+            "{actions:#?}"
+        );
+
+        let both: Repart = serde_json::from_value(serde_json::json!({
+            "directory": "/tmp/repart",
+            "copy_source": null,
+            "layout": { "partitions": [] },
+        }))
+        .unwrap();
+        assert_eq!(
+            both.sorted_configs(None).unwrap_err().to_string(),
+            "repart takes either a directory or a layout, not both"
+        );
+    }
+
+    #[test]
+    fn place_next_to_existing() {
+        let configs = [
+            config("esp", "512M"),
+            config("xbootldr", "1G"),
+            config("root", "10G"),
+        ];
+        let esp = PartitionType::Esp.gpt_type().unwrap();
+        let ntfs = partition_types::BASIC;
+        let existing = [(1, esp), (2, ntfs.clone()), (4, ntfs)];
+
+        assert_eq!(
+            place_definitions(&configs, &existing, 20 * bytesize::GIB).unwrap(),
+            [(1, true), (3, false), (5, false)]
+        );
+        let err = place_definitions(&configs, &existing, 10 * bytesize::GIB).unwrap_err();
+        assert!(err.to_string().contains("only 10.0 GiB is free"), "{err}");
+
+        let linux = [(1, PartitionType::Root.gpt_type().unwrap())];
+        let err = place_definitions(&configs, &linux, 20 * bytesize::GIB).unwrap_err();
+        assert!(err.to_string().contains("would be taken over"), "{err}");
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
     backend::{mounts::generate_cryptdata, provisioners::filesystem::FileSystemProvisionerModule},
+    plan::{Action, Step},
     prelude::*,
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Bootc {
     pub imgref: String,
     pub target_imgref: Option<String>,
@@ -18,28 +19,35 @@ impl Bootc {
     /// The caller must verify that `self.copy_mode.is_bootc()`.
     #[allow(clippy::unwrap_in_result, clippy::needless_pass_by_value)]
     pub fn bootc_copy(&self, target_root: &Path, cryptdata: Option<CryptData>) -> Result<()> {
-        let imgref = &self.imgref;
-        let target_imgref = &self.target_imgref;
-        let enforce_sigpolicy = &self.enforce_sigpolicy;
-        let args = &self.args;
-
         tracing::info!(imgref=?self.imgref, "running bootc install to-filesystem");
 
-        crate::cmd!("bootc" [
-            ["install", "to-filesystem", "--source-imgref", imgref],
-            (cryptdata.iter())
-                .flat_map(|data| data.cmdline_opts.iter().flat_map(|opt| ["--karg", opt])),
-            ["--karg=rhgb", "--karg=quiet", "--karg=splash"],
-            [target_root],
-            (target_imgref.iter()).flat_map(|a| ["--target-imgref", a]),
-            args.iter().flat_map(|e| ["--karg", e]),
-            enforce_sigpolicy.then_some("--enforce-container-sigpolicy"),
-            args.iter(),
-        ] => |cmd| bail!("`bootc install to-filesystem` failed: {:?}", cmd.code()));
+        let cryptargs = cryptdata.map(|data| data.cmdline_opts).unwrap_or_default();
+        let status = (self.bootc_command(target_root, &cryptargs).status())
+            .context("fail to execute bootc")?;
+        if !status.success() {
+            bail!("`bootc install to-filesystem` failed: {:?}", status.code());
+        }
 
         Ok(())
     }
 
+    /// Build the `bootc install to-filesystem` invocation without running it.
+    fn bootc_command(&self, target_root: &Path, cryptargs: &[String]) -> Command {
+        let mut cmd = Command::new("bootc");
+        cmd.args(["install", "to-filesystem", "--source-imgref", &self.imgref])
+            .args(cryptargs.iter().flat_map(|opt| ["--karg", opt]))
+            .args(["--karg=rhgb", "--karg=quiet", "--karg=splash"])
+            .arg(target_root)
+            .args((self.target_imgref.iter()).flat_map(|a| ["--target-imgref", a]))
+            .args(self.kargs.iter().flat_map(|e| ["--karg", e]))
+            .args(
+                self.enforce_sigpolicy
+                    .then_some("--enforce-container-sigpolicy"),
+            )
+            .args(&self.args);
+        cmd
+    }
+
     // This cleans up any folder that is not on the bootc whitelist from a bootc-installed filesystem
     fn bootc_cleanup(mountpoint: &Path) -> Result<()> {
         _ = std::fs::read_dir(mountpoint)?.try_for_each(|f| {
@@ -64,33 +72,46 @@ impl FileSystemProvisionerModule for Bootc {
     fn run(&self, playbook: &crate::playbook::Playbook, mounts: &Mounts) -> Result<()> {
         let tmproot = tempfile::tempdir()?;
         let bootc_rootfs_mountpoint = tmproot.path();
-        mounts.mount_all(
-            bootc_rootfs_mountpoint,
-            playbook
-                .encryption
-                .as_ref()
-                .map(|e| e.encryption_key.as_str()),
-        );
-
-        self.bootc_copy(bootc_rootfs_mountpoint, generate_cryptdata(mounts)?)?;
+        mounts.mount_all(bootc_rootfs_mountpoint, playbook.encryption_key()?)?;
+        // unmount before `tmproot` is dropped, or its contents would be deleted with it
+        scopeguard::defer! {
+            if let Err(e) = mounts.umount_all(bootc_rootfs_mountpoint) {
+                tracing::error!("Cannot unmount partitions: {e:?}");
+            }
+        };
 
-        mounts.umount_all(bootc_rootfs_mountpoint);
-        Ok(())
+        self.bootc_copy(bootc_rootfs_mountpoint, generate_cryptdata(mounts)?)
     }
 
     fn cleanup(&self, playbook: &crate::playbook::Playbook, mounts: &Mounts) -> Result<()> {
         let tmproot = tempfile::tempdir()?;
         let bootc_rootfs_mountpoint = tmproot.path();
-        mounts.mount_all(
-            bootc_rootfs_mountpoint,
-            playbook
-                .encryption
-                .as_ref()
-                .map(|e| e.encryption_key.as_str()),
-        );
+        mounts.mount_all(bootc_rootfs_mountpoint, playbook.encryption_key()?)?;
+        scopeguard::defer! {
+            if let Err(e) = mounts.umount_all(bootc_rootfs_mountpoint) {
+                tracing::error!("Cannot unmount partitions: {e:?}");
+            }
+        };
         Self::bootc_cleanup(bootc_rootfs_mountpoint)?;
         crate::cmd!("sync" => |_| bail!("`sync` failed"));
-        crate::cmd!("umount" [["-R"], [bootc_rootfs_mountpoint]] => |_| bail!("umount -R {bootc_rootfs_mountpoint:?} failed"));
+        Ok(())
+    }
+
+    fn plan(&self, _: &crate::playbook::Playbook, mounts: &Mounts, step: &mut Step) -> Result<()> {
+        let root = Path::new("<tmproot>");
+        mounts.plan_mount_all(root, step);
+        // the UUIDs of the LUKS containers are only known once they have been formatted
+        let cryptargs = (mounts.0.iter())
+            .filter(|mount| mount.encryption_type.is_some())
+            .map(|mount| {
+                let label = mount.label.as_deref().unwrap_or("<label>");
+                format!("rd.luks.name=<uuid>={label}")
+            })
+            .collect_vec();
+        step.push(Action::from_command(
+            &self.bootc_command(root, &cryptargs),
+            false,
+        ));
         Ok(())
     }
 }
@@ -1,15 +1,23 @@
 use crate::{
-    backend::provisioners::filesystem::FileSystemProvisionerModule, backend::util::fs::copy_dir,
+    backend::provisioners::filesystem::FileSystemProvisionerModule,
+    backend::util::fs::copy_dir,
+    plan::{Action, Step},
     prelude::*,
+    session::{self, Resource},
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
 pub struct Copy {
     /// Either a local path, a mountable image file, or an OCI image reference with
     /// a Podman-recognized transport prefix such as `containers-storage:`.
     pub copy_source: String,
 }
 
+/// Where the target partitions are mounted while copying.
+const DESTROOT: &str = "/mnt/custom";
+/// Where image files used as a copy source are mounted.
+const IMAGE_MOUNT_PATH: &str = "/mnt/rdmsqsh";
+
 const OCI_COPY_SOURCE_PREFIXES: [&str; 4] =
     ["containers-storage:", "docker://", "oci:", "oci-archive:"];
 
@@ -44,39 +52,12 @@ fn podman_stdout(args: &[&str], action: &str) -> Result<String> {
     Ok(stdout)
 }
 
-fn cleanup_podman_container(container_id: &str) {
-    if let Err(err) = Command::new("podman")
-        .args(["umount", container_id])
-        .status()
-    {
-        tracing::warn!(
-            ?err,
-            ?container_id,
-            "podman umount cleanup command failed to execute"
-        );
-    }
-
-    if let Err(err) = Command::new("podman")
-        .args(["rm", "-f", container_id])
-        .status()
-    {
-        tracing::warn!(
-            ?err,
-            ?container_id,
-            "podman rm cleanup command failed to execute"
-        );
-    }
-}
-
 impl FileSystemProvisionerModule for Copy {
     fn run(&self, playbook: &crate::playbook::Playbook, mounts: &Mounts) -> Result<()> {
-        let destroot = Path::new("/mnt/custom");
+        let destroot = Path::new(DESTROOT);
         let mut mounts = mounts.clone();
         mounts.sort_mounts();
-        mounts.mount_all(
-            destroot,
-            playbook.encryption.as_ref().map(|e| &*e.encryption_key),
-        )?;
+        mounts.mount_all(destroot, playbook.encryption_key()?)?;
 
         scopeguard::defer! {
             if let Err(e) = mounts.umount_all(destroot) {
@@ -95,8 +76,12 @@ impl FileSystemProvisionerModule for Copy {
             crate::stage!(extracting "Extracting files" {
                 tracing::info!(copy_source, "Copy source is an OCI image, mounting with podman");
                 let container_id = podman_stdout(&["create", copy_source], "create")?;
+                let container = Resource::PodmanContainer(container_id.clone());
+                session::acquire(container.clone());
                 scopeguard::defer! {
-                    cleanup_podman_container(&container_id);
+                    if let Err(err) = session::release(&container) {
+                        tracing::warn!(?err, "Cannot remove podman container");
+                    }
                 }
 
                 let mount_path = podman_stdout(&["mount", &container_id], "mount")?;
@@ -105,24 +90,26 @@ impl FileSystemProvisionerModule for Copy {
         } else {
             let copy_source = PathBuf::from(copy_source);
             if copy_source.is_file() {
-                // XXX: we should be using consistent paths, maybe a const? -ci
-                const MOUNT_PATH: &str = "/mnt/rdmsqsh";
                 tracing::warn!("Copy source is a file, treating as an image to mount");
                 crate::stage!(extracting "Extracting files" {
-                    tracing::trace!(?MOUNT_PATH, "Mounting disk image");
-                    std::fs::create_dir_all(MOUNT_PATH)?;
+                    tracing::trace!(?IMAGE_MOUNT_PATH, "Mounting disk image");
+                    std::fs::create_dir_all(IMAGE_MOUNT_PATH)?;
                     let return_code = Command::new("mount")
                         .arg(&copy_source)
-                        .arg(MOUNT_PATH)
+                        .arg(IMAGE_MOUNT_PATH)
                         .status()?
                         .code();
                     if return_code.is_none_or(|return_code| return_code != 0) {
                         bail!("mount command returns rc={return_code:?}");
                     }
+                    let image_mount = Resource::Mount(PathBuf::from(IMAGE_MOUNT_PATH));
+                    session::acquire(image_mount.clone());
                     scopeguard::defer! {
-                        _ = Command::new("umount").arg(MOUNT_PATH).status();
+                        if let Err(err) = session::release(&image_mount) {
+                            tracing::warn!(?err, "Cannot unmount image");
+                        }
                     }
-                    copy_dir(MOUNT_PATH, destroot)?;
+                    copy_dir(IMAGE_MOUNT_PATH, destroot)?;
                 });
             } else {
                 crate::stage!(copying "Copying files" {
@@ -133,6 +120,38 @@ impl FileSystemProvisionerModule for Copy {
 
         Ok(())
     }
+
+    fn plan(&self, _: &crate::playbook::Playbook, mounts: &Mounts, step: &mut Step) -> Result<()> {
+        let destroot = Path::new(DESTROOT);
+        let mut mounts = mounts.clone();
+        mounts.sort_mounts();
+        mounts.plan_mount_all(destroot, step);
+
+        let copy_source = self.copy_source.trim();
+        if copy_source.is_empty() {
+            bail!("copy_source cannot be empty");
+        }
+
+        let source = if is_oci_copy_source(copy_source) {
+            step.push(Action::host_command("podman", ["create", copy_source]));
+            step.push(Action::host_command("podman", ["mount", "<container>"]));
+            format!("the {copy_source} container")
+        } else if Path::new(copy_source).is_file() {
+            step.push(Action::Mount {
+                source: PathBuf::from(copy_source),
+                target: PathBuf::from(IMAGE_MOUNT_PATH),
+                options: None,
+            });
+            format!("the {copy_source} image")
+        } else {
+            copy_source.to_owned()
+        };
+        step.push(Action::write_file(
+            "/",
+            &format!("copy all files from {source}"),
+        ));
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1,7 +1,7 @@
 mod bootc;
 mod copy;
 
-use crate::prelude::*;
+use crate::{plan::Step, prelude::*};
 use bootc::Bootc;
 use copy::Copy;
 
@@ -14,12 +14,26 @@ pub trait FileSystemProvisionerModule {
     fn cleanup(&self, playbook: &crate::playbook::Playbook, mounts: &Mounts) -> Result<()> {
         Ok(())
     }
+    /// Describe what [`FileSystemProvisionerModule::run`] would do without touching the disk.
+    ///
+    /// # Errors
+    /// The provisioner configuration is invalid.
+    fn plan(
+        &self,
+        playbook: &crate::playbook::Playbook,
+        mounts: &Mounts,
+        step: &mut Step,
+    ) -> Result<()>;
+    /// Name of the provisioner, as used in playbooks.
+    fn name(&self) -> &'static str {
+        crate::backend::util::type_name::<Self>()
+    }
 }
 
 /// The filesystem provisioner is responsible for copying the OS files to the partitions after the disk provisioner has set up the partitions.
 /// Provisioners may also use context from the playbook to determine how to provision the installation, such as the destination disk and encryption settings.
 #[enum_dispatch]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
 #[serde(tag = "module")]
 pub enum FileSystemProvisioner {
     /// Provisions a bootc install.
@@ -158,6 +158,8 @@ pub fn copy_dir_rdm<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> color_eyr
         busy_timeout: std::time::Duration::from_millis(100),
     });
 
+    let copied = std::sync::atomic::AtomicU64::new(0);
+
     walkdir
         .into_iter()
         .par_bridge()
@@ -178,6 +180,10 @@ pub fn copy_dir_rdm<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> color_eyr
             copy(&src_path, &dest_path, &metadata)?;
             copy_attributes(&src_path, &dest_path, &metadata)?;
 
+            let len = metadata.len();
+            let done = copied.fetch_add(len, std::sync::atomic::Ordering::Relaxed) + len;
+            crate::progress::copied_bytes(done, None);
+
             Ok(())
         })?;
 
@@ -393,6 +399,59 @@ pub fn get_whole_disk(partition_path: &Path) -> color_eyre::Result<String> {
     Ok(path)
 }
 
+/// Get the device node of partition `partno` on `disk`, following the kernel naming scheme.
+/// i.e. (/dev/sda, 1) -> /dev/sda1, (/dev/nvme0n1, 2) -> /dev/nvme0n1p2
+///
+/// This does not check whether the partition actually exists.
+#[must_use]
+pub fn partition_node(disk: &Path, partno: usize) -> PathBuf {
+    let mut node = disk.as_os_str().to_owned();
+    // disk names that end with a digit get a `p` separator, so that partitions can be told apart
+    if node
+        .as_encoded_bytes()
+        .last()
+        .is_some_and(u8::is_ascii_digit)
+    {
+        node.push("p");
+    }
+    node.push(partno.to_string());
+    node.into()
+}
+
+/// Check whether `path` refers to a partition rather than a whole disk.
+///
+/// Symlinks such as `/dev/disk/by-id/…` are resolved first. Paths that are not block devices
+/// known to sysfs are never partitions.
+#[must_use]
+pub fn is_partition(path: &Path) -> bool {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.file_name().is_some_and(|name| {
+        Path::new("/sys/class/block")
+            .join(name)
+            .join("partition")
+            .exists()
+    })
+}
+
+/// The member devices of the md RAID array `path`, sorted, or nothing if it is not an array.
+#[must_use]
+pub fn md_members(path: &Path) -> Vec<PathBuf> {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let Some(name) = path.file_name() else {
+        return vec![];
+    };
+    let sysfs = Path::new("/sys/class/block").join(name);
+    if !sysfs.join("md").exists() {
+        return vec![];
+    }
+    let mut members = (exist_then_read_dir(sysfs.join("slaves")).into_iter())
+        .flatten()
+        .map(|slave| Path::new("/dev").join(slave.file_name()))
+        .collect::<Vec<_>>();
+    members.sort();
+    members
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -433,6 +492,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_partition_node() {
+        assert_eq!(
+            partition_node(Path::new("/dev/sda"), 1),
+            Path::new("/dev/sda1")
+        );
+        assert_eq!(
+            partition_node(Path::new("/dev/nvme0n1"), 2),
+            Path::new("/dev/nvme0n1p2")
+        );
+        assert_eq!(
+            partition_node(Path::new("/dev/loop0"), 3),
+            Path::new("/dev/loop0p3")
+        );
+    }
+
     #[test]
     fn test_copy_recurse() -> color_eyre::Result<()> {
         test_copy_impl("recurse", |from, to| copy_dir_rdm(from, to))
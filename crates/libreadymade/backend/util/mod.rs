@@ -4,3 +4,10 @@ pub mod cmd;
 pub mod fs;
 pub mod macros;
 pub mod sys;
+
+/// The unqualified name of `T`, e.g. `GRUB2` for `libreadymade::backend::postinstall::grub2::GRUB2`.
+#[must_use]
+pub fn type_name<T: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
@@ -1,4 +1,5 @@
 mod osprobe;
+pub mod selector;
 
 use std::{
     collections::HashMap,
@@ -34,6 +35,28 @@ pub fn detect_os() -> Vec<Disk> {
         .collect()
 }
 
+/// Size of the disk at `devpath`, or of the file if it is a regular file.
+///
+/// Returns `None` if it is neither, or the size cannot be read.
+#[must_use]
+pub fn size_of(devpath: &Path) -> Option<bytesize::ByteSize> {
+    let path = std::fs::canonicalize(devpath).ok()?;
+    let metadata = std::fs::metadata(&path).ok()?;
+    if metadata.is_file() {
+        return Some(bytesize::ByteSize::b(metadata.len()));
+    }
+    // in 512-byte sectors, whatever the logical block size of the disk
+    let sectors = std::fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(path.file_name()?)
+            .join("size"),
+    )
+    .ok()?;
+    Some(bytesize::ByteSize::b(
+        sectors.trim().parse::<u64>().ok()? * 512,
+    ))
+}
+
 fn make_disk(osprobe: &HashMap<PathBuf, String>, mut disk: lsblk::BlockDevice) -> Disk {
     let model =
         (disk.sysfs()).and_then(|p| std::fs::read_to_string(p.join("device").join("model")));
@@ -0,0 +1,388 @@
+//! Disk selectors, so the same playbook can be used on machines with different disks.
+//!
+//! A selector is either a device path such as `/dev/sda`, or a comma-separated list of terms.
+//! Every filter term must match, then the pick term chooses between the remaining disks:
+//!
+//! | term               | meaning                                                     |
+//! |--------------------|-------------------------------------------------------------|
+//! | `id=<glob>`        | a `/dev/disk/by-id` name matches `<glob>` (`*` and `?`)     |
+//! | `min-size=<size>`  | at least `<size>`, e.g. `64GiB`                             |
+//! | `max-size=<size>`  | at most `<size>`                                            |
+//! | `non-removable`    | not a removable device, e.g. not a USB stick                |
+//! | `no-os`            | no operating system was detected on the disk                |
+//! | `largest`          | pick the largest matching disk                              |
+//! | `smallest`         | pick the smallest matching disk                             |
+//!
+//! For example, `smallest,min-size=64GiB,no-os`. Without a pick term, exactly one disk must match.
+//! Resolution never guesses: if several disks are equally good, it fails.
+
+use super::Disk;
+use crate::prelude::*;
+use bytesize::ByteSize;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde_with::SerializeDisplay, serde_with::DeserializeFromStr,
+)]
+pub enum DiskSelector {
+    /// A fixed device path.
+    Path(PathBuf),
+    /// Disks matching all `filters`, narrowed down by `pick`.
+    Match {
+        filters: Vec<Filter>,
+        pick: Option<Pick>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Id(String),
+    MinSize(ByteSize),
+    MaxSize(ByteSize),
+    NonRemovable,
+    NoOs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pick {
+    Largest,
+    Smallest,
+}
+
+/// A disk that a selector can resolve to, along with what selectors need to know about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub disk: Disk,
+    pub removable: bool,
+    /// Names of the disk in `/dev/disk/by-id`.
+    pub ids: Vec<String>,
+}
+
+impl Candidate {
+    /// Describe every disk Readymade can install to.
+    #[must_use]
+    pub fn list() -> Vec<Self> {
+        let by_id = crate::backend::util::fs::exist_then_read_dir("/dev/disk/by-id")
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| {
+                        let dev = entry.path().canonicalize().ok()?;
+                        Some((dev, entry.file_name().to_string_lossy().into_owned()))
+                    })
+                    .collect_vec()
+            })
+            .unwrap_or_default();
+
+        (super::detect_os().into_iter())
+            .map(|disk| {
+                let name = disk.devpath.file_name().unwrap_or_default();
+                let removable = Path::new("/sys/class/block").join(name).join("removable");
+                let removable = std::fs::read_to_string(removable).is_ok_and(|r| r.trim() == "1");
+                let ids = (by_id.iter())
+                    .filter(|(dev, _)| *dev == disk.devpath)
+                    .map(|(_, id)| id.clone())
+                    .collect();
+                Self {
+                    disk,
+                    removable,
+                    ids,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Filter {
+    fn matches(&self, candidate: &Candidate) -> bool {
+        match self {
+            Self::Id(pattern) => candidate.ids.iter().any(|id| glob_match(pattern, id)),
+            Self::MinSize(size) => candidate.disk.size >= *size,
+            Self::MaxSize(size) => candidate.disk.size <= *size,
+            Self::NonRemovable => !candidate.removable,
+            Self::NoOs => candidate.disk.os_name.is_none(),
+        }
+    }
+}
+
+/// Match `s` against a glob supporting `*` (any run of characters) and `?` (any one character).
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut p, mut i) = (0, 0);
+    // where to resume after the last `*`: (pattern index, string index)
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || Some(&c) == s.get(i) => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((sp, si)) => {
+                    p = sp + 1;
+                    i = si + 1;
+                    star = Some((sp, si + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern
+        .get(p..)
+        .is_some_and(|rest| rest.iter().all(|&c| c == b'*'))
+}
+
+impl DiskSelector {
+    /// The device path, if this selector is one.
+    #[must_use]
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Path(path) => Some(path),
+            Self::Match { .. } => None,
+        }
+    }
+
+    /// Find the disk this selector refers to on this machine.
+    ///
+    /// # Errors
+    /// No disk matches, or the selector cannot tell several disks apart.
+    pub fn resolve(&self) -> Result<PathBuf> {
+        if let Self::Path(path) = self {
+            return Ok(path.clone());
+        }
+        let candidates = Candidate::list();
+        let disk = self.select(&candidates)?;
+        tracing::info!(selector = %self, disk = ?disk.devpath, "Resolved disk selector");
+        Ok(disk.devpath.clone())
+    }
+
+    /// Choose among `candidates`, see [`DiskSelector::resolve`].
+    ///
+    /// # Errors
+    /// No disk matches, or the selector cannot tell several disks apart.
+    pub fn select<'a>(&self, candidates: &'a [Candidate]) -> Result<&'a Disk> {
+        let (filters, pick) = match self {
+            Self::Path(path) => {
+                return (candidates.iter())
+                    .map(|candidate| &candidate.disk)
+                    .find(|disk| disk.devpath == *path)
+                    .ok_or_else(|| eyre!("disk {} does not exist", path.display()));
+            }
+            Self::Match { filters, pick } => (filters, pick),
+        };
+        let mut matching = (candidates.iter())
+            .filter(|candidate| filters.iter().all(|filter| filter.matches(candidate)))
+            .map(|candidate| &candidate.disk)
+            .collect_vec();
+        if let Some(pick) = pick {
+            let best = match pick {
+                Pick::Largest => matching.iter().map(|disk| disk.size).max(),
+                Pick::Smallest => matching.iter().map(|disk| disk.size).min(),
+            };
+            matching.retain(|disk| Some(disk.size) == best);
+        }
+
+        match matching.as_slice() {
+            [disk] => Ok(disk),
+            [] => bail!("no disk matches the selector `{self}`"),
+            disks => bail!(
+                "the selector `{self}` is ambiguous, it matches {}",
+                disks.iter().map(|disk| disk.devpath.display()).join(", ")
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for DiskSelector {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.starts_with('/') {
+            return Ok(Self::Path(PathBuf::from(s)));
+        }
+        let mut filters = vec![];
+        let mut pick = None;
+        for term in s.split(',').map(str::trim) {
+            let (key, value) = term.split_once('=').unzip();
+            let parse_size = |value: Option<&str>| {
+                (value.unwrap_or_default().parse::<ByteSize>())
+                    .map_err(|e| eyre!("invalid size in `{term}`: {e}"))
+            };
+            let new_pick = match key.unwrap_or(term) {
+                "id" => {
+                    filters.push(Filter::Id(value.unwrap_or_default().to_owned()));
+                    None
+                }
+                "min-size" => {
+                    filters.push(Filter::MinSize(parse_size(value)?));
+                    None
+                }
+                "max-size" => {
+                    filters.push(Filter::MaxSize(parse_size(value)?));
+                    None
+                }
+                "non-removable" => {
+                    filters.push(Filter::NonRemovable);
+                    None
+                }
+                "no-os" => {
+                    filters.push(Filter::NoOs);
+                    None
+                }
+                "largest" => Some(Pick::Largest),
+                "smallest" => Some(Pick::Smallest),
+                _ => bail!("unknown disk selector term `{term}`"),
+            };
+            if new_pick.is_some() {
+                if pick.is_some() {
+                    bail!("disk selector `{s}` picks a disk more than once");
+                }
+                pick = new_pick;
+            }
+        }
+        Ok(Self::Match { filters, pick })
+    }
+}
+
+/// Format `size` in the largest binary unit that divides it, so it parses back to the same value.
+fn format_size(size: ByteSize) -> String {
+    let bytes = size.as_u64();
+    [
+        ("TiB", bytesize::TIB),
+        ("GiB", bytesize::GIB),
+        ("MiB", bytesize::MIB),
+        ("KiB", bytesize::KIB),
+    ]
+    .into_iter()
+    .find(|(_, unit)| bytes != 0 && bytes.is_multiple_of(*unit))
+    .map_or_else(
+        || bytes.to_string(),
+        |(name, unit)| format!("{}{name}", bytes / unit),
+    )
+}
+
+impl std::fmt::Display for DiskSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (filters, pick) = match self {
+            Self::Path(path) => return write!(f, "{}", path.display()),
+            Self::Match { filters, pick } => (filters, pick),
+        };
+        let terms = (pick.iter())
+            .map(|pick| match pick {
+                Pick::Largest => "largest".to_owned(),
+                Pick::Smallest => "smallest".to_owned(),
+            })
+            .chain(filters.iter().map(|filter| match filter {
+                Filter::Id(pattern) => format!("id={pattern}"),
+                Filter::MinSize(size) => format!("min-size={}", format_size(*size)),
+                Filter::MaxSize(size) => format!("max-size={}", format_size(*size)),
+                Filter::NonRemovable => "non-removable".to_owned(),
+                Filter::NoOs => "no-os".to_owned(),
+            }));
+        write!(f, "{}", terms.format(","))
+    }
+}
+
+impl From<PathBuf> for DiskSelector {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(dev: &str, gib: u64, os: Option<&str>, removable: bool, id: &str) -> Candidate {
+        Candidate {
+            disk: Disk {
+                disk_name: dev.to_owned(),
+                os_name: os.map(ToOwned::to_owned),
+                devpath: PathBuf::from(dev),
+                size: ByteSize::gib(gib),
+            },
+            removable,
+            ids: vec![id.to_owned()],
+        }
+    }
+
+    fn candidates() -> Vec<Candidate> {
+        vec![
+            candidate("/dev/sda", 32, None, true, "usb-SanDisk_Cruzer"),
+            candidate(
+                "/dev/nvme0n1",
+                512,
+                Some("Windows 11"),
+                false,
+                "nvme-Samsung_SSD_980",
+            ),
+            candidate("/dev/nvme1n1", 256, None, false, "nvme-WD_Black_SN770"),
+            candidate("/dev/sdb", 256, None, false, "ata-ST256"),
+        ]
+    }
+
+    fn select(selector: &str) -> Result<PathBuf> {
+        let candidates = candidates();
+        let selector: DiskSelector = selector.parse()?;
+        selector
+            .select(&candidates)
+            .map(|disk| disk.devpath.clone())
+    }
+
+    #[test]
+    fn select_disks() {
+        assert_eq!(
+            select("largest,non-removable").unwrap(),
+            Path::new("/dev/nvme0n1")
+        );
+        assert_eq!(
+            select("id=nvme-Samsung*").unwrap(),
+            Path::new("/dev/nvme0n1")
+        );
+        assert_eq!(
+            select("smallest,min-size=64GiB,id=nvme-*").unwrap(),
+            Path::new("/dev/nvme1n1")
+        );
+        assert_eq!(select("no-os,smallest").unwrap(), Path::new("/dev/sda"));
+        assert_eq!(select("/dev/sdb").unwrap(), Path::new("/dev/sdb"));
+    }
+
+    #[test]
+    fn select_fails_loudly() {
+        let err = select("no-os,non-removable").unwrap_err().to_string();
+        assert!(err.contains("ambiguous"), "{err}");
+        let err = |selector| select(selector).unwrap_err().to_string();
+        // two disks of the same size
+        assert!(err("smallest,min-size=64GiB,no-os").contains("ambiguous"));
+        assert_eq!(
+            err("min-size=1TiB"),
+            "no disk matches the selector `min-size=1TiB`"
+        );
+        assert_eq!(err("/dev/sdz"), "disk /dev/sdz does not exist");
+        assert_eq!(err("biggest"), "unknown disk selector term `biggest`");
+    }
+
+    #[test]
+    fn selector_roundtrip() {
+        for s in [
+            "/dev/vda",
+            "largest,non-removable",
+            "smallest,id=nvme-*,min-size=64GiB,no-os",
+        ] {
+            let selector: DiskSelector = s.parse().unwrap();
+            assert_eq!(selector.to_string(), s);
+            assert_eq!(selector, selector.to_string().parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("nvme-Samsung*", "nvme-Samsung_SSD_980"));
+        assert!(glob_match("*SSD?980", "nvme-Samsung_SSD_980"));
+        assert!(!glob_match("nvme-Samsung*", "ata-Samsung_SSD"));
+        assert!(glob_match("*", ""));
+    }
+}
@@ -0,0 +1,241 @@
+//! Installing to a disk image file, for building VM and appliance images.
+//!
+//! The image file is attached to a loop device with partition scanning, so the provisioners and
+//! postinstall modules see an ordinary disk. The loop device is detached with the rest of the
+//! [`Session`](crate::session::Session), after which the image is converted to the
+//! [`ImageFormat`] asked for.
+
+use bytesize::ByteSize;
+use uuid::Uuid;
+
+use crate::plan::{Action, Step};
+use crate::prelude::*;
+use crate::session::{self, Resource};
+
+/// Options for installing to a disk image file, see
+/// [`Playbook::image`](crate::playbook::Playbook::image).
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct ImageConfig {
+    /// Create the image file with this size, or grow it to it, e.g. `"16 GiB"`. Leave out to
+    /// install to the image file as it is.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub size: Option<ByteSize>,
+    /// Truncate an existing image file larger than [`Self::size`], throwing away whatever is
+    /// past the new end.
+    #[serde(default)]
+    pub shrink: bool,
+    /// Seed for the UUIDs systemd-repart makes up (`--seed`), so that the same playbook builds
+    /// the same partition table.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub seed: Option<Uuid>,
+    /// What to convert the finished image to. The raw image is kept either way.
+    #[serde(default)]
+    pub format: ImageFormat,
+}
+
+/// The format of a finished disk image.
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// Leave the raw image as it is.
+    #[default]
+    Raw,
+    /// Convert to QEMU's copy-on-write format with `qemu-img`, e.g. `disk.raw` to `disk.qcow2`.
+    Qcow2,
+    /// Compress with `xz`, e.g. `disk.raw` to `disk.raw.xz`.
+    RawXz,
+    /// Compress with `zstd`, e.g. `disk.raw` to `disk.raw.zst`.
+    RawZstd,
+}
+
+impl ImageFormat {
+    /// The file the raw image at `raw` is converted to, or [`None`] if it is left as it is.
+    #[must_use]
+    pub fn output(self, raw: &Path) -> Option<PathBuf> {
+        let append = |extension: &str| {
+            let mut path = raw.as_os_str().to_owned();
+            path.push(".");
+            path.push(extension);
+            PathBuf::from(path)
+        };
+        match self {
+            Self::Raw => None,
+            Self::Qcow2 => Some(raw.with_extension("qcow2")),
+            Self::RawXz => Some(append("xz")),
+            Self::RawZstd => Some(append("zst")),
+        }
+    }
+
+    fn command(self, raw: &Path) -> Option<Command> {
+        let output = self.output(raw)?;
+        let mut cmd;
+        match self {
+            Self::Raw => return None,
+            Self::Qcow2 => {
+                cmd = Command::new("qemu-img");
+                cmd.args(["convert", "-f", "raw", "-O", "qcow2"])
+                    .arg(raw)
+                    .arg(output);
+            }
+            // both write next to the input, with the extension appended
+            Self::RawXz => {
+                cmd = Command::new("xz");
+                cmd.args(["--keep", "--force", "--threads=0"]).arg(raw);
+            }
+            Self::RawZstd => {
+                cmd = Command::new("zstd");
+                cmd.args(["--keep", "--force", "--quiet", "-T0"]).arg(raw);
+            }
+        }
+        Some(cmd)
+    }
+}
+
+impl ImageConfig {
+    /// Create or grow the image file at `path` to [`Self::size`].
+    ///
+    /// # Errors
+    /// The file cannot be created or resized, or it is larger than [`Self::size`] and
+    /// [`Self::shrink`] is not set.
+    pub fn prepare(&self, path: &Path) -> Result<()> {
+        let Some(size) = self.size else {
+            return Ok(());
+        };
+        tracing::info!(?path, %size, "Preparing disk image");
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .wrap_err_with(|| format!("cannot open {}", path.display()))?;
+        let len = ByteSize::b(file.metadata()?.len());
+        if len > size && !self.shrink {
+            bail!(
+                "{} is {len}, larger than the {size} asked for; set `shrink` to truncate it",
+                path.display()
+            );
+        }
+        file.set_len(size.as_u64())
+            .wrap_err_with(|| format!("cannot make {} {size} large", path.display()))
+    }
+
+    /// Convert the finished raw image at `path` to [`Self::format`].
+    ///
+    /// # Errors
+    /// The converter cannot be run or fails.
+    pub fn convert(&self, path: &Path) -> Result<()> {
+        let Some(mut cmd) = self.format.command(path) else {
+            return Ok(());
+        };
+        tracing::info!(?path, format = ?self.format, "Converting disk image");
+        let status = cmd
+            .status()
+            .wrap_err_with(|| format!("cannot run `{}`", cmd.get_program().display()))?;
+        if !status.success() {
+            bail!("converting {} failed: {:?}", path.display(), status.code());
+        }
+        Ok(())
+    }
+
+    /// Plan installing to the image file at `path`, see [`crate::playbook::Playbook::plan`].
+    pub fn plan(&self, path: &Path, step: &mut Step) {
+        if let Some(size) = self.size {
+            step.push(Action::write_file(path, &format!("disk image of {size}")));
+        }
+        step.push(Action::from_command(&attach_command(path), false));
+        if let Some(seed) = self.seed {
+            step.push(Action::note(format!("seed systemd-repart with {seed}")));
+        }
+        if let Some(cmd) = self.format.command(path) {
+            step.push(Action::note("detach the loop device".to_owned()));
+            step.push(Action::from_command(&cmd, false));
+        }
+    }
+}
+
+fn attach_command(image: &Path) -> Command {
+    let mut cmd = Command::new("losetup");
+    cmd.args(["--find", "--show", "--partscan"]).arg(image);
+    cmd
+}
+
+/// Attach the image file at `image` to a free loop device, and return the loop device.
+///
+/// The loop device is registered with the [`session`], so it is detached once the installation is
+/// over.
+///
+/// # Errors
+/// `losetup` cannot be run or fails.
+pub fn attach(image: &Path) -> Result<PathBuf> {
+    let output = (attach_command(image).output()).wrap_err("cannot run `losetup`")?;
+    if !output.status.success() {
+        bail!(
+            "cannot attach {} to a loop device: {}",
+            image.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let device = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    tracing::info!(?image, ?device, "Attached disk image");
+    session::acquire(Resource::LoopDevice(device.clone()));
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs() {
+        let raw = Path::new("/srv/images/appliance.raw");
+        assert_eq!(ImageFormat::Raw.output(raw), None);
+        assert_eq!(
+            ImageFormat::Qcow2.output(raw).unwrap(),
+            Path::new("/srv/images/appliance.qcow2")
+        );
+        assert_eq!(
+            ImageFormat::RawZstd.output(raw).unwrap(),
+            Path::new("/srv/images/appliance.raw.zst")
+        );
+
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "size": "8 GiB",
+            "seed": "0f5d8e36-2b3a-4c5e-9d6f-7a8b9c0d1e2f",
+            "format": "raw_xz"
+        }))
+        .unwrap();
+        assert_eq!(config.size, Some(ByteSize::gib(8)));
+        assert_eq!(config.format, ImageFormat::RawXz);
+    }
+
+    #[test]
+    fn prepare_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.raw");
+        let config = ImageConfig {
+            size: Some(ByteSize::mib(4)),
+            ..ImageConfig::default()
+        };
+        config.prepare(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 1024 * 1024);
+
+        // an existing image is only ever grown, unless shrinking is asked for
+        let smaller = ImageConfig {
+            size: Some(ByteSize::mib(2)),
+            ..ImageConfig::default()
+        };
+        let err = smaller.prepare(&path).unwrap_err();
+        assert!(err.to_string().contains("set `shrink`"));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 1024 * 1024);
+        let shrink = ImageConfig {
+            shrink: true,
+            ..smaller
+        };
+        shrink.prepare(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 1024 * 1024);
+    }
+}
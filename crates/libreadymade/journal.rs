@@ -0,0 +1,285 @@
+//! Journal of completed installation steps, so a failed installation can be resumed.
+//!
+//! [`Playbook::play`] records every step it completes, along with the [`Mounts`] created by the disk
+//! provisioner. If a later step fails,
+//! [`Playbook::resume`] picks up from the failed step against the existing partitions instead of
+//! repartitioning the disk and copying everything again.
+//!
+//! The journal lives in `/run`, so it does not survive a reboot.
+
+use crate::backend::util::fs::partition_node;
+use crate::playbook::Playbook;
+use crate::prelude::*;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+pub const JOURNAL_DIR: &str = "/run/readymade";
+const JOURNAL_FILE: &str = "journal.json";
+
+/// Step key of the disk provisioner.
+pub const DISK_STEP: &str = "disk";
+/// Step key of the filesystem provisioner.
+pub const FILESYSTEM_STEP: &str = "filesystem";
+/// Step key of the filesystem provisioner cleanup.
+pub const CLEANUP_STEP: &str = "cleanup";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Journal {
+    /// Fingerprint of the playbook this journal belongs to, see [`fingerprint`].
+    pub playbook: u64,
+    /// Disk the destination disk selector of the playbook resolved to.
+    pub destination_disk: Option<PathBuf>,
+    /// Disks the selectors of [`Playbook::additional_disks`] resolved to.
+    #[serde(default)]
+    pub additional_disks: Vec<PathBuf>,
+    /// Mounts produced by the disk provisioner, once it has completed.
+    pub mounts: Option<Mounts>,
+    /// Loop device the disk image was attached to when [`Self::mounts`] were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_device: Option<PathBuf>,
+    /// The completed steps, in order.
+    pub completed: Vec<StepResult>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StepResult {
+    /// Step key, e.g. [`DISK_STEP`] or [`postinstall_step`].
+    pub step: String,
+    /// How long the step took to run, in milliseconds.
+    pub duration_ms: u64,
+    /// The step was not run, because a condition did not hold.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    /// The step failed, but was allowed to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+impl StepResult {
+    /// A `step` that began at `started` and just completed.
+    #[must_use]
+    pub fn new(step: &str, started: Instant) -> Self {
+        Self {
+            step: step.to_owned(),
+            duration_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+            ..Self::default()
+        }
+    }
+}
+
+/// Identify a playbook without storing it.
+///
+/// The encryption key is left out, so it can't be guessed from the journal.
+///
+/// # Panics
+/// Never, a [`Playbook`] always serializes.
+#[must_use]
+pub fn fingerprint(playbook: &Playbook) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    serde_json::to_string(&playbook.redacted())
+        .expect("playbook is always serializable")
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+/// How long each step took, one step per line.
+pub struct Timings<'a>(pub &'a [StepResult]);
+
+impl std::fmt::Display for Timings<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for result in self.0 {
+            #[allow(clippy::cast_precision_loss)]
+            let secs = result.duration_ms as f64 / 1000.0;
+            write!(f, "{secs:>9.1}s  {}", result.step)?;
+            if result.skipped {
+                f.write_str(" (skipped)")?;
+            }
+            if let Some(warning) = &result.warning {
+                write!(f, " (failed: {warning})")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Step key of the `i`th postinstall module.
+#[must_use]
+pub fn postinstall_step(i: usize, name: &str) -> String {
+    format!("postinstall/{i}/{name}")
+}
+
+impl Journal {
+    #[must_use]
+    pub fn new(playbook: &Playbook) -> Self {
+        Self {
+            playbook: fingerprint(playbook),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn path() -> PathBuf {
+        Path::new(JOURNAL_DIR).join(JOURNAL_FILE)
+    }
+
+    /// Load the journal left behind by a previous run of `playbook`.
+    ///
+    /// Returns [`None`] if there is no journal, or it belongs to a different playbook.
+    ///
+    /// # Errors
+    /// The journal exists but cannot be read.
+    pub fn load(playbook: &Playbook) -> Result<Option<Self>> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let journal: Self = serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .wrap_err_with(|| format!("cannot parse journal at {}", path.display()))?;
+        if journal.playbook != fingerprint(playbook) {
+            tracing::warn!(?path, "Journal belongs to a different playbook, ignoring");
+            return Ok(None);
+        }
+        Ok(Some(journal))
+    }
+
+    /// # Errors
+    /// The journal cannot be written.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        std::fs::create_dir_all(JOURNAL_DIR)?;
+        // write then rename, so a crash never leaves a truncated journal behind
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, &path).wrap_err("cannot write journal")
+    }
+
+    /// Remove the journal after a successful installation.
+    ///
+    /// # Errors
+    /// The journal exists but cannot be removed.
+    pub fn remove() -> Result<()> {
+        crate::backend::util::fs::exist_then(std::fs::remove_file(Self::path()))
+            .wrap_err("cannot remove journal")
+    }
+
+    #[must_use]
+    pub fn is_done(&self, step: &str) -> bool {
+        self.completed.iter().any(|s| s.step == step)
+    }
+
+    /// Record `step`, which began at `started`, as completed without saving, for use inside the chroot.
+    pub fn mark(&mut self, step: &str, started: Instant) {
+        self.record(StepResult::new(step, started));
+    }
+
+    /// Record a step as completed without saving, for use inside the chroot.
+    pub fn record(&mut self, result: StepResult) {
+        if !self.is_done(&result.step) {
+            self.completed.push(result);
+        }
+    }
+
+    /// Note that the disk image is attached to `device`, and point [`Self::mounts`] at its
+    /// partitions if they were recorded on another loop device.
+    pub fn attach(&mut self, device: &Path) {
+        if let (Some(old), Some(mounts)) = (&self.loop_device, &mut self.mounts)
+            && old != device
+        {
+            tracing::info!(?old, new = ?device, "Disk image is on another loop device now");
+            let prefix = format!("{}p", old.display());
+            for mount in &mut mounts.0 {
+                let partno = (mount.partition.to_str())
+                    .and_then(|node| node.strip_prefix(&prefix))
+                    .and_then(|partno| partno.parse().ok());
+                if let Some(partno) = partno {
+                    mount.partition = partition_node(device, partno);
+                }
+            }
+        }
+        self.loop_device = Some(device.to_owned());
+    }
+
+    /// Record `step`, which began at `started`, as completed.
+    ///
+    /// # Errors
+    /// The journal cannot be written.
+    pub fn complete(&mut self, step: &str, started: Instant) -> Result<()> {
+        self.mark(step, started);
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::{EncryptionConfig, test_playbook};
+    use crate::secret::SecretSource;
+
+    fn playbook() -> Playbook {
+        test_playbook(
+            "/dev/vda",
+            serde_json::json!({ "module": "Repart", "directory": "/tmp/repart", "copy_source": null }),
+            serde_json::json!({ "postinstall": [{ "module": "Dracut" }] }),
+        )
+    }
+
+    #[test]
+    fn fingerprint_ignores_key() {
+        let mut a = playbook();
+        let mut b = playbook();
+        a.encryption = Some(EncryptionConfig {
+            tpm: false,
+            encryption_key: SecretSource::Inline("hunter2".to_owned()).into(),
+        });
+        b.encryption = Some(EncryptionConfig {
+            tpm: false,
+            encryption_key: SecretSource::Inline("correct horse".to_owned()).into(),
+        });
+        assert_eq!(fingerprint(&a), fingerprint(&b));
+
+        b.destination_disk = PathBuf::from("/dev/vdb").into();
+        assert_ne!(fingerprint(&a), fingerprint(&b));
+    }
+
+    #[test]
+    fn follow_loop_device() {
+        let mount = |partition: &str, mountpoint: &str| Mount {
+            partition: partition.into(),
+            mountpoint: mountpoint.into(),
+            options: String::new(),
+            encryption_type: None,
+            label: None,
+            gpt_type: std::sync::OnceLock::default(),
+        };
+        let mut journal = Journal::new(&playbook());
+        journal.attach(Path::new("/dev/loop0"));
+        journal.mounts = Some(Mounts(vec![
+            mount("/dev/loop0p1", "/boot/efi"),
+            mount("/dev/loop0p3", "/"),
+            mount("/dev/mapper/vg-home", "/home"),
+        ]));
+        journal.attach(Path::new("/dev/loop12"));
+        let partitions = (journal.mounts.iter().flat_map(|mounts| &mounts.0))
+            .map(|mount| mount.partition.to_string_lossy().into_owned())
+            .collect_vec();
+        assert_eq!(
+            partitions,
+            ["/dev/loop12p1", "/dev/loop12p3", "/dev/mapper/vg-home"]
+        );
+        assert_eq!(
+            journal.loop_device.as_deref(),
+            Some(Path::new("/dev/loop12"))
+        );
+    }
+
+    #[test]
+    fn mark_once() {
+        let mut journal = Journal::new(&playbook());
+        journal.mark(DISK_STEP, Instant::now());
+        journal.mark(DISK_STEP, Instant::now());
+        assert!(journal.is_done(DISK_STEP));
+        assert!(!journal.is_done(FILESYSTEM_STEP));
+        assert_eq!(journal.completed.len(), 1);
+    }
+}
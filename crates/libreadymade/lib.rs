@@ -1,5 +1,15 @@
 pub mod backend;
 pub mod consts;
 pub mod disks;
+pub mod image;
+pub mod journal;
+pub mod plan;
 pub mod playbook;
+pub mod preflight;
+pub mod preview;
 pub mod prelude;
+pub mod progress;
+pub mod receipt;
+pub mod secret;
+pub mod session;
+pub mod unattended;
@@ -0,0 +1,223 @@
+//! Dry-run planning for [`Playbook`](crate::playbook::Playbook)s.
+//!
+//! A [`Plan`] is an ordered, serializable list of everything Readymade intends to do for a playbook:
+//! every disk write, mount, command invocation and file written in the target system.
+//! It is generated without touching any disk, so it can be shown to users before they confirm,
+//! or diffed between releases.
+
+use crate::prelude::*;
+
+/// A single side effect Readymade intends to perform.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Write to a block device, e.g. partitioning or formatting.
+    DiskWrite {
+        device: PathBuf,
+        description: String,
+    },
+    /// Mount a device onto a path.
+    Mount {
+        source: PathBuf,
+        target: PathBuf,
+        options: Option<String>,
+    },
+    /// Run a program.
+    ///
+    /// `in_target` is set when the program is run inside the chroot of the installed system.
+    Command {
+        program: String,
+        args: Vec<String>,
+        in_target: bool,
+    },
+    /// Create or overwrite a file inside the installed system.
+    WriteFile { path: PathBuf, description: String },
+    /// Remove a file or directory inside the installed system.
+    RemoveFile { path: PathBuf },
+    /// Something worth knowing about the step that is not an action itself.
+    Note { message: String },
+}
+
+impl Action {
+    /// Shorthand for an [`Action::Command`] run on the host.
+    #[must_use]
+    pub fn host_command<I, S>(program: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::Command {
+            program: program.to_owned(),
+            args: args.into_iter().map(Into::into).collect(),
+            in_target: false,
+        }
+    }
+
+    /// Shorthand for an [`Action::Command`] run inside the installed system.
+    #[must_use]
+    pub fn target_command<I, S>(program: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::Command {
+            program: program.to_owned(),
+            args: args.into_iter().map(Into::into).collect(),
+            in_target: true,
+        }
+    }
+
+    /// Shorthand for an [`Action::Command`] built from an existing [`Command`].
+    #[must_use]
+    pub fn from_command(cmd: &Command, in_target: bool) -> Self {
+        Self::Command {
+            program: cmd.get_program().to_string_lossy().to_string(),
+            args: (cmd.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+            in_target,
+        }
+    }
+
+    /// Shorthand for an [`Action::WriteFile`].
+    #[must_use]
+    pub fn write_file<P: Into<PathBuf>>(path: P, description: &str) -> Self {
+        Self::WriteFile {
+            path: path.into(),
+            description: description.to_owned(),
+        }
+    }
+
+    /// Shorthand for an [`Action::RemoveFile`].
+    #[must_use]
+    pub fn remove_file<P: Into<PathBuf>>(path: P) -> Self {
+        Self::RemoveFile { path: path.into() }
+    }
+
+    /// Shorthand for an [`Action::Note`].
+    #[must_use]
+    pub const fn note(message: String) -> Self {
+        Self::Note { message }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DiskWrite {
+                device,
+                description,
+            } => write!(f, "write {}: {description}", device.display()),
+            Self::Mount {
+                source,
+                target,
+                options,
+            } => {
+                write!(f, "mount {} -> {}", source.display(), target.display())?;
+                if let Some(options) = options {
+                    write!(f, " ({options})")?;
+                }
+                Ok(())
+            }
+            Self::Command {
+                program,
+                args,
+                in_target,
+            } => {
+                let prefix = if *in_target { "[target] $" } else { "$" };
+                write!(f, "{prefix} {program}")?;
+                args.iter().try_for_each(|arg| write!(f, " {arg}"))
+            }
+            Self::WriteFile { path, description } => {
+                write!(f, "write file {}: {description}", path.display())
+            }
+            Self::RemoveFile { path } => write!(f, "remove {}", path.display()),
+            Self::Note { message } => write!(f, "({message})"),
+        }
+    }
+}
+
+/// A group of actions performed by one part of the playbook (a provisioner or a postinstall module).
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Step {
+    pub name: String,
+    pub actions: Vec<Action>,
+}
+
+impl Step {
+    pub fn push(&mut self, action: Action) -> &mut Self {
+        self.actions.push(action);
+        self
+    }
+}
+
+/// The ordered list of [`Step`]s a playbook would perform.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    /// Begin a new step and return it so actions can be pushed into it.
+    pub fn step<S: Into<String>>(&mut self, name: S) -> &mut Step {
+        self.steps.push(Step {
+            name: name.into(),
+            actions: vec![],
+        });
+        let Some(step) = self.steps.last_mut() else {
+            unreachable!("a step was just pushed");
+        };
+        step
+    }
+
+    /// Iterate over all actions of all steps in order.
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.steps.iter().flat_map(|step| &step.actions)
+    }
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "[{}/{}] {}", i + 1, self.steps.len(), step.name)?;
+            if step.actions.is_empty() {
+                writeln!(f, "    (nothing to do)")?;
+            }
+            for action in &step.actions {
+                writeln!(f, "    {action}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_plan() {
+        let mut plan = Plan::default();
+        plan.step("Dracut")
+            .push(Action::target_command("dracut", ["--force"]));
+        plan.step("InitialSetup")
+            .push(Action::write_file("/.unconfigured", "first boot marker"));
+        plan.step("Empty");
+
+        assert_eq!(
+            plan.to_string(),
+            "[1/3] Dracut\n    [target] $ dracut --force\n\
+             [2/3] InitialSetup\n    write file /.unconfigured: first boot marker\n\
+             [3/3] Empty\n    (nothing to do)\n"
+        );
+    }
+
+    #[test]
+    fn serialize_action() {
+        let action = Action::remove_file("/etc/machine-id");
+        assert_eq!(
+            serde_json::to_string(&action).unwrap(),
+            r#"{"action":"remove_file","path":"/etc/machine-id"}"#
+        );
+    }
+}
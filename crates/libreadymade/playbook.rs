@@ -3,45 +3,113 @@
 //! but should be generated by an external program such as a GUI app or template system, rather than being manually written by users,
 //! since it is relatively low-level and specific to a particular install (ex. hardcoded disk paths).
 
-use crate::backend::postinstall::PostInstallModule;
+use crate::backend::postinstall::{Outcome, PostInstallModule, schedule};
+use crate::backend::provisioners::DiskProvisioner;
 use crate::backend::provisioners::disk::DiskProvisionerModule;
 use crate::backend::provisioners::filesystem::FileSystemProvisionerModule;
-use crate::backend::util::sys::check_uefi;
+use crate::backend::util::{fs::is_partition, sys::check_uefi};
+use crate::disks::selector::DiskSelector;
+use crate::image::ImageConfig;
+use crate::journal::{self, Journal, StepResult};
+use crate::plan::Plan;
+use crate::preflight::{Fit, source_size};
 use crate::prelude::*;
+use crate::preview::Preview;
+use crate::progress;
+use crate::receipt::Receipt;
+use crate::secret::CachedSecret;
+use crate::session::{self, Resource, Session};
 use serde::{Deserialize, Serialize};
+use std::os::unix::fs::FileTypeExt as _;
 use std::path::PathBuf;
+use std::time::Instant;
 use sys_mount::MountFlags;
 use tiffin::{Container, MountTarget};
 
 /// The encryption configuration for the installation, used by provisioners to determine how to set up encryption on the installation.
 ///
-/// If you use repart, you must specify your TPM & encrypt options (`Encrypt=key-file+tpm2`) in your repart templates.
-/// Readymade respects those encryption options over what's specified in the playbook.
-#[derive(Default, Serialize, Deserialize, Clone, educe::Educe)]
-#[educe(Debug)]
+/// With repart, `Encrypt=` is set on the partitions listed in [`Repart::encrypt`](crate::backend::provisioners::disk::repart::Repart::encrypt).
+/// Definitions that set `Encrypt=` themselves are respected over what's specified in the playbook.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, schemars::JsonSchema)]
 pub struct EncryptionConfig {
     /// Whether to use TPM for encryption.
     pub tpm: bool,
-    /// The encryption key to use for installation.
-    #[educe(Debug(ignore))]
-    pub encryption_key: String,
+    /// Where to read the encryption key from, see [`SecretSource`](crate::secret::SecretSource).
+    pub encryption_key: CachedSecret,
+}
+
+/// What to do with the machine once an unattended installation has succeeded.
+#[derive(
+    Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, schemars::JsonSchema,
+)]
+pub enum PowerAction {
+    /// Leave the machine running.
+    #[default]
+    None,
+    Reboot,
+    PowerOff,
+}
+
+impl PowerAction {
+    /// # Errors
+    /// `systemctl` cannot be run or fails.
+    pub fn perform(self) -> Result<()> {
+        let verb = match self {
+            Self::None => return Ok(()),
+            Self::Reboot => "reboot",
+            Self::PowerOff => "poweroff",
+        };
+        tracing::info!(verb, "Installation finished, changing power state");
+        let status =
+            (Command::new("systemctl").arg(verb).status()).wrap_err("cannot run systemctl")?;
+        if !status.success() {
+            bail!("`systemctl {verb}` failed: {:?}", status.code());
+        }
+        Ok(())
+    }
+}
+
+/// Another disk to set up alongside [`Playbook::destination_disk`], e.g. for `/home` or `/srv`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, schemars::JsonSchema)]
+pub struct AdditionalDisk {
+    /// The disk, as a path or a [`DiskSelector`].
+    #[schemars(with = "String")]
+    pub disk: DiskSelector,
+    /// How to partition this disk. Its mounts are merged with those of the destination disk.
+    pub disk_provisioner: DiskProvisioner,
 }
 
 /// The main playbook type, which describes the installation operation to be performed by Readymade.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
 pub struct Playbook {
     /// The disk to install to, this should be a whole disk (ex. /dev/sda) rather than a partition (ex. /dev/sda1).
-    pub destination_disk: PathBuf,
+    /// Instead of a path, this can be a selector such as `largest,non-removable`, see [`DiskSelector`].
+    #[schemars(with = "String")]
+    pub destination_disk: DiskSelector,
     /// The encryption configuration for the installation.
     pub encryption: Option<EncryptionConfig>,
     /// The disk provisioner to use for the installation, which describes how the installation disk should be partitioned and setup. Some disk provisioners support copying files to the installation disk, making a filesystem provisioner optional.
-    pub disk_provisioner: crate::backend::provisioners::DiskProvisioner,
+    pub disk_provisioner: DiskProvisioner,
+    /// Other disks to partition, whose mounts end up in the same system as those of the destination disk.
+    #[serde(default)]
+    pub additional_disks: Vec<AdditionalDisk>,
     /// The filesystem provisioner to use for the installation, which describes how the installation files should be copied to the partitions after they are set up by the disk provisioner.
     pub filesystem_provisioner: Option<crate::backend::provisioners::FileSystemProvisioner>,
     /// The post-installation modules to run after the provisioning step is complete, used to perform additional configuration on the installation such as installing a bootloader, configuring SELinux, or running custom scripts.
-    pub postinstall: Vec<crate::backend::postinstall::Module>,
+    /// Each module can have [`ModuleOptions`](crate::backend::postinstall::ModuleOptions) such as conditions and a timeout.
+    pub postinstall: Vec<crate::backend::postinstall::Entry>,
+    /// What to do once an unattended installation succeeds, see [`crate::unattended`].
+    #[serde(default)]
+    pub power_action: PowerAction,
+    /// Install to a disk image file given as [`Playbook::destination_disk`], see [`crate::image`].
+    /// A regular file as the destination disk is installed to with the default options.
+    #[serde(default)]
+    pub image: Option<ImageConfig>,
 }
 
+/// Exists on the host while postinstall modules run, so they can tell they are not in the chroot.
+const SETUP_LOCK_PATH: &str = "/var/run/readymade-setup.lock";
+
 // TODO: handle luks lol
 fn mounts_to_container(tempdir: &tempfile::TempDir, mounts: &Mounts) -> Result<Container> {
     let mut container = Container::new(tempdir.path().to_owned());
@@ -81,25 +149,584 @@ fn mounts_to_container(tempdir: &tempfile::TempDir, mounts: &Mounts) -> Result<C
 }
 
 impl Playbook {
-    pub fn play(&self) -> Result<()> {
-        let mounts = self.disk_provisioner.run(self)?;
+    /// Check the playbook for mistakes before any disk is touched.
+    ///
+    /// # Errors
+    /// The playbook is contradictory or cannot work on this host. All problems found are listed.
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems()?;
+        if problems.is_empty() {
+            return Ok(());
+        }
+        bail!("invalid playbook:\n- {}", problems.join("\n- "))
+    }
+
+    /// List everything wrong with the playbook, see [`Playbook::validate`].
+    ///
+    /// # Errors
+    /// The disk provisioner cannot read its own configuration.
+    pub fn problems(&self) -> Result<Vec<String>> {
+        if !self.is_resolved() {
+            return match self.resolve_disk() {
+                Ok(playbook) => playbook.problems(),
+                Err(err) => Ok(vec![err.to_string()]),
+            };
+        }
+
+        let mut problems = vec![];
+        let uefi = check_uefi();
+
+        let disks = self.written_disks()?;
+        for disk in disks.iter().filter(|disk| is_partition(disk)) {
+            problems.push(format!(
+                "destination disk {} is a partition, not a whole disk",
+                disk.display()
+            ));
+        }
+        for disk in disks.iter().duplicates() {
+            problems.push(format!("disk {} is provisioned twice", disk.display()));
+        }
+        problems.extend(self.image_problems()?);
+
+        let mounts = (self.plan_disks(&mut Plan::default()))
+            .wrap_err("cannot determine the partitions the disk provisioners would create")?;
+
+        // partitions created by the other provisioners don't exist yet, but their GPT types are already known.
+        // Manual ones have to exist for their types to be read.
+        let mut types_known = true;
+        let missing = (self.disk_provisioners())
+            .filter_map(|(_, provisioner)| match provisioner {
+                DiskProvisioner::Manual(manual) => Some(&manual.mounts.0),
+                DiskProvisioner::Repart(_)
+                | DiskProvisioner::Gpt(_)
+                | DiskProvisioner::DualBoot(_)
+                | DiskProvisioner::Lvm(_)
+                | DiskProvisioner::Raid(_) => None,
+            })
+            .flatten()
+            .filter(|mount| !mount.partition.exists())
+            .map(|mount| mount.partition.display().to_string())
+            .collect_vec();
+        if !missing.is_empty() {
+            problems.push(format!("partitions do not exist: {}", missing.join(", ")));
+            types_known = false;
+        }
+        for mountpoint in (mounts.0.iter())
+            .filter(|mount| !mount.is_swap())
+            .map(|mount| &mount.mountpoint)
+            .duplicates()
+        {
+            problems.push(format!(
+                "more than one partition is mounted at {}",
+                mountpoint.display()
+            ));
+        }
+
+        if !mounts
+            .0
+            .iter()
+            .any(|mount| mount.mountpoint == Path::new("/"))
+        {
+            problems.push("no partition is mounted at /".to_owned());
+        }
+
+        let encrypted = (mounts.0.iter()).filter_map(|mount| mount.encryption_type.as_ref());
+        let needs_key = (encrypted.clone()).any(|enc| {
+            matches!(
+                enc,
+                EncryptionOption::KeyFile | EncryptionOption::KeyFileTpm2
+            )
+        });
+        match (&self.encryption, encrypted.count()) {
+            // the arrays are never encrypted, see `Raid::arrays`
+            (Some(_), _) if self.uses_raid() => problems.push(
+                "encryption is set, but Raid cannot encrypt its arrays, so it would be ignored"
+                    .to_owned(),
+            ),
+            (Some(_), 0) => problems
+                .push("encryption is set, but no partition is set to be encrypted".to_owned()),
+            (None, _) if needs_key => problems.push(
+                "a partition is encrypted with a key file, but encryption is not set".to_owned(),
+            ),
+            _ => {}
+        }
+        if let Some(problem) = (self.encryption.as_ref())
+            .and_then(|encryption| encryption.encryption_key.source().problem())
+        {
+            problems.push(format!("encryption key: {problem}"));
+        }
+
+        if let Some(fit) = self.fit()?.filter(|fit| !fit.fits()) {
+            problems.push(format!(
+                "the installation does not fit on {}: it {fit}",
+                self.disk()?.display()
+            ));
+        }
+
+        if uefi && types_known && mounts.get_esp_partition().is_none() {
+            problems.push("this host boots with UEFI, but there is no ESP".to_owned());
+        }
+
+        let context = crate::backend::postinstall::Context {
+            destination_disk: self.disk()?.to_owned(),
+            mirrors: self.disk_provisioner.mirrors().to_vec(),
+            uefi,
+            image: self.image_config().is_some(),
+            mounts,
+        };
+        problems.extend(self.postinstall_problems(&context, types_known));
+
+        Ok(problems)
+    }
+
+    /// Problems with the order and requirements of postinstall modules, see [`Playbook::problems`].
+    fn postinstall_problems(
+        &self,
+        context: &crate::backend::postinstall::Context,
+        types_known: bool,
+    ) -> Vec<String> {
+        use crate::backend::postinstall::Module;
+
+        // modules whose conditions can't hold on this host won't run
+        let (uefi, mounts) = (context.uefi, &context.mounts);
+        let mut problems = vec![];
+        let position = |f: fn(&Module) -> bool| {
+            (self.postinstall.iter())
+                .position(|entry| entry.runs_on_host(context) != Some(false) && f(&entry.module))
+        };
+        if !uefi && position(|m| matches!(m, Module::EfiStub(_))).is_some() {
+            problems.push("EfiStub needs a UEFI host, but this host boots with BIOS".to_owned());
+        }
+        if uefi
+            && types_known
+            && position(|m| matches!(m, Module::GRUB2(_))).is_some()
+            && mounts.get_xbootldr_partition().is_none()
+        {
+            problems.push("GRUB2 on UEFI needs an xbootldr partition".to_owned());
+        }
+        if self.uses_raid() && position(|m| matches!(m, Module::Mdadm(_))).is_none() {
+            problems.push(
+                "Raid needs the Mdadm module, or the installed system cannot assemble its arrays"
+                    .to_owned(),
+            );
+        }
+        problems.extend(schedule::problems(&self.postinstall, context));
+        problems
+    }
+
+    /// Describe everything [`Playbook::play`] would do, without touching any disk.
+    ///
+    /// # Errors
+    /// Fails if a provisioner or module cannot make sense of its configuration.
+    pub fn plan(&self) -> Result<Plan> {
+        if !self.is_resolved() {
+            return self.resolve_disk()?.plan();
+        }
+        let mut plan = Plan::default();
+
+        if let Some(image) = self.image_to_attach()? {
+            image.plan(self.disk()?, plan.step("Image"));
+        }
+        let mounts = self.plan_disks(&mut plan)?;
+
         if let Some(filesystem_provisioner) = &self.filesystem_provisioner {
+            let step = plan.step(filesystem_provisioner.name());
+            filesystem_provisioner.plan(self, &mounts, step)?;
+        }
+
+        mounts.plan_mount_all(Path::new("<sysroot>"), plan.step("Setup"));
+
+        let context = crate::backend::postinstall::Context {
+            destination_disk: self.disk()?.to_owned(),
+            mirrors: self.disk_provisioner.mirrors().to_vec(),
+            uefi: check_uefi(),
+            image: self.image_config().is_some(),
+            mounts,
+        };
+        for entry in &self.postinstall {
+            entry.plan(&context, plan.step(entry.module.name()))?;
+        }
+        plan.step("Receipt").push(crate::plan::Action::write_file(
+            crate::consts::READYMADE_STATE_PATH,
+            "install receipt",
+        ));
+
+        Ok(plan)
+    }
+
+    /// The disk image options, if the installation goes to a disk image file.
+    ///
+    /// That is the case when [`Playbook::image`] is set, or [`Playbook::destination_disk`] is a
+    /// regular file.
+    #[must_use]
+    pub fn image_config(&self) -> Option<ImageConfig> {
+        let is_file = self.disk().is_ok_and(Path::is_file);
+        (self.image.clone()).or_else(|| is_file.then(ImageConfig::default))
+    }
+
+    /// The disk image options, if the destination disk is an image file that still has to be
+    /// attached to a loop device.
+    fn image_to_attach(&self) -> Result<Option<ImageConfig>> {
+        let disk = self.disk()?;
+        let is_device =
+            std::fs::metadata(disk).is_ok_and(|metadata| metadata.file_type().is_block_device());
+        Ok(self.image_config().filter(|_| !is_device))
+    }
+
+    /// Problems with installing to a disk image file, see [`Playbook::problems`].
+    fn image_problems(&self) -> Result<Vec<String>> {
+        let disk = self.disk()?;
+        let mut problems = vec![];
+        match self.image_to_attach()? {
+            Some(image) if image.size.is_none() && !disk.exists() => problems.push(format!(
+                "disk image {} does not exist, and no size is given to create it",
+                disk.display()
+            )),
+            // a loop device is fine, since it may be one the image was attached to
+            None if self.image.is_some() && !disk.to_string_lossy().starts_with("/dev/loop") => {
+                problems.push(format!(
+                    "image options are set, but {} is not an image file",
+                    disk.display()
+                ));
+            }
+            _ => {}
+        }
+        if !self.additional_disks.is_empty() && self.image_config().is_some() {
+            problems.push("additional disks cannot be used with a disk image".to_owned());
+        }
+        Ok(problems)
+    }
+
+    /// The path of the destination disk.
+    ///
+    /// # Errors
+    /// [`Playbook::destination_disk`] is a selector that has not been resolved with [`Playbook::resolve_disk`].
+    pub fn disk(&self) -> Result<&Path> {
+        (self.destination_disk.path()).ok_or_else(|| {
+            eyre!(
+                "disk selector `{}` has not been resolved",
+                self.destination_disk
+            )
+        })
+    }
+
+    /// The paths of the destination disk and the [`Playbook::additional_disks`], in that order.
+    ///
+    /// # Errors
+    /// See [`Playbook::disk`].
+    pub fn disks(&self) -> Result<Vec<&Path>> {
+        (self.disk_provisioners())
+            .map(|(selector, _)| {
+                (selector.path())
+                    .ok_or_else(|| eyre!("disk selector `{selector}` has not been resolved"))
+            })
+            .collect()
+    }
+
+    /// Every disk the playbook writes to: [`Playbook::disks`], then the mirrors of their provisioners.
+    ///
+    /// # Errors
+    /// See [`Playbook::disk`].
+    pub fn written_disks(&self) -> Result<Vec<&Path>> {
+        let mirrors = (self.disk_provisioners())
+            .flat_map(|(_, provisioner)| provisioner.mirrors())
+            .map(PathBuf::as_path);
+        Ok(self.disks()?.into_iter().chain(mirrors).collect())
+    }
+
+    /// Whether every disk selector of the playbook is a path.
+    #[must_use]
+    pub fn is_resolved(&self) -> bool {
+        (self.disk_provisioners()).all(|(selector, _)| selector.path().is_some())
+    }
+
+    /// A copy of the playbook with [`Playbook::destination_disk`] and the [`Playbook::additional_disks`]
+    /// resolved to the paths of disks on this machine.
+    ///
+    /// # Errors
+    /// See [`DiskSelector::resolve`].
+    pub fn resolve_disk(&self) -> Result<Self> {
+        let additional: Vec<_> = (self.additional_disks.iter())
+            .map(|additional| additional.disk.resolve())
+            .try_collect()?;
+        Ok(self.with_disks(self.destination_disk.resolve()?, &additional))
+    }
+
+    fn with_disks(&self, disk: PathBuf, additional: &[PathBuf]) -> Self {
+        let mut playbook = Self {
+            destination_disk: DiskSelector::Path(disk),
+            ..self.clone()
+        };
+        for (additional, disk) in playbook.additional_disks.iter_mut().zip(additional) {
+            additional.disk = DiskSelector::Path(disk.clone());
+        }
+        playbook
+    }
+
+    /// Each disk along with its provisioner, starting with the destination disk.
+    fn disk_provisioners(&self) -> impl Iterator<Item = (&DiskSelector, &DiskProvisioner)> {
+        std::iter::once((&self.destination_disk, &self.disk_provisioner)).chain(
+            (self.additional_disks.iter())
+                .map(|additional| (&additional.disk, &additional.disk_provisioner)),
+        )
+    }
+
+    /// Whether any disk is provisioned with [`DiskProvisioner::Raid`].
+    fn uses_raid(&self) -> bool {
+        (self.disk_provisioners())
+            .any(|(_, provisioner)| matches!(provisioner, DiskProvisioner::Raid(_)))
+    }
+
+    /// The playbook as seen by the disk provisioner of `disk`.
+    fn for_disk(&self, disk: &Path) -> Self {
+        Self {
+            destination_disk: DiskSelector::Path(disk.to_owned()),
+            additional_disks: vec![],
+            ..self.clone()
+        }
+    }
+
+    /// Run the disk provisioner of every disk, and merge the mounts they produce.
+    fn provision_disks(&self) -> Result<Mounts> {
+        // read the key before the playbook is copied for each disk, it may only be readable once
+        self.encryption_key()?;
+        let mut mounts = self.disk_provisioner.run(self)?;
+        for (selector, provisioner) in self.disk_provisioners().skip(1) {
+            let disk = (selector.path()).ok_or_eyre("additional disk is not resolved")?;
+            tracing::info!(?disk, "Provisioning additional disk");
+            mounts.0.extend(provisioner.run(&self.for_disk(disk))?.0);
+        }
+        Ok(mounts)
+    }
+
+    /// Set up again what the disk provisioners of a previous run built on the disks, see
+    /// [`DiskProvisionerModule::reactivate`].
+    fn reactivate_disks(&self, mounts: &mut Mounts) -> Result<()> {
+        // read the key before the playbook is copied for each disk, it may only be readable once
+        self.encryption_key()?;
+        for (disk, (_, provisioner)) in self.disks()?.into_iter().zip(self.disk_provisioners()) {
+            provisioner.reactivate(&self.for_disk(disk), mounts)?;
+        }
+        Ok(())
+    }
+
+    /// Plan the disk provisioner of every disk, see [`Playbook::provision_disks`].
+    fn plan_disks(&self, plan: &mut Plan) -> Result<Mounts> {
+        let mut mounts = Mounts(vec![]);
+        for (disk, (_, provisioner)) in self.disks()?.into_iter().zip(self.disk_provisioners()) {
+            let step = if self.additional_disks.is_empty() {
+                plan.step(provisioner.name())
+            } else {
+                plan.step(format!("{} ({})", provisioner.name(), disk.display()))
+            };
+            mounts
+                .0
+                .extend(provisioner.plan(&self.for_disk(disk), step)?.0);
+        }
+        Ok(mounts)
+    }
+
+    /// A copy of the playbook that is safe to store: an inline encryption key is replaced.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let mut playbook = self.clone();
+        if let Some(encryption) = &mut playbook.encryption {
+            encryption.encryption_key = encryption.encryption_key.source().redacted().into();
+        }
+        playbook
+    }
+
+    /// The encryption key, read from its source the first time it is needed.
+    ///
+    /// # Errors
+    /// See [`crate::secret::SecretSource::read`].
+    pub fn encryption_key(&self) -> Result<Option<&str>> {
+        (self.encryption.as_ref())
+            .map(|encryption| encryption.encryption_key.get())
+            .transpose()
+    }
+
+    /// Where the installed files come from: a directory, image file or OCI image reference.
+    #[must_use]
+    pub fn copy_source(&self) -> Option<String> {
+        use crate::backend::provisioners::FileSystemProvisioner;
+        match (&self.filesystem_provisioner, &self.disk_provisioner) {
+            (Some(FileSystemProvisioner::Copy(copy)), _) => Some(copy.copy_source.clone()),
+            (Some(FileSystemProvisioner::Bootc(bootc)), _) => Some(bootc.imgref.clone()),
+            (None, DiskProvisioner::Repart(repart)) => {
+                (repart.copy_source.as_ref()).map(|source| source.display().to_string())
+            }
+            (
+                None,
+                DiskProvisioner::Manual(_)
+                | DiskProvisioner::Gpt(_)
+                | DiskProvisioner::DualBoot(_)
+                | DiskProvisioner::Lvm(_)
+                | DiskProvisioner::Raid(_),
+            ) => None,
+        }
+    }
+
+    /// Whether the installation fits on the destination disk, see [`Fit`].
+    ///
+    /// Only known for repart wiping the destination disk, and only if the size of the disk can be
+    /// read.
+    ///
+    /// # Errors
+    /// The repart definitions cannot be read.
+    pub fn fit(&self) -> Result<Option<Fit>> {
+        use crate::backend::provisioners::disk::repart::Empty;
+        let DiskProvisioner::Repart(repart) = &self.disk_provisioner else {
+            return Ok(None);
+        };
+        if repart.empty != Empty::Force {
+            // checked against the free space when planning, see `Repart::partition_numbers`
+            return Ok(None);
+        }
+        let size = (self.image_to_attach()?).and_then(|image| image.size);
+        let Some(disk) = size.or_else(|| crate::disks::size_of(self.disk().ok()?)) else {
+            return Ok(None);
+        };
+        let configs = repart.sorted_configs(self.encryption.as_ref())?;
+        let source = self.copy_source().as_deref().and_then(source_size);
+        Ok(Some(Fit::new(
+            configs.iter().map(|(_, config)| &config.partition),
+            source,
+            disk,
+        )))
+    }
+
+    /// The partition table the installation leaves on the destination disk, see [`Preview`].
+    ///
+    /// Only known for repart, which can lay out the partitions without writing them.
+    ///
+    /// # Errors
+    /// systemd-repart cannot lay out the partitions.
+    pub fn preview(&self) -> Result<Option<Preview>> {
+        let DiskProvisioner::Repart(repart) = &self.disk_provisioner else {
+            return Ok(None);
+        };
+        (repart.preview(
+            self.disk()?,
+            self.encryption.as_ref(),
+            self.encryption_key()?,
+        ))
+        .map(Some)
+    }
+
+    /// Run the playbook from the start.
+    ///
+    /// Completed steps are recorded in a [`Journal`], so that a failed installation can be picked up
+    /// again with [`Playbook::resume`]. Returns every step along with how long it took.
+    ///
+    /// # Errors
+    /// The playbook is invalid, or any step fails.
+    pub fn play(&self) -> Result<Vec<StepResult>> {
+        self.run(Journal::new(self))
+    }
+
+    /// Continue a previously failed run of the same playbook, skipping the steps that completed.
+    ///
+    /// The partitions and [`Mounts`] of the previous run are reused, volume groups and RAID arrays
+    /// are activated again, and encrypted partitions are unlocked again. A disk image is attached
+    /// again, and the mounts are moved over if it gets another loop device than before.
+    ///
+    /// # Errors
+    /// There is no journal for this playbook, or any remaining step fails.
+    pub fn resume(&self) -> Result<Vec<StepResult>> {
+        let journal = Journal::load(self)?
+            .ok_or_eyre("nothing to resume, no journal was found for this playbook")?;
+        tracing::info!(completed = ?journal.completed, "Resuming installation");
+        self.run(journal)
+    }
+
+    fn run(&self, mut journal: Journal) -> Result<Vec<StepResult>> {
+        if !self.is_resolved() {
+            // resolve the selectors once, and stick to the same disks when resuming
+            let playbook = match journal.destination_disk.take() {
+                Some(disk) => self.with_disks(disk, &journal.additional_disks),
+                None => self.resolve_disk()?,
+            };
+            let disks = playbook.disks()?;
+            journal.destination_disk = disks.first().map(|disk| disk.to_path_buf());
+            journal.additional_disks = disks
+                .iter()
+                .skip(1)
+                .map(|disk| disk.to_path_buf())
+                .collect();
+            return playbook.run(journal);
+        }
+        self.validate()?;
+        journal.save()?;
+        let Some(image) = self.image_to_attach()? else {
+            // unmount, close and remove everything left behind if any step fails
+            let _session = Session::begin();
+            return self.install(journal);
+        };
+        let completed = {
+            // the loop device is torn down last, once nothing on it is in use
+            let _session = Session::begin();
+            image.prepare(self.disk()?)?;
+            let device = crate::image::attach(self.disk()?)?;
+            journal.attach(&device);
+            let playbook = Self {
+                destination_disk: DiskSelector::Path(device),
+                image: Some(image.clone()),
+                ..self.clone()
+            };
+            playbook.install(journal)?
+        };
+        image.convert(self.disk()?)?;
+        Ok(completed)
+    }
+
+    /// Run every step that is not done yet, see [`Playbook::run`].
+    fn install(&self, mut journal: Journal) -> Result<Vec<StepResult>> {
+        let total_steps = self.total_steps();
+
+        let mounts = if let Some(mut mounts) = journal.mounts.clone() {
+            tracing::info!("Disk provisioner already completed, reusing its mounts");
+            self.reactivate_disks(&mut mounts)?;
+            mounts
+        } else {
+            let name = self.disk_provisioner.name();
+            progress::begin_step(1, total_steps, name, "Partitioning disk");
+            let started = Instant::now();
+            let mounts = self.provision_disks()?;
+            journal.mounts = Some(mounts.clone());
+            journal.complete(journal::DISK_STEP, started)?;
+            mounts
+        };
+        if let Some(filesystem_provisioner) = &self.filesystem_provisioner
+            && !journal.is_done(journal::FILESYSTEM_STEP)
+        {
+            let name = filesystem_provisioner.name();
+            progress::begin_step(2, total_steps, name, "Copying files");
+            let started = Instant::now();
             filesystem_provisioner.run(self, &mounts)?;
+            journal.complete(journal::FILESYSTEM_STEP, started)?;
         }
 
-        self.setup_system(&mounts)?;
+        self.setup_system(&mounts, &mut journal)?;
 
-        if let Some(filesystem_provisioner) = &self.filesystem_provisioner {
+        if let Some(filesystem_provisioner) = &self.filesystem_provisioner
+            && !journal.is_done(journal::CLEANUP_STEP)
+        {
+            let started = Instant::now();
             filesystem_provisioner.cleanup(self, &mounts)?;
+            journal.complete(journal::CLEANUP_STEP, started)?;
         }
-        Ok(())
+
+        Journal::remove()?;
+        Ok(journal.completed)
     }
 
-    #[tracing::instrument]
-    fn setup_system(&self, mounts: &Mounts) -> Result<()> {
+    #[tracing::instrument(skip(journal))]
+    fn setup_system(&self, mounts: &Mounts, journal: &mut Journal) -> Result<()> {
         // Let's create a lockfile to prevent running _inner_sys_setup outside the chroot jail
-        let lockfile_path = "/var/run/readymade-setup.lock";
-        std::fs::write(lockfile_path, b"")?;
+        std::fs::write(SETUP_LOCK_PATH, b"")?;
+        let lockfile = Resource::File(PathBuf::from(SETUP_LOCK_PATH));
+        session::acquire(lockfile.clone());
 
         let tempdir = tempfile::tempdir()?;
 
@@ -110,25 +737,27 @@ impl Playbook {
         let mut container = mounts_to_container(&tempdir, mounts)?;
         // let fstab = mounts.generate_fstab()?;
         // tiffin will run `nix::unistd::chdir("/")` when entering the container, so we can use `sysroot as above`
-        container.run(|| self.inner_sys_setup(mounts))??;
+        let res = container.run(|| self.inner_sys_setup(mounts, journal));
+        // the journal can only be written from outside the chroot
+        journal.save()?;
+        res??;
 
         // Let's remove the lockfile now that we're done
-        std::fs::remove_file(lockfile_path)
+        session::release(&lockfile)
             .wrap_err("Failed to remove setup lock file after installation")?;
 
         Ok(())
     }
 
     #[allow(clippy::unwrap_in_result)]
-    #[tracing::instrument]
-    pub fn inner_sys_setup(&self, mounts: &Mounts) -> Result<()> {
+    #[tracing::instrument(skip(journal))]
+    pub fn inner_sys_setup(&self, mounts: &Mounts, journal: &mut Journal) -> Result<()> {
         // ===SAFETY CHECK===
         // Let's make sure we're NOT running OUTSIDE the chroot jail
         // Many fstabs have been lost before due to this.
-        let lockfile_path = "/var/run/readymade-setup.lock";
-        if Path::new(lockfile_path).exists() {
+        if Path::new(SETUP_LOCK_PATH).exists() {
             bail!(
-                "Safety check failed: Setup lock file inside chroot exists at {lockfile_path}. This is likely a bug in the installer, please report it."
+                "Safety check failed: Setup lock file inside chroot exists at {SETUP_LOCK_PATH}. This is likely a bug in the installer, please report it."
             );
         }
 
@@ -137,8 +766,10 @@ impl Playbook {
 
         // We will run the specified postinstall modules now
         let context = crate::backend::postinstall::Context {
-            destination_disk: self.destination_disk.clone(),
+            destination_disk: self.disk()?.to_owned(),
+            mirrors: self.disk_provisioner.mirrors().to_vec(),
             uefi: check_uefi(),
+            image: self.image_config().is_some(),
             // uefi: if self.installation_type.is_chromebook_install() {
             //     true
             // } else {
@@ -155,29 +786,229 @@ impl Playbook {
         //     std::fs::write("/etc/fstab", fstab).wrap_err("cannot write to /etc/fstab")?;
         // }
 
-        // // Write the state dump to the chroot
-        // let state_dump_path = Path::new(crate::consts::READYMADE_STATE_PATH);
-        // let parent =
-        //     (state_dump_path.parent()).context("Invalid state dump path - no parent directory")?;
-        // std::fs::create_dir_all(parent)
-        //     .wrap_err("Failed to create parent directories for state dump")?;
-        // std::fs::write(
-        //     state_dump_path,
-        //     state_dump
-        //         .export_string()
-        //         .wrap_err("Failed to serialize state dump")?,
-        // )
-        // .wrap_err("Failed to write state dump file")?;
-
         // if let Some(data) = crypt_data.filter(|_| state_dump.state.copy_mode.is_repart()) {
         //     tracing::info!("Writing /etc/crypttab...");
         //     std::fs::write("/etc/crypttab", data.crypttab)
         //         .wrap_err("cannot write to /etc/crypttab")?;
         // }
 
-        (self.postinstall.iter())
-            .inspect(|module| tracing::debug!(?module, "Running module"))
-            .map(|module| module.run(&context))
-            .try_collect()
+        let total_steps = self.total_steps();
+        let first_step = total_steps - self.postinstall.len() + 1;
+        let steps = (self.postinstall.iter().enumerate())
+            .map(|(i, entry)| journal::postinstall_step(i, entry.module.name()))
+            .collect_vec();
+        let done = steps.iter().map(|step| journal.is_done(step)).collect_vec();
+        for entry in (self.postinstall.iter().zip(&done)).filter(|(_, done)| **done) {
+            tracing::info!(?entry, "Module already completed, skipping");
+        }
+        let res = schedule::run(
+            &self.postinstall,
+            &context,
+            &done,
+            |i| {
+                let Some(entry) = self.postinstall.get(i) else {
+                    return;
+                };
+                let name = entry.module.name();
+                tracing::debug!(?entry, "Running module");
+                progress::begin_step(
+                    first_step + i,
+                    total_steps,
+                    name,
+                    &format!("Running {name}"),
+                );
+            },
+            |i, outcome, started| {
+                let Some(step) = steps.get(i) else {
+                    return;
+                };
+                let mut result = journal::StepResult::new(step, started);
+                match outcome {
+                    Outcome::Completed => {}
+                    Outcome::Skipped(_) => result.skipped = true,
+                    Outcome::Failed(warning) => result.warning = Some(warning),
+                }
+                journal.record(result);
+            },
+        );
+        tracing::info!(
+            "Postinstall timings:\n{}",
+            journal::Timings(&journal.completed)
+        );
+        res?;
+
+        Receipt::new(self, mounts, journal.completed.clone())?.write()
+    }
+
+    /// The number of steps reported through [`progress`]: one per provisioner and postinstall module.
+    fn total_steps(&self) -> usize {
+        1 + usize::from(self.filesystem_provisioner.is_some()) + self.postinstall.len()
+    }
+}
+
+/// A playbook for tests that provisions `disk` with `disk_provisioner`, with `fields` such as
+/// `encryption` or `postinstall` set over the defaults.
+#[cfg(test)]
+pub(crate) fn test_playbook(
+    disk: &str,
+    disk_provisioner: serde_json::Value,
+    fields: serde_json::Value,
+) -> Playbook {
+    let mut playbook = serde_json::json!({
+        "destination_disk": disk,
+        "encryption": null,
+        "filesystem_provisioner": null,
+        "postinstall": []
+    });
+    if let Some(playbook) = playbook.as_object_mut() {
+        playbook.insert("disk_provisioner".to_owned(), disk_provisioner);
+        if let serde_json::Value::Object(fields) = fields {
+            playbook.extend(fields);
+        }
+    }
+    serde_json::from_value(playbook).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problems_in_manual_playbook() {
+        let playbook = test_playbook(
+            "/dev/readymade-test",
+            serde_json::json!({
+                "module": "Manual",
+                "mounts": [{
+                    "partition": "/dev/readymade-test2",
+                    "mountpoint": "/boot",
+                    "options": "",
+                    "encryption_type": null,
+                    "label": null
+                }]
+            }),
+            serde_json::json!({ "postinstall": [{ "module": "Dracut" }, { "module": "Fstab" }] }),
+        );
+
+        assert_eq!(
+            playbook.problems().unwrap(),
+            [
+                "partitions do not exist: /dev/readymade-test2",
+                "no partition is mounted at /",
+                "Fstab must run before Dracut",
+            ]
+        );
+        assert!(playbook.validate().is_err());
+    }
+
+    #[test]
+    fn problems_across_disks() {
+        let manual = |partition: &str, mountpoint: &str| {
+            serde_json::json!({
+                "module": "Manual",
+                "mounts": [{
+                    "partition": partition,
+                    "mountpoint": mountpoint,
+                    "options": "",
+                    "encryption_type": null,
+                    "label": null
+                }]
+            })
+        };
+        let playbook = test_playbook(
+            "/dev/readymade-test",
+            manual("/dev/readymade-test1", "/"),
+            serde_json::json!({
+                "additional_disks": [
+                    { "disk": "/dev/readymade-test", "disk_provisioner": manual("/dev/readymade-test2", "/") }
+                ],
+            }),
+        );
+
+        let mut plan = Plan::default();
+        assert_eq!(playbook.plan_disks(&mut plan).unwrap().0.len(), 2);
+        assert_eq!(plan.steps.len(), 2);
+        assert_eq!(
+            playbook.problems().unwrap(),
+            [
+                "disk /dev/readymade-test is provisioned twice",
+                "partitions do not exist: /dev/readymade-test1, /dev/readymade-test2",
+                "more than one partition is mounted at /",
+            ]
+        );
+    }
+
+    #[test]
+    fn problems_with_raid() {
+        let playbook = |fields: serde_json::Value| {
+            test_playbook(
+                "/dev/readymade-test",
+                serde_json::json!({ "module": "Raid", "level": "raid1", "members": ["/dev/readymade-test-b"] }),
+                fields,
+            )
+        };
+
+        let encrypted = playbook(
+            serde_json::json!({ "encryption": { "tpm": false, "encryption_key": "hunter2" } }),
+        );
+        assert_eq!(
+            encrypted.problems().unwrap(),
+            [
+                "encryption is set, but Raid cannot encrypt its arrays, so it would be ignored",
+                "Raid needs the Mdadm module, or the installed system cannot assemble its arrays",
+            ]
+        );
+        let configured = playbook(serde_json::json!({ "postinstall": [{ "module": "Mdadm" }] }));
+        assert_eq!(configured.problems().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn image_playbook() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.raw");
+        let playbook = |options: serde_json::Value| {
+            test_playbook(
+                image.to_str().unwrap(),
+                serde_json::json!({
+                    "module": "Manual",
+                    "mounts": [{
+                        "partition": "/dev/readymade-test1",
+                        "mountpoint": "/",
+                        "options": "",
+                        "encryption_type": null,
+                        "label": null
+                    }]
+                }),
+                serde_json::json!({
+                    "postinstall": [{ "module": "EfiStub", "distro_name": "Ultramarine Linux" }],
+                    "image": options
+                }),
+            )
+        };
+
+        assert_eq!(playbook(serde_json::Value::Null).image_config(), None);
+        // EfiStub is skipped for images, so it doesn't matter how this host boots
+        let sized = playbook(serde_json::json!({ "size": "1 GiB" }));
+        assert!(sized.image_to_attach().unwrap().is_some());
+        assert_eq!(
+            sized.problems().unwrap(),
+            ["partitions do not exist: /dev/readymade-test1"]
+        );
+        assert_eq!(
+            playbook(serde_json::json!({})).problems().unwrap(),
+            [
+                format!(
+                    "disk image {} does not exist, and no size is given to create it",
+                    image.display()
+                ),
+                "partitions do not exist: /dev/readymade-test1".to_owned(),
+            ]
+        );
+
+        std::fs::write(&image, []).unwrap();
+        assert_eq!(
+            playbook(serde_json::Value::Null).image_config(),
+            Some(ImageConfig::default())
+        );
     }
 }
@@ -0,0 +1,269 @@
+//! Pre-flight check of whether an installation fits on its destination disk.
+//!
+//! systemd-repart only finds out the disk is too small once it runs, halfway through the
+//! installation. [`Fit`] works it out beforehand from the sizes in the repart definitions, the size
+//! of the files that are copied, and the size of the disk.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Read as _,
+    os::unix::fs::MetadataExt as _,
+};
+
+use bytesize::ByteSize;
+use parking_lot::Mutex;
+use repart::Partition;
+
+use crate::{backend::provisioners::disk::repart::SystemdRepartData, prelude::*};
+
+/// Whether an installation fits on its destination disk, and by how much.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fit {
+    /// The smallest sizes and paddings of all new partitions added up.
+    pub partitions: ByteSize,
+    /// Size of the files copied to the disk, if known.
+    pub source: Option<ByteSize>,
+    /// What the disk has to hold at least.
+    pub needed: ByteSize,
+    pub disk: ByteSize,
+}
+
+impl Fit {
+    /// Work out whether `partitions`, filled with `source`, fit on `disk`.
+    ///
+    /// Every partition takes at least its `SizeMinBytes=` and `PaddingMinBytes=`. The copied files
+    /// go to the partitions with `CopyFiles=`, so those have to hold at least `source` on top of
+    /// the partitions that don't copy anything. This leaves out filesystem overhead, so an
+    /// installation that fits only barely may still fail.
+    pub fn new<'a, I>(partitions: I, source: Option<ByteSize>, disk: ByteSize) -> Self
+    where
+        I: IntoIterator<Item = &'a Partition>,
+    {
+        let (mut copying, mut rest) = (0, 0);
+        for partition in partitions {
+            let size = partition.size_min_bytes.as_u64() + partition.padding_min_bytes.as_u64();
+            if partition.copy_files.is_empty() {
+                rest += size;
+            } else {
+                copying += size;
+            }
+        }
+        let minimum = copying + rest;
+        let with_source = source.map_or(0, |source| source.as_u64() + rest);
+        Self {
+            partitions: ByteSize::b(minimum),
+            source,
+            needed: ByteSize::b(minimum.max(with_source)),
+            disk,
+        }
+    }
+
+    /// Work out whether the definitions in `directory`, filled with `source` (see
+    /// [`source_size`]), fit on `disk`.
+    ///
+    /// # Errors
+    /// The definitions cannot be read.
+    pub fn for_repart(directory: &Path, source: Option<ByteSize>, disk: ByteSize) -> Result<Self> {
+        let configs = SystemdRepartData::get_configs(directory)?.configs;
+        Ok(Self::new(
+            configs.values().map(|config| &config.partition),
+            source,
+            disk,
+        ))
+    }
+
+    #[must_use]
+    pub fn fits(&self) -> bool {
+        self.needed <= self.disk
+    }
+
+    /// The space left over on the disk, or missing from it if the installation doesn't fit.
+    #[must_use]
+    pub const fn margin(&self) -> ByteSize {
+        ByteSize::b(self.disk.as_u64().abs_diff(self.needed.as_u64()))
+    }
+}
+
+impl std::fmt::Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.fits() {
+            write!(
+                f,
+                "needs {} of {}, leaving {} to spare",
+                self.needed,
+                self.disk,
+                self.margin()
+            )
+        } else {
+            write!(
+                f,
+                "needs {}, but the disk is only {}, {} short",
+                self.needed,
+                self.disk,
+                self.margin()
+            )
+        }
+    }
+}
+
+/// Sizes measured by [`source_size`], by copy source.
+static SOURCE_SIZES: Mutex<BTreeMap<String, Option<ByteSize>>> = Mutex::new(BTreeMap::new());
+
+/// Size of the files at the copy source, which is a directory or an image file.
+///
+/// Anything else, like an OCI image reference, has no size known before it is pulled. Measuring
+/// a source takes a while, so it is only done once.
+#[must_use]
+pub fn source_size(source: &str) -> Option<ByteSize> {
+    if let Some(size) = SOURCE_SIZES.lock().get(source) {
+        return *size;
+    }
+    let size = measure(Path::new(source));
+    SOURCE_SIZES.lock().insert(source.to_owned(), size);
+    size
+}
+
+fn measure(path: &Path) -> Option<ByteSize> {
+    let metadata = std::fs::metadata(path).ok()?;
+    tracing::debug!(?path, "Measuring the copy source");
+    let size = if metadata.is_file() {
+        image_size(path, metadata.len())
+    } else if metadata.is_dir() {
+        tree_size(path).map_err(Into::into)
+    } else {
+        return None;
+    };
+    (size)
+        .inspect_err(|err| tracing::warn!(?err, ?path, "cannot measure the copy source"))
+        .ok()
+        .map(ByteSize::b)
+}
+
+/// Size of the files in the image file at `path`, which is `len` bytes long.
+///
+/// A squashfs image is compressed, so its files are added up instead. Other images are taken to
+/// be raw filesystems, which their files fit in.
+fn image_size(path: &Path, len: u64) -> Result<u64> {
+    let mut magic = [0; 4];
+    let read = std::fs::File::open(path)?.read_exact(&mut magic);
+    if read.is_err() || &magic != SQUASHFS_MAGIC {
+        return Ok(len);
+    }
+    let output = Command::new("unsquashfs")
+        .arg("-lln")
+        .arg(path)
+        .output()
+        .wrap_err("cannot run `unsquashfs`")?;
+    if !output.status.success() {
+        bail!(
+            "`unsquashfs -lln {}` failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(listing_size(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Magic number at the start of a squashfs image.
+const SQUASHFS_MAGIC: &[u8; 4] = b"hsqs";
+
+/// Add up the sizes of the regular files in the output of `unsquashfs -lln`, e.g.
+/// `-rw-r--r-- 0/0  1024 2024-09-24 12:00 squashfs-root/etc/os-release`.
+fn listing_size(listing: &str) -> u64 {
+    (listing.lines())
+        .filter(|line| line.starts_with('-'))
+        .filter_map(|line| line.split_whitespace().nth(2)?.parse::<u64>().ok())
+        .sum()
+}
+
+/// Add up the sizes of the files under `root` like `du --apparent-size -x`: hard links are
+/// counted once, and other filesystems mounted below `root` are left out.
+fn tree_size(root: &Path) -> std::io::Result<u64> {
+    let device = std::fs::symlink_metadata(root)?.dev();
+    let mut linked = HashSet::new();
+    let mut dirs = vec![root.to_path_buf()];
+    let mut size = 0;
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.dev() != device {
+                continue;
+            }
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.nlink() == 1 || linked.insert(metadata.ino()) {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repart::Config;
+
+    fn partition(ini: &str) -> Partition {
+        let config: Config = serde_systemd_unit::from_str(&format!("[Partition]\n{ini}")).unwrap();
+        config.partition
+    }
+
+    #[test]
+    fn source_and_minimums() {
+        let partitions = [
+            partition("Type=esp\nSizeMinBytes=512M\nCopyFiles=/boot/efi:/\n"),
+            partition("Type=swap\nSizeMinBytes=4G\n"),
+            partition("Type=root\nSizeMinBytes=1G\nPaddingMinBytes=1G\nCopyFiles=/:/\n"),
+        ];
+
+        let empty = Fit::new(&partitions, None, ByteSize::gib(16));
+        assert_eq!(empty.partitions, ByteSize::mib(512 + 6 * 1024));
+        assert_eq!(empty.needed, empty.partitions);
+        assert!(empty.fits());
+
+        // the source doesn't fit next to swap
+        let full = Fit::new(&partitions, Some(ByteSize::gib(13)), ByteSize::gib(16));
+        assert_eq!(full.needed, ByteSize::gib(17));
+        assert!(!full.fits());
+        assert_eq!(full.margin(), ByteSize::gib(1));
+        assert_eq!(
+            full.to_string(),
+            "needs 17.0 GiB, but the disk is only 16.0 GiB, 1.0 GiB short"
+        );
+    }
+
+    #[test]
+    fn measure_source() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("etc")).unwrap();
+        std::fs::write(dir.path().join("etc/os-release"), [0; 1000]).unwrap();
+        std::fs::hard_link(
+            dir.path().join("etc/os-release"),
+            dir.path().join("os-release"),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("image.raw"), [0; 24]).unwrap();
+        let source = dir.path().to_str().unwrap();
+
+        assert_eq!(source_size(source), Some(ByteSize::b(1024)));
+        assert_eq!(
+            source_size(&format!("{source}/image.raw")),
+            Some(ByteSize::b(24))
+        );
+        assert_eq!(source_size("ghcr.io/ultramarine-linux/base:latest"), None);
+    }
+
+    #[test]
+    fn squashfs_listing() {
+        let listing = "\
+            drwxr-xr-x 0/0                54 2024-09-24 12:00 squashfs-root\n\
+            drwxr-xr-x 0/0                31 2024-09-24 12:00 squashfs-root/etc\n\
+            -rw-r--r-- 0/0              1000 2024-09-24 12:00 squashfs-root/etc/os-release\n\
+            lrwxrwxrwx 0/0                21 2024-09-24 12:00 squashfs-root/os-release -> etc/os-release\n\
+            crw-rw-rw- 0/0             1,  3 2024-09-24 12:00 squashfs-root/null\n\
+            -rwxr-xr-x 0/0                24 2024-09-24 12:00 squashfs-root/init";
+        assert_eq!(listing_size(listing), 1024);
+    }
+}
@@ -0,0 +1,183 @@
+//! Preview of the partition table an installation leaves on its destination disk.
+//!
+//! systemd-repart can lay out the new partition table without writing it (`--dry-run yes`). A
+//! [`Preview`] is made from that output, so the user sees which partitions are created, kept and
+//! deleted before anything is written.
+
+use bytesize::ByteSize;
+use repart::{Activity, Output};
+
+use crate::prelude::*;
+
+/// A partition in a [`Preview`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PreviewPartition {
+    /// Partition number, counting from 1
+    pub number: u64,
+    /// systemd-repart's name of the partition type, e.g. `root-x86-64`, or its GUID
+    pub part_type: String,
+    pub label: String,
+    /// Byte offset of the partition on the disk
+    pub offset: u64,
+    pub size: ByteSize,
+    pub activity: Activity,
+}
+
+/// The partition table of a disk after the installation.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Preview {
+    pub disk: ByteSize,
+    /// Partitions sorted by offset, followed by the deleted ones
+    pub partitions: Vec<PreviewPartition>,
+}
+
+/// A stretch of the disk in [`Preview::segments`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment<'a> {
+    /// The partition there, or `None` for free space
+    pub partition: Option<&'a PreviewPartition>,
+    /// Share of the whole disk, between 0 and 1
+    pub share: f64,
+}
+
+impl Preview {
+    /// Make a preview from the output of `systemd-repart --dry-run yes --json`.
+    ///
+    /// `deleted` are the partitions the new table drops without systemd-repart reporting them,
+    /// i.e. everything on a disk that is wiped.
+    pub fn new<I>(output: &Output, deleted: I, disk: ByteSize) -> Self
+    where
+        I: IntoIterator<Item = PreviewPartition>,
+    {
+        let mut partitions = (output.partitions.iter())
+            .map(|part| PreviewPartition {
+                number: part.partno + 1,
+                part_type: part.part_type.clone(),
+                label: part.label.clone(),
+                offset: part.offset,
+                size: if part.activity == Activity::Delete {
+                    part.old_size
+                } else {
+                    part.raw_size
+                },
+                activity: part.activity,
+            })
+            .collect_vec();
+        partitions.extend(deleted.into_iter().map(|part| PreviewPartition {
+            activity: Activity::Delete,
+            ..part
+        }));
+        partitions.sort_by_key(|part| (part.activity == Activity::Delete, part.offset));
+        Self { disk, partitions }
+    }
+
+    /// Whether any partition on the disk is deleted.
+    #[must_use]
+    pub fn deletes(&self) -> bool {
+        (self.partitions.iter()).any(|part| part.activity == Activity::Delete)
+    }
+
+    /// The disk from start to end, as the partitions left after the installation and the free
+    /// space between them, for drawing it as a bar.
+    #[must_use]
+    pub fn segments(&self) -> Vec<Segment<'_>> {
+        #[allow(clippy::cast_precision_loss)]
+        let share = |bytes: u64| bytes as f64 / self.disk.as_u64().max(1) as f64;
+        let mut segments = vec![];
+        let mut end = 0;
+        for part in (self.partitions.iter()).filter(|part| part.activity != Activity::Delete) {
+            if part.offset > end {
+                segments.push(Segment {
+                    partition: None,
+                    share: share(part.offset - end),
+                });
+            }
+            segments.push(Segment {
+                partition: Some(part),
+                share: share(part.size.as_u64()),
+            });
+            end = end.max(part.offset + part.size.as_u64());
+        }
+        if self.disk.as_u64() > end {
+            segments.push(Segment {
+                partition: None,
+                share: share(self.disk.as_u64() - end),
+            });
+        }
+        segments
+    }
+}
+
+impl std::fmt::Display for Preview {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.partitions {
+            let activity = match part.activity {
+                Activity::Create => "create",
+                Activity::Resize => "resize",
+                Activity::Unchanged => "keep",
+                Activity::Delete => "delete",
+                Activity::Unknown => "unknown",
+            };
+            writeln!(
+                f,
+                "{activity:<8} {:>3} {:<16} {:<16} {}",
+                part.number, part.part_type, part.label, part.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::indexing_slicing)]
+    fn wiped_disk() {
+        let output: Output = serde_json::from_str(
+            r#"[
+                {"type": "esp", "label": "esp", "uuid": "94b39668-b1ef-451c-8d8d-8f07a6db2920",
+                 "partno": 0, "file": "/tmp/20-efi.conf", "node": "/dev/vda1", "offset": 1048576,
+                 "old_size": 0, "raw_size": 536870912, "old_padding": 0, "raw_padding": 0,
+                 "activity": "create"},
+                {"type": "root-x86-64", "label": "root", "uuid": "129f1ef9-0acc-4c4e-a120-9c59f227966f",
+                 "partno": 1, "file": "/tmp/50-root.conf", "node": "/dev/vda2", "offset": 537919488,
+                 "old_size": 0, "raw_size": 3221225472, "old_padding": 0, "raw_padding": 0,
+                 "activity": "create"}
+            ]"#,
+        )
+        .unwrap();
+        let windows = PreviewPartition {
+            number: 3,
+            part_type: "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7".to_owned(),
+            label: "Windows".to_owned(),
+            offset: 1_048_576,
+            size: ByteSize::gib(8),
+            activity: Activity::Unchanged,
+        };
+        let preview = Preview::new(&output, [windows], ByteSize::gib(8));
+
+        assert!(preview.deletes());
+        assert_eq!(
+            (preview.partitions.iter())
+                .map(|part| (part.number, part.activity))
+                .collect_vec(),
+            [
+                (1, Activity::Create),
+                (2, Activity::Create),
+                (3, Activity::Delete)
+            ]
+        );
+
+        // 1 MiB free, the ESP, root right after it, the rest
+        let segments = preview.segments();
+        assert_eq!(segments.len(), 4);
+        assert!(segments[0].partition.is_none());
+        assert_eq!(segments[1].partition.map(|p| p.number), Some(1));
+        assert_eq!(segments[2].partition.map(|p| p.number), Some(2));
+        assert!(segments[3].partition.is_none());
+        let total: f64 = segments.iter().map(|s| s.share).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+}
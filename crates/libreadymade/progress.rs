@@ -0,0 +1,138 @@
+//! Installation progress reporting.
+//!
+//! Provisioners and postinstall modules report what they are doing through [`crate::stage!`],
+//! and frontends such as `readymade-playbook` [`subscribe`] to receive every [`ProgressEvent`].
+
+use crate::prelude::*;
+use parking_lot::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Byte copy events are dropped if the previous one was sent less than this long ago.
+const BYTES_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A progress update sent to every subscribed sink.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// Stable identifier of the current stage, e.g. `initramfs`.
+    pub stage: String,
+    /// Human-readable description of the current stage.
+    pub message: String,
+    /// 1-based index of the playbook step being run.
+    pub step: usize,
+    /// Number of steps in the playbook.
+    pub total_steps: usize,
+    /// Set while files are being copied.
+    pub bytes: Option<ByteProgress>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ByteProgress {
+    pub done: u64,
+    /// Total number of bytes to copy, if known in advance.
+    pub total: Option<u64>,
+}
+
+type Sink = Box<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+static SINKS: RwLock<Vec<Sink>> = RwLock::new(Vec::new());
+static CURRENT: Mutex<Option<(ProgressEvent, Instant)>> = Mutex::new(None);
+
+/// Register a sink that receives every subsequent [`ProgressEvent`].
+///
+/// Sinks are called synchronously from the installing thread, so they should return quickly.
+pub fn subscribe<F: Fn(&ProgressEvent) + Send + Sync + 'static>(sink: F) {
+    SINKS.write().push(Box::new(sink));
+}
+
+fn emit(event: &ProgressEvent) {
+    SINKS.read().iter().for_each(|sink| sink(event));
+}
+
+/// Begin step `step` out of `total_steps` of the playbook.
+pub fn begin_step(step: usize, total_steps: usize, stage: &str, message: &str) {
+    let event = ProgressEvent {
+        stage: stage.to_owned(),
+        message: message.to_owned(),
+        step,
+        total_steps,
+        bytes: None,
+    };
+    emit(&event);
+    *CURRENT.lock() = Some((event, Instant::now()));
+}
+
+/// Enter a stage within the current step. Called by [`crate::stage!`].
+pub fn begin_stage(stage: &str, message: &str) {
+    let mut current = CURRENT.lock();
+    let (event, last) = current.get_or_insert_with(|| (ProgressEvent::default(), Instant::now()));
+    stage.clone_into(&mut event.stage);
+    message.clone_into(&mut event.message);
+    event.bytes = None;
+    *last = Instant::now();
+    let event = event.clone();
+    drop(current);
+    emit(&event);
+}
+
+/// Report that `done` bytes have been copied so far in the current stage.
+///
+/// This may be called very often; events are rate-limited.
+pub fn copied_bytes(done: u64, total: Option<u64>) {
+    let mut current = CURRENT.lock();
+    let Some((event, last)) = current.as_mut() else {
+        return;
+    };
+    let bytes = ByteProgress { done, total };
+    event.bytes = Some(bytes);
+    if last.elapsed() < BYTES_INTERVAL && total != Some(done) {
+        return;
+    }
+    *last = Instant::now();
+    let event = event.clone();
+    drop(current);
+    emit(&event);
+}
+
+impl std::fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.total_steps != 0 {
+            write!(f, "[{}/{}] ", self.step, self.total_steps)?;
+        }
+        write!(f, "{}", self.message)?;
+        match self.bytes {
+            Some(ByteProgress {
+                done,
+                total: Some(total),
+            }) => write!(
+                f,
+                " ({} / {})",
+                bytesize::ByteSize::b(done),
+                bytesize::ByteSize::b(total)
+            ),
+            Some(ByteProgress { done, total: None }) => {
+                write!(f, " ({})", bytesize::ByteSize::b(done))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_event() {
+        let event = ProgressEvent {
+            stage: "copying".to_owned(),
+            message: "Copying files".to_owned(),
+            step: 2,
+            total_steps: 9,
+            bytes: Some(ByteProgress {
+                done: 1024,
+                total: None,
+            }),
+        };
+        assert_eq!(event.to_string(), "[2/9] Copying files (1.0 KiB)");
+    }
+}
@@ -0,0 +1,159 @@
+//! Install receipt, written into the installed system at [`READYMADE_STATE_PATH`].
+//!
+//! The receipt records how a machine was installed, so support and repair tooling don't have to
+//! guess: the playbook (without its encryption key), the partitions that were created, the mounts,
+//! kernel arguments and how long each step took.
+
+use crate::backend::mounts::{MAPPER_CACHE, generate_cryptdata};
+use crate::consts::READYMADE_STATE_PATH;
+use crate::journal::StepResult;
+use crate::playbook::Playbook;
+use crate::prelude::*;
+
+/// Format version of [`Receipt`], bumped whenever a field changes meaning or is removed.
+pub const RECEIPT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    /// See [`RECEIPT_VERSION`].
+    pub version: u32,
+    /// Version of Readymade that performed the installation.
+    pub readymade_version: String,
+    /// When the installation finished, in seconds since the Unix epoch.
+    pub installed_at: u64,
+    /// The playbook, see [`Playbook::redacted`].
+    pub playbook: Playbook,
+    /// See [`Playbook::copy_source`].
+    pub copy_source: Option<String>,
+    pub partitions: Vec<PartitionInfo>,
+    pub mounts: Mounts,
+    /// Kernel command line of the installer environment.
+    pub host_cmdline: String,
+    /// Kernel arguments Readymade added for the installed system, e.g. to unlock LUKS partitions.
+    pub kernel_args: Vec<String>,
+    /// Every step of the playbook that was run, in order.
+    pub steps: Vec<StepResult>,
+}
+
+/// A partition as found on the disk after installation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Device node at installation time, e.g. `/dev/vda3`. This may differ on the next boot.
+    pub node: PathBuf,
+    /// GPT partition UUID.
+    pub partuuid: Option<String>,
+    /// Filesystem or LUKS UUID.
+    pub uuid: Option<String>,
+    /// GPT partition label.
+    pub partlabel: Option<String>,
+    /// LUKS mapper device the partition was opened as, if it is encrypted.
+    pub mapper: Option<PathBuf>,
+}
+
+impl PartitionInfo {
+    fn probe(node: &Path) -> Self {
+        let mut info = Self {
+            node: node.to_owned(),
+            mapper: MAPPER_CACHE.read().get(&node.to_string_lossy()).cloned(),
+            ..Self::default()
+        };
+        match lsblk::BlockDevice::from_path(node) {
+            Ok(dev) => {
+                info.partuuid = dev.partuuid;
+                info.uuid = dev.uuid;
+                info.partlabel = dev.partlabel;
+            }
+            Err(err) => tracing::warn!(?node, ?err, "Cannot probe partition for the receipt"),
+        }
+        info
+    }
+}
+
+impl Receipt {
+    /// Collect the receipt of an installation. Must be called in the chroot of the installed system.
+    ///
+    /// # Errors
+    /// The LUKS partitions cannot be inspected.
+    pub fn new(playbook: &Playbook, mounts: &Mounts, steps: Vec<StepResult>) -> Result<Self> {
+        let partitions = (mounts.0.iter())
+            .map(|mount| &mount.partition)
+            .unique()
+            .map(|node| PartitionInfo::probe(node))
+            .collect();
+        let kernel_args = generate_cryptdata(mounts)?
+            .map(|data| data.cmdline_opts)
+            .unwrap_or_default();
+        let host_cmdline = std::fs::read_to_string("/proc/cmdline")
+            .map(|cmdline| cmdline.trim().to_owned())
+            .unwrap_or_default();
+        let installed_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+
+        Ok(Self {
+            version: RECEIPT_VERSION,
+            readymade_version: env!("CARGO_PKG_VERSION").to_owned(),
+            installed_at,
+            playbook: playbook.redacted(),
+            copy_source: playbook.copy_source(),
+            partitions,
+            mounts: mounts.clone(),
+            host_cmdline,
+            kernel_args,
+            steps,
+        })
+    }
+
+    /// Write the receipt to [`READYMADE_STATE_PATH`].
+    ///
+    /// # Errors
+    /// The receipt cannot be written.
+    pub fn write(&self) -> Result<()> {
+        let path = Path::new(READYMADE_STATE_PATH);
+        tracing::info!(?path, "Writing install receipt");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err("Failed to create parent directories for install receipt")?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .wrap_err("Failed to write install receipt")
+    }
+
+    /// Read the receipt of the system installed at `root`.
+    ///
+    /// # Errors
+    /// There is no receipt, or it cannot be parsed.
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(READYMADE_STATE_PATH.trim_start_matches('/'));
+        let content = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("cannot read install receipt at {}", path.display()))?;
+        serde_json::from_str(&content)
+            .wrap_err_with(|| format!("cannot parse install receipt at {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playbook::EncryptionConfig;
+    use crate::secret::SecretSource;
+
+    #[test]
+    fn receipt_redacts_key() {
+        let mut playbook = crate::playbook::test_playbook(
+            "/dev/vda",
+            serde_json::json!({ "module": "Repart", "directory": "/tmp/repart", "copy_source": "/run/rootfsbase" }),
+            serde_json::Value::Null,
+        );
+        playbook.encryption = Some(EncryptionConfig {
+            tpm: false,
+            encryption_key: SecretSource::Inline("hunter2".to_owned()).into(),
+        });
+
+        let receipt = Receipt::new(&playbook, &Mounts(vec![]), vec![]).unwrap();
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert!(!json.contains("hunter2"));
+        assert_eq!(receipt.copy_source.as_deref(), Some("/run/rootfsbase"));
+        assert_eq!(receipt.version, RECEIPT_VERSION);
+    }
+}
@@ -0,0 +1,221 @@
+//! Secrets such as LUKS passphrases, and where to read them from.
+//!
+//! A playbook ends up in journals, receipts, debug dumps and pipes between processes, so it should
+//! only say *where* a secret is. The secret itself is read once, kept in a [`Secret`] that is wiped
+//! from memory when dropped, and never serialized.
+
+use crate::prelude::*;
+use std::io::Read;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+/// A secret read into memory, wiped when dropped.
+pub type Secret = Zeroizing<String>;
+
+/// Where to read a secret from.
+///
+/// In JSON, a plain string is the secret itself, while an object names a source:
+/// `{ "file": "/run/credentials/key" }`, `{ "fd": 3 }` or `{ "keyring": "readymade:luks" }`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+pub enum SecretSource {
+    /// The secret itself. It ends up wherever the playbook does, so avoid this for automated installs.
+    Inline(String),
+    /// Read from a file, e.g. in `$CREDENTIALS_DIRECTORY`. A trailing newline is ignored.
+    File { file: PathBuf },
+    /// Read until EOF from a file descriptor inherited from the parent process, e.g. a pipe.
+    ///
+    /// It can only be read once.
+    Fd { fd: i32 },
+    /// Read from the `user` key with this description in the kernel keyring, using `keyctl`.
+    Keyring { keyring: String },
+}
+
+impl Default for SecretSource {
+    fn default() -> Self {
+        Self::Inline(String::new())
+    }
+}
+
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inline(_) => f.write_str("Inline(<redacted>)"),
+            Self::File { file } => f.debug_struct("File").field("file", file).finish(),
+            Self::Fd { fd } => f.debug_struct("Fd").field("fd", fd).finish(),
+            Self::Keyring { keyring } => {
+                f.debug_struct("Keyring").field("keyring", keyring).finish()
+            }
+        }
+    }
+}
+
+impl SecretSource {
+    /// The same source, without the secret if it is inline, so it is safe to store.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        match self {
+            Self::Inline(_) => Self::Inline("<redacted>".to_owned()),
+            source => source.clone(),
+        }
+    }
+
+    /// Why the secret cannot be read, if that can be told without reading it.
+    #[must_use]
+    pub fn problem(&self) -> Option<String> {
+        match self {
+            Self::Inline(secret) if secret.is_empty() => Some("the secret is empty".to_owned()),
+            Self::File { file } if !file.exists() => {
+                Some(format!("secret file {} does not exist", file.display()))
+            }
+            Self::Fd { fd } if !fd_path(*fd).exists() => {
+                Some(format!("secret file descriptor {fd} is not open"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the secret.
+    ///
+    /// # Errors
+    /// The source cannot be read, or the secret is empty.
+    pub fn read(&self) -> Result<Secret> {
+        let mut secret = match self {
+            Self::Inline(secret) => Zeroizing::new(secret.clone()),
+            Self::File { file } => read_to_secret(file)
+                .wrap_err_with(|| format!("cannot read secret from {}", file.display()))?,
+            // reopen the descriptor instead of taking ownership of it, which would need `unsafe`
+            Self::Fd { fd } => read_to_secret(&fd_path(*fd))
+                .wrap_err_with(|| format!("cannot read secret from file descriptor {fd}"))?,
+            Self::Keyring { keyring } => read_keyring(keyring)
+                .wrap_err_with(|| format!("cannot read secret {keyring:?} from the keyring"))?,
+        };
+        if secret.ends_with('\n') {
+            secret.pop();
+        }
+        if secret.is_empty() {
+            bail!("secret from {self:?} is empty");
+        }
+        Ok(secret)
+    }
+}
+
+fn fd_path(fd: i32) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{fd}"))
+}
+
+fn read_to_secret(path: &Path) -> Result<Secret> {
+    // preallocate, so the secret is not left behind in memory by reallocations
+    let mut secret = Zeroizing::new(String::with_capacity(4096));
+    std::fs::File::open(path)?.read_to_string(&mut secret)?;
+    Ok(secret)
+}
+
+fn read_keyring(description: &str) -> Result<Secret> {
+    let output = Command::new("keyctl")
+        .args(["pipe", &format!("%user:{description}")])
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .wrap_err("cannot run keyctl")?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        bail!("`keyctl pipe` failed: {:?}", output.status.code());
+    }
+    Ok(Zeroizing::new(
+        std::str::from_utf8(&stdout)
+            .wrap_err("secret is not valid UTF-8")?
+            .to_owned(),
+    ))
+}
+
+/// A [`SecretSource`] that is read at most once, since e.g. a file descriptor can't be read twice.
+#[derive(Clone, Default, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(transparent)]
+pub struct CachedSecret {
+    source: SecretSource,
+    #[serde(skip)]
+    secret: OnceLock<Secret>,
+}
+
+impl std::fmt::Debug for CachedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl PartialEq for CachedSecret {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for CachedSecret {}
+
+impl From<SecretSource> for CachedSecret {
+    fn from(source: SecretSource) -> Self {
+        Self {
+            source,
+            secret: OnceLock::new(),
+        }
+    }
+}
+
+impl CachedSecret {
+    #[must_use]
+    pub const fn source(&self) -> &SecretSource {
+        &self.source
+    }
+
+    /// The secret, read from its source the first time.
+    ///
+    /// # Errors
+    /// See [`SecretSource::read`].
+    pub fn get(&self) -> Result<&str> {
+        if let Some(secret) = self.secret.get() {
+            return Ok(secret);
+        }
+        let secret = self.source.read()?;
+        Ok(self.secret.get_or_init(|| secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("key");
+        std::fs::write(&file, "hunter2\n").unwrap();
+
+        let parse = |json| serde_json::from_value::<SecretSource>(json).unwrap();
+        let inline = parse(serde_json::json!("hunter2"));
+        let from_file = parse(serde_json::json!({ "file": file }));
+        assert_eq!(inline, SecretSource::Inline("hunter2".to_owned()));
+        assert_eq!(*inline.read().unwrap(), "hunter2");
+        assert_eq!(*from_file.read().unwrap(), "hunter2");
+        assert_eq!(
+            parse(serde_json::json!({ "keyring": "readymade:luks" })),
+            SecretSource::Keyring {
+                keyring: "readymade:luks".to_owned()
+            }
+        );
+
+        assert!(!format!("{inline:?}").contains("hunter2"));
+        assert!(
+            !serde_json::to_string(&inline.redacted())
+                .unwrap()
+                .contains("hunter2")
+        );
+        assert_eq!(from_file.redacted(), from_file);
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(from_file.problem().is_some());
+        let err = from_file.read().unwrap_err();
+        assert!(
+            err.to_string().starts_with("cannot read secret from"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Teardown of everything an installation sets up on the host.
+//!
+//! Mounts, LUKS mapper devices, LVM volume groups, md RAID arrays, loop devices, podman containers and the setup lock file are registered with
+//! [`acquire`] as soon as they exist. Whoever set a resource up normally tears it down again with
+//! [`release`], or [`forget`]s it if it already did so itself. If a step fails or panics, the
+//! [`Session`] of the installation tears down whatever is left, in reverse order, so the disk is
+//! free for another attempt.
+
+use crate::backend::mounts::{MAPPER_CACHE, cryptsetup_close};
+use crate::prelude::*;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, umount, umount2};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Something on the host that has to be undone once the installation is over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    /// A filesystem mounted on this path, including loop mounts of image files.
+    Mount(PathBuf),
+    /// A LUKS mapper device opened for the partition `node`.
+    Mapper { node: String, mapper: PathBuf },
+    /// An LVM volume group that was activated, which keeps the devices underneath it busy.
+    VolumeGroup(String),
+    /// An md RAID array that was assembled, which keeps its member partitions busy.
+    RaidArray(PathBuf),
+    /// A loop device a disk image file was attached to, see [`crate::image`].
+    LoopDevice(PathBuf),
+    /// A podman container created to read an OCI image from.
+    PodmanContainer(String),
+    /// A file that only exists while installing, e.g. the setup lock file.
+    File(PathBuf),
+}
+
+impl Resource {
+    /// Undo the resource. Resources that are already gone are not an error.
+    ///
+    /// # Errors
+    /// The resource exists but cannot be removed.
+    pub fn teardown(&self) -> Result<()> {
+        tracing::debug!(resource = ?self, "Tearing down");
+        match self {
+            Self::Mount(target) => match umount(target) {
+                Ok(()) | Err(Errno::EINVAL | Errno::ENOENT) => Ok(()),
+                Err(Errno::EBUSY) => {
+                    tracing::warn!(?target, "Mount is busy, detaching it lazily");
+                    umount2(target, MntFlags::MNT_DETACH)
+                        .wrap_err_with(|| format!("cannot unmount {}", target.display()))
+                }
+                Err(e) => Err(e).wrap_err_with(|| format!("cannot unmount {}", target.display())),
+            },
+            Self::Mapper { node, mapper } => {
+                // whoever still holds the old cache keeps their copy
+                Arc::make_mut(&mut *MAPPER_CACHE.write()).remove(node);
+                if !mapper.exists() {
+                    return Ok(());
+                }
+                cryptsetup_close(&mapper.to_string_lossy())
+            }
+            Self::VolumeGroup(vg) => {
+                // the volumes only show up in /dev while the group is active
+                if !Path::new("/dev").join(vg).exists() {
+                    return Ok(());
+                }
+                let status = (Command::new("vgchange")
+                    .args(["--activate", "n", vg])
+                    .status())
+                .wrap_err("cannot run `vgchange`")?;
+                if !status.success() {
+                    bail!("`vgchange --activate n {vg}` failed: {:?}", status.code());
+                }
+                Ok(())
+            }
+            Self::RaidArray(array) => {
+                if !array.exists() {
+                    return Ok(());
+                }
+                let status = (Command::new("mdadm").arg("--stop").arg(array).status())
+                    .wrap_err("cannot run `mdadm`")?;
+                if !status.success() {
+                    bail!(
+                        "`mdadm --stop {}` failed: {:?}",
+                        array.display(),
+                        status.code()
+                    );
+                }
+                Ok(())
+            }
+            Self::LoopDevice(device) => {
+                if !device.exists() {
+                    return Ok(());
+                }
+                let status = (Command::new("losetup").arg("--detach").arg(device).status())
+                    .wrap_err("cannot run `losetup`")?;
+                if !status.success() {
+                    bail!(
+                        "`losetup --detach {}` failed: {:?}",
+                        device.display(),
+                        status.code()
+                    );
+                }
+                Ok(())
+            }
+            Self::PodmanContainer(id) => {
+                // unmounting fails if the container was never mounted, which is fine
+                _ = Command::new("podman").args(["umount", id]).status();
+                let status = (Command::new("podman").args(["rm", "-f", id]).status())
+                    .wrap_err("cannot run `podman rm`")?;
+                if !status.success() {
+                    bail!("`podman rm -f {id}` failed: {:?}", status.code());
+                }
+                Ok(())
+            }
+            Self::File(path) => crate::backend::util::fs::exist_then(std::fs::remove_file(path))
+                .wrap_err_with(|| format!("cannot remove {}", path.display())),
+        }
+    }
+}
+
+static RESOURCES: Mutex<Vec<Resource>> = Mutex::new(Vec::new());
+
+/// Register a resource that was just set up.
+pub fn acquire(resource: Resource) {
+    tracing::trace!(?resource, "Acquired");
+    RESOURCES.lock().push(resource);
+}
+
+/// Unregister a resource that was already torn down by its owner.
+pub fn forget(resource: &Resource) {
+    let mut resources = RESOURCES.lock();
+    if let Some(i) = resources.iter().rposition(|r| r == resource) {
+        resources.remove(i);
+    }
+}
+
+/// Tear down a resource now and unregister it.
+///
+/// # Errors
+/// See [`Resource::teardown`].
+pub fn release(resource: &Resource) -> Result<()> {
+    forget(resource);
+    resource.teardown()
+}
+
+/// Tear down every registered resource, most recent first.
+///
+/// Failures are logged, and do not stop the remaining resources from being torn down.
+pub fn teardown() {
+    loop {
+        // don't hold the lock while tearing down
+        let Some(resource) = RESOURCES.lock().pop() else {
+            break;
+        };
+        if let Err(err) = resource.teardown() {
+            tracing::error!(?resource, ?err, "Cannot tear down resource");
+        }
+    }
+}
+
+/// Guard for one installation; everything still registered is torn down when it is dropped.
+///
+/// Only one session should be active at a time, since resources are tracked globally.
+#[must_use = "resources are torn down as soon as the session is dropped"]
+#[derive(Debug)]
+pub struct Session(());
+
+impl Session {
+    pub fn begin() -> Self {
+        let leftover = RESOURCES.lock().len();
+        if leftover != 0 {
+            tracing::warn!(leftover, "Resources left over from a previous session");
+            teardown();
+        }
+        Self(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            tracing::error!("Installation panicked, tearing down");
+        }
+        teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_tears_down_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("lock");
+        let other = dir.path().join("other");
+        std::fs::write(&lock, b"").unwrap();
+        std::fs::write(&other, b"").unwrap();
+
+        let session = Session::begin();
+        acquire(Resource::File(lock.clone()));
+        acquire(Resource::File(other.clone()));
+        release(&Resource::File(other.clone())).unwrap();
+        assert!(!other.exists());
+        assert!(lock.exists());
+        drop(session);
+        assert!(!lock.exists());
+        assert!(RESOURCES.lock().is_empty());
+    }
+}
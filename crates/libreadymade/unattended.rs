@@ -0,0 +1,156 @@
+//! Unattended installations, for deploying many identical machines.
+//!
+//! A playbook is picked up without any interaction from either:
+//! - `readymade.playbook=/path/to/playbook.json` on the kernel command line, or
+//! - a [`PLAYBOOK_FILE`] at the root of a filesystem labelled [`MEDIA_LABEL`], e.g. on a USB stick.
+//!
+//! The playbook is validated, a short countdown gives the operator a chance to abort with Ctrl+C,
+//! then it is run and [`Playbook::power_action`] is performed.
+//!
+//! `readymade-playbook unattended` does this at boot, from `readymade-unattended.service`,
+//! before the display manager starts.
+
+use crate::playbook::Playbook;
+use crate::prelude::*;
+use std::time::Duration;
+
+/// Kernel command line parameter pointing to a playbook.
+pub const CMDLINE_PARAM: &str = "readymade.playbook";
+/// Filesystem label of removable media carrying a playbook.
+pub const MEDIA_LABEL: &str = "READYMADE";
+/// Name of the playbook file at the root of the removable media.
+pub const PLAYBOOK_FILE: &str = "readymade-playbook.json";
+/// How long to wait before touching any disk.
+pub const COUNTDOWN: Duration = Duration::from_secs(10);
+
+/// Where an unattended playbook was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Path given on the kernel command line.
+    Cmdline(PathBuf),
+    /// Block device of the labelled removable media.
+    Media(PathBuf),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cmdline(path) => write!(f, "{} (from {CMDLINE_PARAM}=)", path.display()),
+            Self::Media(dev) => write!(f, "{PLAYBOOK_FILE} on {}", dev.display()),
+        }
+    }
+}
+
+/// Extract the playbook path from a kernel command line.
+#[must_use]
+pub fn cmdline_playbook(cmdline: &str) -> Option<PathBuf> {
+    (cmdline.split_whitespace())
+        .filter_map(|param| param.split_once('='))
+        .filter(|(key, _)| *key == CMDLINE_PARAM)
+        .map(|(_, path)| PathBuf::from(path))
+        .next_back()
+}
+
+fn read_playbook(path: &Path) -> Result<Playbook> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("cannot read playbook {}", path.display()))?;
+    serde_json::from_str(&content)
+        .wrap_err_with(|| format!("cannot parse playbook {}", path.display()))
+}
+
+/// Read [`PLAYBOOK_FILE`] from the filesystem on `dev`, mounted read-only for as short as possible.
+fn read_media_playbook(dev: &Path) -> Result<Option<Playbook>> {
+    let mountpoint = tempfile::Builder::new()
+        .prefix("readymade-media")
+        .tempdir()?;
+    sys_mount::Mount::builder()
+        .flags(sys_mount::MountFlags::RDONLY)
+        .mount(dev, mountpoint.path())
+        .wrap_err_with(|| format!("cannot mount {}", dev.display()))?;
+    // unmount before `mountpoint` is dropped
+    scopeguard::defer! {
+        if let Err(err) = nix::mount::umount(mountpoint.path()) {
+            tracing::warn!(?dev, ?err, "Cannot unmount unattended install media");
+        }
+    };
+
+    let path = mountpoint.path().join(PLAYBOOK_FILE);
+    if !path.exists() {
+        tracing::info!(?dev, "Media labelled {MEDIA_LABEL} has no {PLAYBOOK_FILE}");
+        return Ok(None);
+    }
+    read_playbook(&path).map(Some)
+}
+
+/// Look for an unattended playbook, see the [module documentation](self).
+///
+/// Media that happens to carry the [`MEDIA_LABEL`] but no readable playbook is only logged, so
+/// the interactive installer still starts.
+///
+/// # Errors
+/// A playbook was requested on the kernel command line but cannot be read or parsed.
+pub fn find() -> Result<Option<(Source, Playbook)>> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    if let Some(path) = cmdline_playbook(&cmdline) {
+        let playbook = read_playbook(&path)?;
+        return Ok(Some((Source::Cmdline(path), playbook)));
+    }
+
+    let dev = Path::new("/dev/disk/by-label").join(MEDIA_LABEL);
+    if !dev.exists() {
+        return Ok(None);
+    }
+    let found = dev.canonicalize().map_err(color_eyre::Report::from).and_then(|dev| {
+        Ok(read_media_playbook(&dev)?.map(|playbook| (Source::Media(dev), playbook)))
+    });
+    Ok(found
+        .inspect_err(|err| {
+            tracing::error!(?err, ?dev, "Cannot read the unattended install media");
+        })
+        .ok()
+        .flatten())
+}
+
+/// Validate and run an unattended playbook, then perform its [`Playbook::power_action`].
+///
+/// Progress is printed to stdout, so it shows up on the console.
+///
+/// # Errors
+/// The playbook is invalid or the installation fails.
+pub fn run(source: &Source, playbook: &Playbook) -> Result<()> {
+    println!("Unattended installation: {source}");
+    // show the operator which disks are about to be erased
+    let playbook = playbook.resolve_disk()?;
+    playbook.validate()?;
+    let disks = (playbook.written_disks()?.iter())
+        .map(|disk| disk.display())
+        .join(", ");
+
+    for remaining in (1..=COUNTDOWN.as_secs()).rev() {
+        println!("Installing to {disks} in {remaining}s, press Ctrl+C to cancel");
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    crate::progress::subscribe(|event| println!("{event}"));
+    let steps = playbook.play()?;
+    print!("{}", crate::journal::Timings(&steps));
+    println!("Installation complete");
+    playbook.power_action.perform()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playbook_from_cmdline() {
+        assert_eq!(
+            cmdline_playbook("BOOT_IMAGE=/vmlinuz quiet readymade.playbook=/run/pb.json rhgb"),
+            Some(PathBuf::from("/run/pb.json"))
+        );
+        assert_eq!(
+            cmdline_playbook("quiet readymade.playbooks=/x rd.live.image"),
+            None
+        );
+    }
+}
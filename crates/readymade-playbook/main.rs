@@ -1,23 +1,147 @@
-use color_eyre::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::{Result, eyre::WrapErr};
 use libreadymade::playbook::Playbook;
-use std::fs;
+use std::{fs, path::Path, path::PathBuf};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+/// Run and inspect Readymade playbooks
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Parse a playbook and check it for mistakes, without touching any disk
+    Validate { playbook: PathBuf },
+    /// Print everything a playbook would do, without touching any disk
+    Plan {
+        playbook: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+    /// Install using a playbook
+    Run {
+        playbook: PathBuf,
+        /// Format of the progress events printed to stdout
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+        /// Continue a failed run of the same playbook instead of starting over
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Run the playbook given on the kernel command line or on labelled media, if any
+    Unattended,
+    /// Print the JSON Schema of the playbook format
+    Schema,
+    /// List the disks Readymade can install to, along with any OS found on them
+    ListDisks {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
 
+fn read_playbook(path: &Path) -> Result<Playbook> {
+    let content = fs::read_to_string(path)
+        .wrap_err_with(|| format!("cannot read playbook {}", path.display()))?;
+    serde_json::from_str(&content)
+        .wrap_err_with(|| format!("cannot parse playbook {}", path.display()))
+}
+
+fn init_logging() {
+    // keep stdout clean for anything parsing the output
     tracing_subscriber::registry()
-        .with(fmt::layer().compact())
+        .with(fmt::layer().compact().with_writer(std::io::stderr))
         .with(EnvFilter::from_env("READYMADE_LOG"))
         .init();
+}
 
-    let playbook_file = std::env::args()
-        .nth(1)
-        .expect("usage: readymade-playbook <playbook-file>");
+fn print_progress(output: OutputFormat) {
+    libreadymade::progress::subscribe(move |event| match output {
+        OutputFormat::Human => println!("{event}"),
+        OutputFormat::Json => match serde_json::to_string(event) {
+            Ok(line) => println!("{line}"),
+            Err(err) => tracing::error!(?err, "cannot serialize progress event"),
+        },
+    });
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
 
-    let playbook: Playbook = serde_json::from_str(fs::read_to_string(playbook_file)?.as_str())?;
+    let cli = Cli::parse();
+    init_logging();
 
-    playbook.play()?;
+    match cli.command {
+        Commands::Validate { playbook: path } => {
+            let playbook = read_playbook(&path)?;
+            playbook.validate()?;
+            println!("{}: ok", path.display());
+            if let Some(fit) = playbook.fit()? {
+                println!("{}: {fit}", playbook.disk()?.display());
+            }
+        }
+        Commands::Plan { playbook, output } => {
+            let plan = read_playbook(&playbook)?.plan()?;
+            match output {
+                OutputFormat::Human => print!("{plan}"),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+            }
+        }
+        Commands::Run {
+            playbook,
+            output,
+            resume,
+        } => {
+            print_progress(output);
+            let playbook = read_playbook(&playbook)?;
+            let steps = if resume {
+                playbook.resume()?
+            } else {
+                playbook.play()?
+            };
+            if output == OutputFormat::Human {
+                print!("{}", libreadymade::journal::Timings(&steps));
+            }
+        }
+        Commands::Unattended => {
+            // an unreadable playbook is only fatal when asked for on the kernel command line
+            if let Some((source, playbook)) = libreadymade::unattended::find()? {
+                tracing::info!(%source, "Running unattended installation");
+                libreadymade::unattended::run(&source, &playbook)?;
+            }
+        }
+        Commands::Schema => {
+            let schema = schemars::schema_for!(Playbook);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        }
+        Commands::ListDisks { output } => {
+            let disks = libreadymade::disks::detect_os();
+            match output {
+                OutputFormat::Human => {
+                    for disk in disks {
+                        println!(
+                            "{}\t{}\t{}\t{}",
+                            disk.devpath.display(),
+                            disk.size,
+                            disk.disk_name,
+                            disk.os_name.as_deref().unwrap_or("-"),
+                        );
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&disks)?),
+            }
+        }
+    }
 
     Ok(())
 }
@@ -9,7 +9,7 @@ use bytesize::ByteSize as Size;
 use serde::Deserialize;
 use serde_with::{
     StringWithSeparator,
-    formats::{ColonSeparator, SemicolonSeparator, SpaceSeparator},
+    formats::{ColonSeparator, SpaceSeparator},
     serde_as,
 };
 use std::env::consts::ARCH;
@@ -36,24 +36,33 @@ pub struct Partition {
     #[serde(default)]
     pub priority: i32,
     // #[validate(range(min = 0, max = 1_000_000))]
-    #[serde(default = "_default_weight")]
+    #[serde(default = "default_weight")]
     pub weight: u32,
     // #[validate(range(min = 0, max = 1_000_000))]
     #[serde(default)]
     pub padding_weight: u32,
-    #[serde(default = "_default_size_min_bytes")]
+    #[serde(default = "default_size_min_bytes")]
+    #[serde(
+        serialize_with = "size_as_bytes",
+        deserialize_with = "size_from_string"
+    )]
     pub size_min_bytes: Size,
-    #[serde(default)]
+    /// Zero for no limit.
+    #[serde(default, deserialize_with = "size_from_string")]
+    #[serde(serialize_with = "size_as_bytes", skip_serializing_if = "is_zero")]
     pub size_max_bytes: Size,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "size_from_string")]
+    #[serde(serialize_with = "size_as_bytes", skip_serializing_if = "is_zero")]
     pub padding_min_bytes: Size,
-    #[serde(default)]
+    /// Zero for no limit.
+    #[serde(default, deserialize_with = "size_from_string")]
+    #[serde(serialize_with = "size_as_bytes", skip_serializing_if = "is_zero")]
     pub padding_max_bytes: Size,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub copy_blocks: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub format: Option<FileSystem>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[serde_as(as = "StringWithSeparator::<ColonSeparator, String>")]
     pub copy_files: Vec<String>,
 
@@ -65,22 +74,18 @@ pub struct Partition {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub compression_level: Option<String>,
 
-    // todo: serialize ; and whitespace-separated values as vec
-
-    // separate by ;
-    #[serde(default)]
-    #[serde_as(as = "StringWithSeparator::<SemicolonSeparator, String>")]
+    // one path per line, the key repeated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exclude_files: Vec<String>,
-    #[serde(default)]
-    #[serde_as(as = "StringWithSeparator::<SemicolonSeparator, String>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exclude_files_target: Vec<String>,
 
     // separate by whitespace
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[serde_as(as = "StringWithSeparator::<SpaceSeparator, String>")]
     pub make_directories: Vec<String>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[serde_as(as = "StringWithSeparator::<SpaceSeparator, String>")]
     pub subvolumes: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -90,7 +95,7 @@ pub struct Partition {
     #[serde(default)]
     pub verity: Verity,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     #[serde(serialize_with = "turn_to_string")]
     #[serde(deserialize_with = "bool_from_string")]
     pub factory_reset: bool,
@@ -123,6 +128,61 @@ impl Partition {
     }
 }
 
+// systemd-repart takes plain byte counts, but not the decimals `ByteSize` would print
+#[allow(clippy::trivially_copy_pass_by_ref)] // signature required by serde
+fn size_as_bytes<S>(value: &Size, se: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    se.serialize_u64(value.as_u64())
+}
+
+// Sizes are parsed like systemd does, where the suffixes K, M, G, T, P and E are powers of 1024,
+// unlike `ByteSize`, which takes them as powers of 1000
+fn size_from_string<'de, D>(deserializer: D) -> Result<Size, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_size(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid size {s:?}")))
+}
+
+/// Parse a size the way systemd does, e.g. `512M` or `1.5G`.
+#[must_use]
+pub fn parse_size(s: &str) -> Option<Size> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let exponent = match suffix.trim_start() {
+        "" | "B" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        "E" => 6,
+        _ => return None,
+    };
+    let multiplier = 1024_u64.checked_pow(exponent)?;
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(multiplier).map(Size::b);
+    }
+    let fraction = number.parse::<f64>().ok()?;
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    Some(Size::b((fraction * multiplier as f64) as u64))
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // signature required by serde
+const fn is_zero(value: &Size) -> bool {
+    value.as_u64() == 0
+}
+
 fn turn_to_string<T, S>(value: &T, se: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -146,11 +206,11 @@ where
     }
 }
 
-const fn _default_weight() -> u32 {
+const fn default_weight() -> u32 {
     1000
 }
 
-const fn _default_size_min_bytes() -> Size {
+const fn default_size_min_bytes() -> Size {
     Size::mib(10)
 }
 
@@ -319,7 +379,7 @@ ini_enum! {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{Config, Partition};
 
     #[test]
     fn read_config() {
@@ -328,6 +388,7 @@ mod tests {
 
         println!("{res:#?}");
         println!("{:?}", res.partition.mount_point_as_tuple());
+        assert_eq!(res.partition.size_min_bytes, bytesize::ByteSize::mib(16));
 
         let config2 = include_str!("test/root.conf");
         let res2: Config = serde_systemd_unit::from_str(config2).unwrap();
@@ -336,38 +397,61 @@ mod tests {
         println!("{:?}", res2.partition.mount_point_as_tuple());
     }
 
-    // FIXME: port this to serde_systemd_unit
-
-    // #[test]
-    // fn ser_new_config() {
-    //     let res = serde_systemd_unit::to_string(&RepartConfig {
-    //         partition: Partition {
-    //             part_type: super::PartTypeIdent::Esp,
-    //             label: Some("My Label".to_owned()),
-    //             uuid: Some(uuid::uuid!("7466c448-87ac-4e1c-b3e3-fe83b7a19262")),
-    //             priority: Default::default(),
-    //             weight: Default::default(),
-    //             padding_weight: Default::default(),
-    //             size_min_bytes: super::_default_size_min_bytes(),
-    //             size_max_bytes: bytesize::ByteSize::kb(100),
-    //             padding_min_bytes: super::Size::default(),
-    //             padding_max_bytes: super::Size::default(),
-    //             copy_blocks: Some("hai".to_owned()),
-    //             format: Some(super::FileSystem::Ext4),
-    //             copy_files: vec![],
-    //             exclude_files: vec![],
-    //             exclude_files_target: vec![],
-    //             make_directories: vec![],
-    //             subvolumes: vec![],
-    //             default_subvolume: None,
-    //             encrypt: Default::default(),
-    //             verity: super::Verity::Off,
-    //             factory_reset: false,
-    //             mount_point: vec![],
-    //             ..Default::default()
-    //         },
-    //     })
-    //     .unwrap();
-    //     println!("{res}");
-    // }
+    #[test]
+    fn ser_new_config() {
+        let res = serde_systemd_unit::to_string(&Config {
+            partition: Partition {
+                part_type: super::PartTypeIdent::Esp,
+                label: Some("My Label".to_owned()),
+                uuid: Some(uuid::uuid!("7466c448-87ac-4e1c-b3e3-fe83b7a19262")),
+                priority: Default::default(),
+                weight: Default::default(),
+                padding_weight: Default::default(),
+                size_min_bytes: super::default_size_min_bytes(),
+                size_max_bytes: bytesize::ByteSize::kb(100),
+                padding_min_bytes: super::Size::default(),
+                padding_max_bytes: super::Size::default(),
+                copy_blocks: Some("hai".to_owned()),
+                format: Some(super::FileSystem::Ext4),
+                copy_files: vec![],
+                exclude_files: vec!["/boot/efi/".to_owned(), "/boot/".to_owned()],
+                exclude_files_target: vec![],
+                make_directories: vec![],
+                subvolumes: vec![],
+                default_subvolume: None,
+                encrypt: super::EncryptOption::default(),
+                verity: super::Verity::Off,
+                factory_reset: false,
+                mount_point: vec!["/boot/efi".to_owned()],
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            res,
+            "[Partition]
+Type=esp
+Label=My Label
+UUID=7466c448-87ac-4e1c-b3e3-fe83b7a19262
+Priority=0
+Weight=0
+PaddingWeight=0
+SizeMinBytes=10485760
+SizeMaxBytes=100000
+CopyBlocks=hai
+Format=ext4
+ExcludeFiles=/boot/efi/
+ExcludeFiles=/boot/
+Encrypt=off
+Verity=off
+MountPoint=/boot/efi
+"
+        );
+
+        let config: Config = serde_systemd_unit::from_str(&res).unwrap();
+        assert_eq!(config.partition.label.as_deref(), Some("My Label"));
+        assert_eq!(config.partition.size_max_bytes, bytesize::ByteSize::kb(100));
+        assert_eq!(config.partition.mount_point, ["/boot/efi"]);
+        assert_eq!(config.partition.exclude_files, ["/boot/efi/", "/boot/"]);
+    }
 }
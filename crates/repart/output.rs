@@ -98,6 +98,15 @@ pub struct Output {
 }
 
 impl Output {
+    /// The partition made from the definition file named `file`, e.g. `50-root.conf`.
+    ///
+    /// systemd-repart reports the full path of each definition, so only the file names are compared.
+    #[must_use]
+    pub fn partition_for(&self, file: &str) -> Option<&OutputPartition> {
+        (self.partitions.iter())
+            .find(|part| part.file.file_name() == Some(std::ffi::OsStr::new(file)))
+    }
+
     /// Generate a `BTreeMap` of mountpoint -> node name for generating /etc/fstab
     /// from DDI partition types
     pub fn mountpoints(&self) -> impl Iterator<Item = (&'static str, String)> + '_ {
@@ -347,25 +356,61 @@ impl Output {
 //     Ok(mapper)
 // }
 
+// systemd-repart writes null for a column it has nothing for
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + serde::Deserialize<'de>,
+{
+    use serde::Deserialize as _;
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// What systemd-repart did to a partition.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activity {
+    /// The partition was created from its definition.
+    Create,
+    /// An existing partition was grown.
+    Resize,
+    /// An existing partition matched the definition and was left alone.
+    #[default]
+    Unchanged,
+    /// An existing partition was removed, e.g. by `FactoryReset=`.
+    Delete,
+    /// Something a newer systemd-repart did, which this version doesn't know of.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A partition in the JSON output of systemd-repart.
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone)]
 pub struct OutputPartition {
     // "type"
     #[serde(rename = "type")]
     pub part_type: String,
-    label: String,
-    uuid: uuid::Uuid,
-    partno: i32,
-    /// Path to repart config template
-    file: PathBuf,
+    /// Empty for partitions without a label
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub label: String,
+    /// Partition UUID
+    pub uuid: uuid::Uuid,
+    /// Index in the partition table, counting from 0
+    pub partno: u64,
+    /// Path to the definition file the partition was made from, empty for partitions no
+    /// definition matched
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub file: PathBuf,
     /// /dev node (/dev/XXX)
     pub node: String,
-    offset: usize,
-    old_size: ByteSize,
-    raw_size: ByteSize,
-    old_padding: usize,
-    raw_padding: usize,
+    /// Byte offset of the partition on the disk
+    pub offset: u64,
+    pub old_size: ByteSize,
+    pub raw_size: ByteSize,
+    pub old_padding: u64,
+    pub raw_padding: u64,
     /// action systemd-repart did to this partition
-    activity: String,
+    pub activity: Activity,
 }
 impl OutputPartition {
     /// Returns a Discoverable Disk Image (DDI) mountpoint if defined
@@ -584,6 +629,33 @@ mod tests {
     fn test_deserialize() {
         let output = deserialize();
         assert_eq!(output.partitions.len(), 3);
+        let root = output.partition_for("50-root.conf").unwrap();
+        assert_eq!(root.node, "/dev/vda3");
+        assert_eq!(root.partno, 2);
+        assert_eq!(root.offset, 1_611_661_312);
+        assert_eq!(root.activity, Activity::Create);
+        assert!(output.partition_for("60-home.conf").is_none());
+    }
+
+    #[test]
+    fn foreign_partition() {
+        let output: Output = serde_json::from_str(
+            r#"[{"type": "linux-generic", "label": null, "uuid": "94b39668-b1ef-451c-8d8d-8f07a6db2920",
+                 "partno": 3, "file": null, "node": "/dev/vda4", "offset": 1048576,
+                 "old_size": 4096, "raw_size": 4096, "old_padding": 0, "raw_padding": 0,
+                 "activity": "delete"}]"#,
+        )
+        .unwrap();
+        let part = output.partitions.first().unwrap();
+        assert_eq!(part.label, "");
+        assert_eq!(part.file, PathBuf::new());
+        assert_eq!(part.activity, Activity::Delete);
+    }
+
+    #[test]
+    fn unknown_activity() {
+        let activity: Activity = serde_json::from_str(r#""shrink""#).unwrap();
+        assert_eq!(activity, Activity::Unknown);
     }
 
     #[test]
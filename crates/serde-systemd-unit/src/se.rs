@@ -1,4 +1,13 @@
-use serde::{Serialize, ser};
+//! Serializer for systemd unit files.
+//!
+//! A struct is a section named after the struct. Fields holding a struct or a map are sections of
+//! their own, named after the field, and every other field is an entry of the struct's section.
+//! Sequences are written as the same key repeated, and `None` leaves the entry out.
+
+use serde::{
+    Serialize,
+    ser::{self, Impossible},
+};
 
 #[derive(Default)]
 pub struct Serializer {
@@ -9,43 +18,85 @@ pub struct Serializer {
 ///
 /// # Errors
 ///
-/// This function will return an error if the data structure cannot be serialized for any reason.
-pub fn to_string<T: Serialize>(value: &T) -> Result<String, Err> {
+/// This function will return an error if the data structure cannot be serialized for any reason,
+/// e.g. if it is not a struct or a map, if sections are nested, or if a value has a line break.
+pub fn to_string<T: Serialize>(value: &T) -> Result<String, Error> {
     let mut serializer = Serializer::default();
     value.serialize(&mut serializer)?;
     Ok(serializer.output)
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("error")]
-pub struct Err;
+#[error("{0}")]
+pub struct Error(String);
 
-impl serde::ser::Error for Err {
-    fn custom<T>(_: T) -> Self
+impl serde::ser::Error for Error {
+    fn custom<T>(msg: T) -> Self
     where
         T: std::fmt::Display,
     {
-        Self
+        Self(msg.to_string())
     }
 }
 
-impl ser::Serializer for &mut Serializer {
-    type Ok = ();
-    type Error = Err;
-
-    type SerializeSeq = Self;
-    type SerializeTuple = Self;
-    type SerializeTupleStruct = Self;
-    type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
-
-    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
-        self.output += if v { "true" } else { "false" };
-        Ok(())
+fn unsupported(what: &str) -> Error {
+    Error(format!("cannot serialize {what} in a systemd unit file"))
+}
+
+fn push_header(output: &mut String, name: &str) {
+    if !output.is_empty() {
+        output.push('\n');
     }
+    output.push('[');
+    output.push_str(name);
+    output.push_str("]\n");
+}
+
+/// Forwards the methods for plain values to `Self::entry`.
+macro_rules! forward_to_entry {
+    ($($method:ident($ty:ty)),* $(,)?) => {$(
+        fn $method(self, v: $ty) -> Result<Self::Ok, Self::Error> {
+            self.entry(&v)
+        }
+    )*};
+}
 
+/// Forwards the methods for plain values to `to_string`.
+macro_rules! forward_to_string {
+    ($($method:ident($ty:ty)),* $(,)?) => {$(
+        fn $method(self, v: $ty) -> Result<Self::Ok, Self::Error> {
+            Ok(v.to_string())
+        }
+    )*};
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Section<'a>;
+    type SerializeStruct = Section<'a>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a bool outside of a section"))
+    }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a number outside of a section"))
+    }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a number outside of a section"))
+    }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a number outside of a section"))
+    }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a string outside of a section"))
+    }
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
         self.serialize_i64(i64::from(v))
     }
@@ -55,11 +106,6 @@ impl ser::Serializer for &mut Serializer {
     fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
         self.serialize_i64(i64::from(v))
     }
-    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
-        self.output += &v.to_string();
-        Ok(())
-    }
-
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
         self.serialize_u64(u64::from(v))
     }
@@ -69,32 +115,14 @@ impl ser::Serializer for &mut Serializer {
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
         self.serialize_u64(u64::from(v))
     }
-    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        self.output += &v.to_string();
-        Ok(())
-    }
-
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
         self.serialize_f64(f64::from(v))
     }
-    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
-        self.output += &v.to_string();
-        Ok(())
-    }
-
-    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
-        self.serialize_str(&v.to_string())
-    }
-
-    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        self.output += "\"";
-        self.output += &v.replace('"', "\\\"").replace('\n', "\\\n");
-        self.output += "\"";
-        Ok(())
+    fn serialize_char(self, _: char) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("a string outside of a section"))
     }
-
     fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Self::Error> {
-        todo!()
+        Err(unsupported("bytes"))
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
@@ -106,70 +134,56 @@ impl ser::Serializer for &mut Serializer {
     {
         value.serialize(self)
     }
-
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
         Ok(())
     }
-
     fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
-        self.serialize_unit()
+        Ok(())
     }
-
     fn serialize_unit_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
         _variant: &'static str,
     ) -> Result<Self::Ok, Self::Error> {
-        todo!()
+        Err(unsupported("an enum outside of a section"))
     }
-
     fn serialize_newtype_struct<T>(
         self,
-        name: &'static str,
+        _name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        self.output += "[";
-        self.output += name;
-        self.output += "]\n";
         value.serialize(self)
     }
-
     fn serialize_newtype_variant<T>(
         self,
-        name: &'static str,
+        _name: &'static str,
         _variant_index: u32,
         _variant: &'static str,
-        value: &T,
+        _value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        self.output += "[";
-        self.output += name;
-        self.output += "]\n";
-        value.serialize(self)
+        Err(unsupported("an enum outside of a section"))
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        todo!()
+        Err(unsupported("a sequence outside of a section"))
     }
-
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        todo!()
+        Err(unsupported("a tuple"))
     }
-
     fn serialize_tuple_struct(
         self,
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        todo!()
+        Err(unsupported("a tuple struct"))
     }
-
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
@@ -177,170 +191,494 @@ impl ser::Serializer for &mut Serializer {
         _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        todo!()
+        Err(unsupported("a tuple variant"))
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        Ok(self)
+        Ok(Section::top(&mut self.output, None))
     }
-
     fn serialize_struct(
         self,
         name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        self.output += "[";
-        self.output += name;
-        self.output += "]\n";
-        Ok(self)
+        Ok(Section::top(&mut self.output, Some(name)))
     }
-
     fn serialize_struct_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        variant: &'static str,
+        _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        self.output += "\n";
-        self.output += variant;
-        self.output += "=";
-        Ok(self)
+        Err(unsupported("a struct variant"))
     }
 }
 
-impl ser::SerializeSeq for &'_ mut Serializer {
-    type Ok = ();
+/// The section entries are written to, for fields that aren't sections themselves.
+struct Parent<'a> {
+    /// Name of the section, or `None` for a map, which has no section of its own.
+    name: Option<&'static str>,
+    /// Whether the header of the section is the last one written.
+    open: &'a mut bool,
+}
+
+impl Parent<'_> {
+    fn open(&mut self, output: &mut String, key: &str) -> Result<(), Error> {
+        if !*self.open {
+            let name = (self.name).ok_or_else(|| Error(format!("{key} is not in a section")))?;
+            push_header(output, name);
+            *self.open = true;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a struct or the entries of a map.
+pub struct Section<'a> {
+    output: &'a mut String,
+    name: Option<&'static str>,
+    /// Whether the header for `name` is the last one written.
+    open: bool,
+    /// Whether this is a section of the top-level struct, whose fields can't be sections.
+    nested: bool,
+    /// Key of the map entry being serialized.
+    key: Option<String>,
+}
+
+impl<'a> Section<'a> {
+    const fn top(output: &'a mut String, name: Option<&'static str>) -> Self {
+        Self {
+            output,
+            name,
+            open: false,
+            nested: false,
+            key: None,
+        }
+    }
+
+    const fn nested(output: &'a mut String) -> Self {
+        Self {
+            output,
+            name: None,
+            open: true,
+            nested: true,
+            key: None,
+        }
+    }
+
+    fn field<T>(&mut self, key: &str, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        let parent = (!self.nested).then_some(Parent {
+            name: self.name,
+            open: &mut self.open,
+        });
+        value.serialize(Entry {
+            output: self.output,
+            key,
+            parent,
+            repeated: false,
+        })
+    }
+}
 
-    type Error = Err;
+impl ser::SerializeStruct for Section<'_> {
+    type Ok = ();
+    type Error = Error;
 
-    fn serialize_element<T>(&mut self, _value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        todo!()
+        self.field(key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        todo!()
+        Ok(())
     }
 }
 
-impl ser::SerializeTuple for &'_ mut Serializer {
+impl ser::SerializeMap for Section<'_> {
     type Ok = ();
+    type Error = Error;
 
-    type Error = Err;
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
 
-    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        todo!()
+        let key = (self.key.take()).ok_or_else(|| Error("map value without a key".to_owned()))?;
+        self.field(&key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        todo!()
+        Ok(())
     }
 }
 
-impl ser::SerializeTupleStruct for &'_ mut Serializer {
-    type Ok = ();
-
-    type Error = Err;
+/// A field of a section: a `Key=value` entry, or with a [`Parent`], possibly a section itself.
+struct Entry<'a, 'p> {
+    output: &'a mut String,
+    key: &'a str,
+    /// The section this field belongs to, if the field may be a section itself.
+    parent: Option<Parent<'p>>,
+    /// Whether this is an element of a sequence, which can't be a sequence again.
+    repeated: bool,
+}
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+impl<'a> Entry<'a, '_> {
+    fn entry<T>(mut self, value: &T) -> Result<(), Error>
     where
         T: ?Sized + Serialize,
     {
-        todo!()
+        let value = value.serialize(ValueSerializer)?;
+        if value.contains('\n') {
+            return Err(Error(format!("value of {} has a line break", self.key)));
+        }
+        if let Some(parent) = &mut self.parent {
+            parent.open(self.output, self.key)?;
+        }
+        self.output.push_str(self.key);
+        self.output.push('=');
+        self.output.push_str(&value);
+        self.output.push('\n');
+        Ok(())
     }
 
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        todo!()
+    fn section(self) -> Result<Section<'a>, Error> {
+        let Some(parent) = self.parent else {
+            return Err(Error(format!(
+                "{} cannot be a section inside a section",
+                self.key
+            )));
+        };
+        push_header(self.output, self.key);
+        // entries of the parent that follow need its header again
+        *parent.open = false;
+        Ok(Section::nested(self.output))
     }
 }
 
-impl ser::SerializeTupleVariant for &mut Serializer {
+impl<'a, 'p> ser::Serializer for Entry<'a, 'p> {
     type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Repeat<'a, 'p>;
+    type SerializeTuple = Repeat<'a, 'p>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Section<'a>;
+    type SerializeStruct = Section<'a>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    forward_to_entry! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+    }
 
-    type Error = Err;
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("bytes"))
+    }
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.entry(variant)
+    }
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        todo!()
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(unsupported("an enum variant with data"))
     }
 
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        todo!()
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        if self.repeated {
+            return Err(unsupported("a sequence inside a sequence"));
+        }
+        Ok(Repeat {
+            output: self.output,
+            key: self.key,
+            parent: self.parent,
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(unsupported("a tuple struct"))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(unsupported("a tuple variant"))
     }
-}
 
-impl ser::SerializeMap for &'_ mut Serializer {
-    type Ok = ();
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        self.section()
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.section()
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(unsupported("a struct variant"))
+    }
+}
 
-    type Error = Err;
+/// The elements of a sequence, each written as its own entry with the same key, or as a repeated
+/// section.
+pub struct Repeat<'a, 'p> {
+    output: &'a mut String,
+    key: &'a str,
+    parent: Option<Parent<'p>>,
+}
 
-    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+impl Repeat<'_, '_> {
+    fn element<T>(&mut self, value: &T) -> Result<(), Error>
     where
         T: ?Sized + Serialize,
     {
-        key.serialize(&mut **self)
+        let parent = (self.parent.as_mut()).map(|parent| Parent {
+            name: parent.name,
+            open: &mut *parent.open,
+        });
+        value.serialize(Entry {
+            output: self.output,
+            key: self.key,
+            parent,
+            repeated: true,
+        })
     }
+}
 
-    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+impl ser::SerializeSeq for Repeat<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.element(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        self.output += "\n";
         Ok(())
     }
 }
 
-impl ser::SerializeStruct for &'_ mut Serializer {
+impl ser::SerializeTuple for Repeat<'_, '_> {
     type Ok = ();
-    type Error = Err;
+    type Error = Error;
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        self.output += key;
-        self.output += "=";
-        value.serialize(&mut **self)?;
-        self.output += "\n";
-        Ok(())
+        self.element(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        self.output += "\n";
         Ok(())
     }
 }
 
-impl ser::SerializeStructVariant for &'_ mut Serializer {
-    type Ok = ();
-
-    type Error = Err;
+/// Turns a plain value into the text after the `=` of an entry.
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    forward_to_string! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+    }
 
-    fn serialize_field<T>(&mut self, _key: &'static str, _value: &T) -> Result<(), Self::Error>
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(unsupported("bytes"))
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(String::new())
+    }
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        todo!()
+        value.serialize(self)
     }
-
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        todo!()
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(String::new())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Ok(String::new())
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        Ok(variant.to_owned())
+    }
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(unsupported("an enum variant with data"))
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(unsupported("a sequence inside a sequence"))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(unsupported("a tuple inside a sequence"))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(unsupported("a tuple struct"))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(unsupported("a tuple variant"))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(unsupported("a map as a value"))
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(unsupported("a struct as a value"))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(unsupported("a struct variant"))
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::indexing_slicing)]
 mod tests {
     use serde::Serialize;
     #[test]
@@ -352,13 +690,64 @@ mod tests {
             bye: usize,
         }
 
-        println!(
-            "{}",
+        assert_eq!(
             super::to_string(&Meow {
                 hello: "hai".into(),
                 bye: 42,
             })
-            .unwrap()
+            .unwrap(),
+            "[Meow]\nHello=hai\nBye=42\n"
         );
     }
+
+    #[test]
+    fn sections_and_repeated_keys() {
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Unit {
+            unit: UnitSection,
+            install: Install,
+        }
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct UnitSection {
+            description: String,
+            documentation: Option<String>,
+            after: Vec<&'static str>,
+        }
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Install {
+            wanted_by: Vec<&'static str>,
+        }
+
+        let unit = Unit {
+            unit: UnitSection {
+                description: "Test unit".to_owned(),
+                documentation: None,
+                after: vec!["network.target", "local-fs.target"],
+            },
+            install: Install {
+                wanted_by: vec!["multi-user.target"],
+            },
+        };
+        let s = super::to_string(&unit).unwrap();
+        assert_eq!(
+            s,
+            "[Unit]\nDescription=Test unit\nAfter=network.target\nAfter=local-fs.target\n\n[Install]\nWantedBy=multi-user.target\n"
+        );
+        let parsed: crate::SystemdIni = s.parse().unwrap();
+        assert_eq!(
+            parsed.sections["Unit"]["After"].as_array(),
+            ["network.target", "local-fs.target"]
+        );
+
+        let err = super::to_string(&UnitSection {
+            description: "two\nlines".to_owned(),
+            documentation: None,
+            after: vec![],
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "value of Description has a line break");
+    }
 }
@@ -43,6 +43,12 @@ page-confirmation-problem-device-mounted = {$dev} is mounted on {$mountpoint}. U
 page-confirmation-problem-devblkopen = The block-device <tt>{$dev}</tt> is in use by the following processes:
     <tt>{$pids}</tt>
     These processes must be closed before the installer can proceed. 
+page-confirmation-problem-too-small = {$disk} is too small: the installation needs at least {$needed}, but the disk is only {$size}.
+page-confirmation-partition-create = New
+page-confirmation-partition-resize = Grown
+page-confirmation-partition-keep = Kept
+page-confirmation-partition-delete = Deleted
+page-confirmation-partition-unknown = Changed
 
 dialog-confirm-warn-efipartfound-title = EFI Partition Detected
 dialog-confirm-warn-efipartfound-desc = If you are installing alongside another system, please ensure its EFI partition does not exist on the destination disk.
@@ -42,4 +42,5 @@ popd
 install -Dpm644 data/com.fyralabs.Readymade.svg $root/usr/share/icons/hicolor/scalable/apps/com.fyralabs.Readymade.svg
 install -Dpm644 data/com.fyralabs.Readymade.desktop $root/usr/share/applications/com.fyralabs.Readymade.desktop
 install -Dpm644 data/com.fyralabs.pkexec.readymade.policy $root/usr/share/polkit-1/actions/com.fyralabs.pkexec.readymade.policy
+install -Dpm644 data/readymade-unattended.service $root/usr/lib/systemd/system/readymade-unattended.service
 install -Dpm644 crates/libreadymade/templates/ultramarine.toml $root/etc/readymade.toml
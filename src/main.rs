@@ -278,6 +278,20 @@ fn main() -> Result<()> {
             .inspect_err(|e| _ = sentry_eyre::capture_report(e));
     }
 
+    if let Some((i, _)) = std::env::args().find_position(|arg| arg == "--preview") {
+        tracing::info!("Previewing partitions");
+        let channel = IpcSender::connect(
+            std::env::args()
+                .nth(i.wrapping_add(1))
+                .context("No IPC channel ID passed")?,
+        )?;
+        let request: pages::confirmation::PreviewRequest =
+            serde_json::from_reader(std::io::stdin())?;
+        return (request.preview())
+            .and_then(|preview| Ok(channel.send(preview)?))
+            .inspect_err(|e| _ = sentry_eyre::capture_report(e));
+    }
+
     *CONFIG.write() = cfg::get_cfg()?;
     *INSTALLATION_STATE.write() = InstallationState::from(&*CONFIG.read());
 
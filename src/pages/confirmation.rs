@@ -1,14 +1,58 @@
-use std::{os::unix::fs::FileTypeExt, path::PathBuf, process::Command, time::Duration};
+use std::{
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use ipc_channel::ipc::IpcOneShotServer;
+
+use libreadymade::{
+    backend::{
+        install::InstallationType,
+        provisioners::disk::repart::{Empty, Repart},
+    },
+    preflight::Fit,
+    preview::Preview,
+};
+use parking_lot::Mutex;
+use repart::Activity;
+use serde::{Deserialize, Serialize};
+
+/// Width of the partition bar in pixels.
+const PREVIEW_WIDTH: f64 = 640.0;
 
 use crate::prelude::*;
 
+/// The repart definitions of a whole-disk installation.
+fn wholedisk_dir(is_bootc: bool) -> PathBuf {
+    libreadymade::consts::repart_dir().join(if is_bootc {
+        "bootcwholedisk"
+    } else {
+        "wholedisk"
+    })
+}
+
 page!(Confirmation {
     problem: Option<Problem>,
     root: libhelium::ViewMono,
     warns: Vec<Warning>,
     warn_dialog: Option<Controller<Warning>>,
+    preview: Option<Preview>,
+    /// Disk the preview was made for, so it is only made again when another disk is picked
+    previewed_disk: Option<PathBuf>,
+    preview_bar: gtk::Box,
+    preview_table: gtk::Grid,
 }:
     init(root, sender, model, widgets) {
+        // measuring the live system takes a while, so do it once and off the main thread
+        std::thread::spawn(|| SOURCE_SIZE.get_or_init(|| {
+            let source = std::env::var("REPART_COPY_SOURCE");
+            libreadymade::preflight::source_size(
+                source.as_deref().unwrap_or(libreadymade::consts::ROOTFS_BASE),
+            )
+        }));
         gtk::glib::timeout_add(Duration::from_secs(1), move || {
             sender.input(Self::Input::Check);
             gtk::glib::ControlFlow::Continue
@@ -52,6 +96,11 @@ page!(Confirmation {
         },
         Check => {
             self.problem = Problem::detect();
+            self.request_preview(&sender);
+        },
+        Previewed(preview: Option<Preview>) => {
+            self.preview = preview;
+            self.show_preview();
         }
     } => { StartInstallation }
 
@@ -119,6 +168,18 @@ page!(Confirmation {
         }
     },
 
+    gtk::Box {
+        set_orientation: gtk::Orientation::Vertical,
+        set_spacing: 8,
+        set_halign: gtk::Align::Center,
+        set_margin_vertical: 8,
+        #[watch]
+        set_visible: model.preview.is_some(),
+
+        append: &model.preview_bar,
+        append: &model.preview_table,
+    },
+
     // relm4 doesn't support if lets
     gtk::Label {
         #[watch]
@@ -189,6 +250,160 @@ impl ConfirmationPage {
     }
 }
 
+/// A dry run of systemd-repart on a disk, for the confirmation page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewRequest {
+    pub disk: PathBuf,
+    pub repart: Repart,
+}
+
+impl PreviewRequest {
+    /// Lay out the partitions without writing anything. Reading the disk needs root.
+    pub fn preview(&self) -> Result<Preview> {
+        self.repart.preview(&self.disk, None, None)
+    }
+
+    /// Run [`Self::preview`] in a `readymade --preview` subprocess through pkexec, like the
+    /// installation itself.
+    fn preview_using_subprocess(&self) -> Result<Preview> {
+        let (server, channel_id) = IpcOneShotServer::<Preview>::new()?;
+        let mut child = Command::new("pkexec")
+            .arg(std::env::current_exe()?)
+            .args(["--preview", &channel_id])
+            .arg(format!(
+                "READYMADE_LOG={}",
+                std::env::var("READYMADE_LOG").as_deref().unwrap_or("info")
+            ))
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("cannot run the preview subprocess")?;
+        // dropping stdin closes it, so the subprocess reads the request up to EOF
+        serde_json::to_writer(child.stdin.take().context("no stdin")?, self)?;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("the preview subprocess failed with {status}");
+        }
+        // the subprocess has sent the preview before exiting
+        let (_, preview) = server.accept()?;
+        Ok(preview)
+    }
+}
+
+impl ConfirmationPage {
+    /// Ask systemd-repart for the new partition table of the destination disk, once per disk.
+    fn request_preview(&mut self, sender: &relm4::ComponentSender<Self>) {
+        let state = INSTALLATION_STATE.read();
+        let Some(disk) = state.destination_disk.as_ref() else {
+            return;
+        };
+        if self.previewed_disk.as_ref() == Some(&disk.devpath) {
+            return;
+        }
+        self.previewed_disk = Some(disk.devpath.clone());
+        let directory = match state.installation_type {
+            Some(InstallationType::WholeDisk) => {
+                wholedisk_dir(crate::CONFIG.read().install.bootc_imgref.is_some())
+            }
+            Some(InstallationType::ChromebookInstall) => {
+                libreadymade::consts::repart_dir().join("chromebookinstall")
+            }
+            _ => return sender.input(Self::Input::Previewed(None)),
+        };
+        let request = PreviewRequest {
+            disk: disk.devpath.clone(),
+            repart: Repart {
+                directory: Some(directory),
+                layout: None,
+                copy_source: None,
+                empty: Empty::Force,
+                encrypt: vec![],
+            },
+        };
+        let sender = sender.input_sender().clone();
+        // systemd-repart takes a moment even without writing anything
+        std::thread::spawn(move || {
+            let preview = (request.preview_using_subprocess())
+                .inspect_err(
+                    |err| tracing::error!(?err, ?request.disk, "cannot preview partitions"),
+                )
+                .ok();
+            sender.emit(Self::Input::Previewed(preview));
+        });
+    }
+
+    /// Draw [`Self::preview`] as a bar of the disk and a table of its partitions.
+    fn show_preview(&self) {
+        while let Some(child) = self.preview_bar.first_child() {
+            self.preview_bar.remove(&child);
+        }
+        while let Some(child) = self.preview_table.first_child() {
+            self.preview_table.remove(&child);
+        }
+        let Some(preview) = &self.preview else {
+            return;
+        };
+        self.preview_bar.add_css_class("partition-bar");
+        self.preview_table.set_column_spacing(16);
+        self.preview_table.set_row_spacing(4);
+
+        for segment in preview.segments() {
+            let block = gtk::Box::default();
+            #[allow(clippy::cast_possible_truncation)]
+            block.set_size_request((segment.share * PREVIEW_WIDTH).round() as i32, 24);
+            if let Some(part) = segment.partition {
+                block.add_css_class(activity_class(part.activity));
+                block.set_tooltip_text(Some(&format!("{} ({})", part_name(part), part.size)));
+            } else {
+                block.add_css_class("partition-free");
+            }
+            self.preview_bar.append(&block);
+        }
+
+        for (row, part) in (0..).zip(&preview.partitions) {
+            let cells = [
+                part.number.to_string(),
+                part_name(part),
+                part.size.to_string(),
+                activity_msg(part.activity),
+            ];
+            for (column, text) in (0..).zip(cells) {
+                let label = gtk::Label::new(Some(&text));
+                label.set_xalign(0.0);
+                label.add_css_class(activity_class(part.activity));
+                self.preview_table.attach(&label, column, row, 1, 1);
+            }
+        }
+    }
+}
+
+fn part_name(part: &libreadymade::preview::PreviewPartition) -> String {
+    if part.label.is_empty() {
+        part.part_type.clone()
+    } else {
+        part.label.clone()
+    }
+}
+
+const fn activity_class(activity: Activity) -> &'static str {
+    match activity {
+        Activity::Create => "partition-create",
+        Activity::Resize => "partition-resize",
+        Activity::Unchanged => "partition-keep",
+        Activity::Delete => "partition-delete",
+        Activity::Unknown => "partition-unknown",
+    }
+}
+
+fn activity_msg(activity: Activity) -> String {
+    match activity {
+        Activity::Create => t!("page-confirmation-partition-create"),
+        Activity::Resize => t!("page-confirmation-partition-resize"),
+        Activity::Unchanged => t!("page-confirmation-partition-keep"),
+        Activity::Delete => t!("page-confirmation-partition-delete"),
+        Activity::Unknown => t!("page-confirmation-partition-unknown"),
+    }
+}
+
 impl ConfirmationPage {
     fn overlay_widget(&self) -> Option<gtk::Overlay> {
         let root = &self.root;
@@ -203,8 +418,14 @@ impl ConfirmationPage {
 enum Problem {
     DeviceMounted(String, PathBuf),
     DevBlkOpen(String, Vec<usize>),
+    DoesNotFit(String, Fit),
 }
 
+/// Size of the live system copied to the disk, see [`Problem::detect_fit`].
+static SOURCE_SIZE: OnceLock<Option<bytesize::ByteSize>> = OnceLock::new();
+/// The last [`Fit`] worked out, with the disk it is for, so the definitions aren't read every check.
+static FIT: Mutex<Option<(PathBuf, Fit)>> = Mutex::new(None);
+
 impl Problem {
     fn detect() -> Option<Self> {
         let Some(disk) = &INSTALLATION_STATE.read().destination_disk else {
@@ -226,7 +447,34 @@ impl Problem {
         {
             return Some(Self::DevBlkOpen(disk.to_string(), procs));
         }
-        None
+        Self::detect_fit()
+    }
+
+    /// Check that a whole-disk installation fits, once the size of the live system is known.
+    fn detect_fit() -> Option<Self> {
+        let state = INSTALLATION_STATE.read();
+        let disk = state.destination_disk.as_ref()?;
+        if state.installation_type? != InstallationType::WholeDisk {
+            return None;
+        }
+        let is_bootc = crate::CONFIG.read().install.bootc_imgref.is_some();
+        // a bootc image is only pulled during the installation, so its size is not known
+        let source = if is_bootc { None } else { *SOURCE_SIZE.get()? };
+        let mut fit = FIT.lock();
+        if !fit
+            .as_ref()
+            .is_some_and(|(devpath, _)| *devpath == disk.devpath)
+        {
+            let directory = wholedisk_dir(is_bootc);
+            let new = Fit::for_repart(&directory, source, disk.size)
+                .inspect_err(|err| {
+                    tracing::error!(?err, ?directory, "cannot read repart definitions")
+                })
+                .ok()?;
+            *fit = Some((disk.devpath.clone(), new));
+        }
+        let (_, fit) = fit.as_ref()?;
+        (!fit.fits()).then(|| Self::DoesNotFit(disk.disk_name.clone(), *fit))
     }
     fn msg(&self) -> String {
         match self {
@@ -240,6 +488,12 @@ impl Problem {
                 dev = dev,
                 pids = pids.iter().join(", "),
             ),
+            Self::DoesNotFit(disk, fit) => t!(
+                "page-confirmation-problem-too-small",
+                disk = disk,
+                needed = fit.needed.to_string(),
+                size = fit.disk.to_string(),
+            ),
         }
     }
 }
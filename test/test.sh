@@ -23,6 +23,6 @@ TEST_DESTINATION_DISK=${test_device} TEST_REPART_DIRECTORY=../repart TEST_REPART
 
 cargo build -p readymade-playbook --bin readymade-playbook --release
 
-sudo ../../target/release/readymade-playbook playbook.json
+sudo ../../target/release/readymade-playbook run playbook.json
 
 isotovideo --exit-status-from-test-results UEFI=1 UEFI_PFLASH_CODE=/usr/share/edk2/ovmf/OVMF_CODE.fd UEFI_PFLASH_VARS=/usr/share/edk2/ovmf/OVMF_VARS.fd CASEDIR=../distri HDD_1=./test.img